@@ -6,12 +6,21 @@
 
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, quote_spanned, ToTokens};
+use syn::parse::discouraged::Speculative;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::{
-    parse_macro_input, Data, DeriveInput, Error, Field, Fields, Index, Lit, Meta, MetaList,
-    NestedMeta, Result,
+    parse_macro_input, parse_quote, Data, DataEnum, DeriveInput, Error, Expr, Field, Fields,
+    Generics, Index, ItemFn, Lit, Member, Meta, MetaList, NestedMeta, Path, Result, Token,
 };
 
+/// Max length of a help message when the `strict` feature is enabled.
+const DEFAULT_MAX_HELP_LENGTH: usize = 1024;
+
+/// Max number of struct-level labels, unless overridden with `max_labels`.
+const DEFAULT_MAX_LABELS: usize = 10;
+
 #[proc_macro_derive(MetricStorage, attributes(metric))]
 pub fn metric_storage(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -21,55 +30,834 @@ pub fn metric_storage(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         .into()
 }
 
+#[proc_macro_derive(MetricLabel, attributes(metric))]
+pub fn metric_label(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_label(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+#[proc_macro_attribute]
+pub fn instrument_metrics(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let args =
+        parse_macro_input!(attr with Punctuated::<InstrumentArg, Token![,]>::parse_terminated);
+    let item = parse_macro_input!(item as ItemFn);
+
+    expand_instrument(args, item)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// A single `key = value` argument of `#[instrument_metrics(...)]`.
+enum InstrumentArg {
+    Storage(Ident, Box<Expr>),
+    Field(Ident, Member),
+}
+
+impl Parse for InstrumentArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let ident: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        if ident == "storage" {
+            Ok(Self::Storage(ident, input.parse()?))
+        } else if ident == "counter" || ident == "histogram" {
+            Ok(Self::Field(ident, input.parse()?))
+        } else {
+            Err(Error::new(ident.span(), "unexpected parameter"))
+        }
+    }
+}
+
+fn expand_instrument(
+    args: Punctuated<InstrumentArg, Token![,]>,
+    mut item: ItemFn,
+) -> Result<TokenStream> {
+    let mut storage = None;
+    let mut counter = None;
+    let mut histogram = None;
+    for arg in args {
+        match arg {
+            InstrumentArg::Storage(ident, expr) => {
+                MetricAttrs::check_none("storage", ident.span(), storage.is_some())?;
+                storage = Some(expr);
+            }
+            InstrumentArg::Field(ident, member) if ident == "counter" => {
+                MetricAttrs::check_none("counter", ident.span(), counter.is_some())?;
+                counter = Some(member);
+            }
+            InstrumentArg::Field(ident, member) => {
+                MetricAttrs::check_none("histogram", ident.span(), histogram.is_some())?;
+                histogram = Some(member);
+            }
+        }
+    }
+
+    let storage = match storage {
+        Some(storage) => storage,
+        None => {
+            return Err(Error::new(
+                Span::call_site(),
+                "`storage` is required: `#[instrument_metrics(storage = ...)]`",
+            ))
+        }
+    };
+    if counter.is_none() && histogram.is_none() {
+        return Err(Error::new(
+            Span::call_site(),
+            "at least one of `counter` and `histogram` is required",
+        ));
+    }
+
+    let counter = counter.map(|counter| {
+        quote_spanned! { counter.span() => __instrument_metrics.#counter.inc(); }
+    });
+    let histogram = histogram.map(|histogram| {
+        quote_spanned! { histogram.span() =>
+            let __instrument_metrics_timer = __instrument_metrics.#histogram.start_timer();
+        }
+    });
+
+    // The body of an `async fn` only runs when its future is polled,
+    // so the timer measures the whole execution of the future.
+    let block = &item.block;
+    item.block = parse_quote! {{
+        let __instrument_metrics = &#storage;
+        #counter
+        #histogram
+        #block
+    }};
+
+    Ok(item.into_token_stream())
+}
+
+fn expand_label(input: DeriveInput) -> Result<TokenStream> {
+    let name = input.ident;
+
+    let mut attrs = MetricAttrs::default();
+    for attr in &input.attrs {
+        if !attr.path.is_ident("metric") {
+            continue;
+        }
+
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            _ => {
+                return Err(Error::new(
+                    attr.path.span(),
+                    "value for the `metric` attribute should be a list: `metric(...)`",
+                ))
+            }
+        };
+
+        for attr in list.nested {
+            match attr {
+                NestedMeta::Meta(attr) if attr.path().is_ident("crate") => {
+                    attrs.parse_krate(attr)?
+                }
+                attr => return Err(Error::new(attr.span(), "unexpected parameter")),
+            }
+        }
+    }
+    let krate = attrs
+        .krate
+        .unwrap_or_else(|| syn::parse_quote! { prometheus_metric_storage });
+
+    let generics = input.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let input = match input.data {
+        Data::Enum(input) => input,
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "MetricLabel can only be derived for enums",
+            ))
+        }
+    };
+
+    let mut arms = Vec::new();
+    let mut variants = Vec::new();
+    for variant in &input.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new(
+                variant.span(),
+                "MetricLabel can only be derived for enums without fields",
+            ));
+        }
+        let ident = &variant.ident;
+        let value = to_snake_case(&ident.to_string());
+        arms.push(quote! { Self::#ident => #value, });
+        variants.push(ident);
+    }
+    let indices = 0..variants.len();
+
+    Ok(quote! {
+        impl #impl_generics #krate::MetricLabel for #name #ty_generics #where_clause {
+            fn as_str(&self) -> &str {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+
+        impl #impl_generics #krate::MetricLabelVariants for #name #ty_generics #where_clause {
+            fn variants() -> &'static [Self] {
+                &[#(Self::#variants,)*]
+            }
+
+            fn index(&self) -> usize {
+                match self {
+                    #(Self::#variants => #indices,)*
+                }
+            }
+        }
+    })
+}
+
 fn expand(input: DeriveInput) -> Result<TokenStream> {
     let name = input.ident;
 
     let attrs = MetricAttrs::parse(&input.attrs, true)?;
 
+    let labels = attrs.labels.unwrap_or_default();
+
+    for (name, ty) in &attrs.label_types {
+        if !labels.contains(name) {
+            return Err(Error::new(ty.span(), format!("no such label `{}`", name)));
+        }
+    }
+
+    for (name, _, span) in &attrs.fixed_labels {
+        if labels.contains(name) {
+            return Err(Error::new(
+                *span,
+                format!(
+                    "label `{}` is already defined in struct-level `labels`",
+                    name
+                ),
+            ));
+        }
+    }
+
+    let max_labels = attrs.max_labels.unwrap_or(DEFAULT_MAX_LABELS);
+    if let (Some(span), true) = (attrs.labels_span, labels.len() > max_labels) {
+        return Err(Error::new(
+            span,
+            format!(
+                "storage has {} labels, which is more than the limit of {}; \
+                 consider building const labels as a map \
+                 and using `from_const_labels_unregistered`, \
+                 or raise the limit with `max_labels = {}`",
+                labels.len(),
+                max_labels,
+                labels.len()
+            ),
+        ));
+    }
+
+    let config = StructConfig {
+        name: name.to_string(),
+        krate: attrs
+            .krate
+            .unwrap_or_else(|| syn::parse_quote! { prometheus_metric_storage }),
+        subsystem: match (attrs.subsystem, attrs.subsystem_from_module) {
+            (Some(_), Some(span)) => {
+                return Err(Error::new(
+                    span,
+                    "`subsystem` and `subsystem_from_module` can't be used together",
+                ))
+            }
+            (None, Some(_)) => quote! {
+                module_path!().rsplit("::").next().unwrap_or("").to_string()
+            },
+            (subsystem, None) => {
+                let subsystem = subsystem.unwrap_or_default();
+                quote! { #subsystem.to_string() }
+            }
+        },
+        namespace: attrs.namespace.unwrap_or_default(),
+        separators: match (attrs.separator, attrs.namespace_separator) {
+            (None, None) => None,
+            (separator, namespace_separator) => {
+                if let Some(name_fn) = &attrs.name_fn {
+                    return Err(Error::new(
+                        name_fn.span(),
+                        "`name_fn` can't be used together with \
+                         `separator` or `namespace_separator`",
+                    ));
+                }
+                let separator = separator.unwrap_or_else(|| "_".to_string());
+                let namespace_separator = namespace_separator.unwrap_or_else(|| separator.clone());
+                Some((namespace_separator, separator))
+            }
+        },
+        name_fn: attrs.name_fn,
+        labels: labels.clone(),
+        label_types: attrs.label_types,
+        fixed_labels: attrs
+            .fixed_labels
+            .into_iter()
+            .map(|(name, value, _)| (name, value))
+            .collect(),
+        fixed_labels_fn: attrs.fixed_labels_fn,
+        renames: attrs.renames,
+        rename_all: attrs.rename_all,
+        default_buckets: attrs.buckets,
+        max_help_length: match attrs.max_help_length {
+            Some(max_help_length) => Some(max_help_length),
+            None if cfg!(feature = "strict") => Some(DEFAULT_MAX_HELP_LENGTH),
+            None => None,
+        },
+    };
+
+    let vis = input.vis;
+    let generics = input.generics;
     let input = match input.data {
         Data::Struct(input) => input,
-        Data::Enum(_) => panic!("MetricsStorage can't be implemented for enums"),
+        Data::Enum(_) if attrs.owned_registry.is_some() => {
+            return Err(Error::new(
+                attrs.owned_registry.unwrap(),
+                "`owned_registry` can only be used with structs",
+            ))
+        }
+        Data::Enum(_) if attrs.builder.is_some() => {
+            return Err(Error::new(
+                attrs.builder.unwrap(),
+                "`builder` can only be used with structs",
+            ))
+        }
+        Data::Enum(input) => return expand_enum(name, generics, input, &config),
         Data::Union(_) => panic!("MetricsStorage can't be implemented for unions"),
     };
 
-    let subsystem = attrs.subsystem.unwrap_or_else(|| "".to_string());
+    let krate = &config.krate;
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let inline = if attrs.inline {
+        quote! { #[inline] }
+    } else {
+        quote! {}
+    };
 
-    let labels = attrs.labels.unwrap_or_default();
     let label_idents: Vec<_> = labels
         .iter()
         .map(|l| Ident::new(l, Span::call_site()))
         .collect();
+    let label_params: Vec<_> = labels.iter().map(|l| config.label_param(l)).collect();
+    let label_values: Vec<_> = labels.iter().map(|l| config.label_value(l)).collect();
+    let label_defaults = &attrs.label_defaults;
 
-    let (init, reg) = match input.fields {
-        Fields::Named(fields) => {
-            let ident: Vec<_> = fields
-                .named
-                .iter()
-                .map(|field| field.ident.clone().unwrap())
-                .collect();
-            let reg = registrators(
-                fields
-                    .named
-                    .iter()
-                    .map(|field| field.ident.clone().unwrap()),
-            );
-            let init = initializers(fields.named.into_iter(), subsystem)?;
-            let init = quote! { Self { #(#ident: #init,)* } };
-            (init, reg)
-        }
-        Fields::Unnamed(fields) => {
-            let reg = registrators((0..fields.unnamed.len()).map(|i| Index {
-                index: i as _,
-                span: Span::call_site(),
-            }));
-            let init = initializers(fields.unnamed.into_iter(), subsystem)?;
-            let init = quote! { Self ( #(#init,)* ) };
-            (init, reg)
-        }
-        Fields::Unit => (quote! { Self }, quote! {}),
+    let fields = match &input.fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| MetricField::parse(field.ident.to_token_stream(), field, &config))
+            .collect::<Result<Vec<_>>>()?,
+        Fields::Unnamed(fields) => fields
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let member = Index {
+                    index: i as _,
+                    span: Span::call_site(),
+                };
+                MetricField::parse(member.to_token_stream(), field, &config)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Fields::Unit => Vec::new(),
+    };
+
+    for (ident, _) in &config.renames {
+        if !input
+            .fields
+            .iter()
+            .any(|field| field.ident.as_ref() == Some(ident))
+        {
+            return Err(Error::new(ident.span(), "no such field"));
+        }
+    }
+
+    let fixed_labels = config.fixed_labels_init();
+    let storage_error = config.storage_error();
+    let registry_error = config.registry_error();
+
+    let members: Vec<_> = fields.iter().map(|field| &field.member).collect();
+    let inits: Vec<_> = fields.iter().map(|field| &field.init).collect();
+
+    let mut groups: Vec<(&Ident, Vec<&TokenStream>)> = Vec::new();
+    for field in &fields {
+        if let Some(group) = &field.group {
+            match groups.iter_mut().find(|(name, _)| *name == group) {
+                Some((_, members)) => members.push(&field.member),
+                None => groups.push((group, vec![&field.member])),
+            }
+        }
+    }
+    let observers = groups.iter().map(|(group, members)| {
+        let observe = Ident::new(&format!("observe_all_{}", group), group.span());
+        quote! {
+            #inline
+            fn #observe(&self, value: f64, label_values: &[&str]) {
+                #(#krate::Observe::observe_with_label_values(&self.#members, value, label_values);)*
+            }
+        }
+    });
+
+    let init = match &input.fields {
+        Fields::Named(_) => quote! { Self { #(#members: #inits,)* } },
+        Fields::Unnamed(_) => quote! { Self ( #(#inits,)* ) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let part_idents: Vec<_> = input
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => ident.clone(),
+            None => Ident::new(&format!("field_{}", i), Span::call_site()),
+        })
+        .collect();
+    let part_types: Vec<_> = input.fields.iter().map(|field| &field.ty).collect();
+
+    let bucket_getters = part_idents
+        .iter()
+        .zip(&fields)
+        .filter_map(|(ident, field)| {
+            let buckets = field.buckets.as_ref()?;
+            let getter = Ident::new(&format!("{}_buckets", ident), ident.span());
+            Some(quote! {
+                #inline
+                fn #getter(&self) -> Vec<f64> {
+                    vec![#(#buckets),*]
+                }
+            })
+        });
+
+    let per_variant_getters = fields.iter().zip(&part_types).filter_map(|(field, ty)| {
+        let per = field.per.as_ref()?;
+        let member = &field.member;
+        Some(quote! {
+            #inline
+            fn #member(&self, label: #per) -> &<#ty as #krate::PerVariantField>::Metric {
+                #krate::PerVariantField::get(&self.#member, label)
+            }
+        })
+    });
+
+    // Lazy metrics are created on first access, which saves time
+    // in constructors of large storages. In exchange, errors that only
+    // show up when a metric is created turn into panics in accessors.
+    let lazy_getters = fields.iter().zip(&part_types).filter_map(|(field, ty)| {
+        if !field.lazy {
+            return None;
+        }
+        let member = &field.member;
+        Some(quote! {
+            #inline
+            fn #member(&self) -> &<#ty as std::ops::Deref>::Target {
+                &self.#member
+            }
+        })
+    });
+
+    let typed_getters = fields.iter().zip(&part_types).filter_map(|(field, ty)| {
+        if field.label_types.is_empty() {
+            return None;
+        }
+        let member = &field.member;
+        let (params, values): (Vec<_>, Vec<_>) = field
+            .labels
+            .iter()
+            .map(|label| {
+                let ident = Ident::new(label, Span::call_site());
+                match field.label_types.iter().find(|(name, _)| name == label) {
+                    Some((_, ty)) => (
+                        quote! { #ident: #ty },
+                        quote! { #krate::MetricLabel::as_str(&#ident) },
+                    ),
+                    None => (quote! { #ident: &str }, quote! { #ident }),
+                }
+            })
+            .unzip();
+        Some(quote! {
+            #inline
+            fn #member(&self, #(#params,)*) -> <#ty as #krate::MetricVecField>::Metric {
+                #krate::MetricVecField::with_label_values(&self.#member, &[#(#values,)*])
+            }
+        })
+    });
+
+    let enablers = fields.iter().filter_map(|field| {
+        if !field.optional {
+            return None;
+        }
+        let member = &field.member;
+        let enable = Ident::new(&format!("enable_{}", member), member.span());
+        let storage = &config.name;
+        let field = member.to_string();
+        Some(quote! {
+            #inline
+            fn #enable(
+                &self, registry: &(impl #krate::MetricRegistry + ?Sized)
+            ) -> Result<(), #krate::MetricStorageError> {
+                #krate::OptionalMetric::enable(&self.#member, |metric| {
+                    #krate::MetricRegistry::register(registry, Box::new(Clone::clone(metric)))
+                        .map_err(|source| #krate::MetricStorageError::Register {
+                            storage: #storage,
+                            field: #field,
+                            source,
+                        })
+                })
+                .map_err(|err| match err {
+                    #krate::MetricStorageError::Prometheus(source) => (#storage_error)(source),
+                    err => err,
+                })?;
+                Ok(())
+            }
+        })
+    });
+
+    let from_parts = match &input.fields {
+        Fields::Named(_) => quote! { Self { #(#part_idents,)* } },
+        Fields::Unnamed(_) => quote! { Self ( #(#part_idents,)* ) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let rebound = match &input.fields {
+        Fields::Named(_) => quote! { Self { #(#members: self.#members.clone(),)* } },
+        Fields::Unnamed(_) => quote! { Self ( #(self.#members.clone(),)* ) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let targets: Vec<_> = members
+        .iter()
+        .map(|member| quote! { &self.#member })
+        .collect();
+    let per_field = |body: &dyn Fn(&MetricField, &TokenStream) -> TokenStream| {
+        let bodies = fields
+            .iter()
+            .zip(&targets)
+            .map(|(field, target)| body(field, target));
+        quote! { #(#bodies)* }
+    };
+
+    let reg = per_field(&|field, target| field.register(&config, target));
+    let unreg = per_field(&|field, target| field.unregister(krate, target));
+    let descs = per_field(&|field, target| field.descs(krate, target));
+    let histogram_buckets = per_field(&|field, target| field.histogram_buckets(krate, target));
+    let field_descs = per_field(&|field, target| field.field_descs(krate, target));
+    let snapshot = per_field(&|field, target| field.snapshot(krate, target));
+    let metric_names = fields.iter().filter_map(|field| field.name.as_ref());
+    let field_labels = fields.iter().filter_map(MetricField::field_labels);
+
+    // Flattened storages and skipped fields don't have to be `Clone`,
+    // so they can't be rebound.
+    let rebind = if fields.iter().any(|field| field.role != FieldRole::Metric) {
+        quote! {}
+    } else {
+        quote! {
+            #inline
+            fn rebind(
+                &self, registry: &(impl #krate::MetricRegistry + ?Sized)
+            ) -> Result<Self, #krate::MetricStorageError> {
+                let metrics = #rebound;
+                <Self as #krate::MetricStorage>::register_fields(&metrics, registry)?;
+                Ok(metrics)
+            }
+        }
+    };
+
+    let with_defaults = if label_defaults.is_empty() {
+        quote! {}
+    } else {
+        let required_labels: Vec<_> = labels
+            .iter()
+            .filter(|label| !label_defaults.iter().any(|(name, _)| name == *label))
+            .collect();
+        let required_idents: Vec<_> = required_labels
+            .iter()
+            .map(|l| Ident::new(l, Span::call_site()))
+            .collect();
+        let required_params: Vec<_> = required_labels
+            .iter()
+            .map(|l| config.label_param(l))
+            .collect();
+        let required_values: Vec<_> = required_labels
+            .iter()
+            .map(|l| config.label_value(l))
+            .collect();
+        let (default_labels, default_values): (Vec<_>, Vec<_>) = label_defaults
+            .iter()
+            .map(|(name, value)| (name, value))
+            .unzip();
+        let const_labels = quote! {
+            let mut const_labels = std::collections::HashMap::new();
+            #(const_labels.insert(#required_labels.to_string(), #required_values);)*
+            #(const_labels.insert(#default_labels.to_string(), #default_values.to_string());)*
+        };
+
+        quote! {
+            #inline
+            fn new_unregistered_with_defaults(
+                #(#required_params,)*
+            ) -> #krate::Result<Self> {
+                #const_labels
+                <Self as #krate::MetricStorage>::from_const_labels_unregistered(const_labels)
+            }
+
+            #inline
+            fn new_with_defaults(
+                registry: &(impl #krate::MetricRegistry + ?Sized), #(#required_params,)*
+            ) -> Result<Self, #krate::MetricStorageError> {
+                let metrics = Self::new_unregistered_with_defaults(#(#required_idents,)*)
+                    .map_err(#storage_error)?;
+                <Self as #krate::MetricStorage>::register_fields(&metrics, registry)?;
+                Ok(metrics)
+            }
+
+            #inline
+            fn instance_with_defaults(
+                registry: &#krate::StorageRegistry<impl #krate::MetricRegistry>, #(#required_params,)*
+            ) -> Result<&Self, #krate::MetricStorageError>
+            where
+                Self: Send + Sync + 'static,
+            {
+                #const_labels
+                registry.get_or_create_storage::<Self>(const_labels).map_err(#registry_error)
+            }
+        }
+    };
+
+    let owned_registry = if attrs.owned_registry.is_some() {
+        let wrapper = Ident::new(&format!("{}WithRegistry", name), name.span());
+        let doc = format!(
+            "[`{}`] together with a registry that owns its metrics.",
+            name
+        );
+        quote! {
+            #[doc = #doc]
+            #vis struct #wrapper #generics #where_clause {
+                registry: #krate::Registry,
+                metrics: #name #ty_generics,
+            }
+
+            #[allow(unused)]
+            impl #impl_generics #wrapper #ty_generics #where_clause {
+                #inline
+                fn new(#(#label_params,)*) -> Result<Self, #krate::MetricStorageError> {
+                    let registry = #krate::Registry::new();
+                    let metrics = <#name #ty_generics>::new(&registry, #(#label_idents,)*)?;
+                    Ok(Self { registry, metrics })
+                }
+
+                #inline
+                fn metrics(&self) -> &#name #ty_generics {
+                    &self.metrics
+                }
+
+                #inline
+                fn registry(&self) -> &#krate::Registry {
+                    &self.registry
+                }
+
+                #inline
+                fn gather(&self) -> Vec<#krate::MetricFamily> {
+                    self.registry.gather()
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let builder = if attrs.builder.is_some() {
+        let builder = Ident::new(&format!("{}Builder", name), name.span());
+        let doc = format!(
+            "Builder for [`{}`] that overrides histogram buckets \
+             and adds const labels at runtime.",
+            name
+        );
+        let (bucket_idents, setters): (Vec<_>, Vec<_>) = part_idents
+            .iter()
+            .zip(fields.iter().zip(&part_types))
+            .filter(|(_, (field, _))| field.init_with_buckets.is_some())
+            .map(|(ident, (_, ty))| {
+                let setter = Ident::new(&format!("with_{}_buckets", ident), ident.span());
+                // The macro can't tell histograms from other metrics,
+                // so setters are bounded on `HistMetricInit` instead.
+                // The bound is higher-ranked to defer its check
+                // to call sites.
+                let setter = quote! {
+                    #inline
+                    fn #setter(mut self, buckets: Vec<f64>) -> Self
+                    where
+                        for<'__b> #ty: #krate::HistMetricInit,
+                    {
+                        self.#ident = Some(buckets);
+                        self
+                    }
+                };
+                (ident, setter)
+            })
+            .unzip();
+        let metric_fields: Vec<_> = fields
+            .iter()
+            .filter(|field| field.role == FieldRole::Metric)
+            .map(|field| field.member.to_string())
+            .collect();
+        let builder_inits = part_idents.iter().zip(&fields).map(|(ident, field)| {
+            let init = &field.init;
+            let init = match &field.init_with_buckets {
+                Some(init_with_buckets) => quote! {
+                    match self.#ident {
+                        Some(buckets) => #init_with_buckets,
+                        None => #init,
+                    }
+                },
+                None => quote! { #init },
+            };
+            if field.role != FieldRole::Metric {
+                return init;
+            }
+            let field = field.member.to_string();
+            quote! {
+                {
+                    let mut const_labels = const_labels.clone();
+                    for (field, name, value) in &self.__field_const_labels {
+                        if field == #field {
+                            const_labels.insert(name.clone(), value.clone());
+                        }
+                    }
+                    #init
+                }
+            }
+        });
+        let built = match &input.fields {
+            Fields::Named(_) => quote! { #name { #(#members: #builder_inits,)* } },
+            Fields::Unnamed(_) => quote! { #name ( #(#builder_inits,)* ) },
+            Fields::Unit => quote! { #name },
+        };
+        quote! {
+            #[doc = #doc]
+            #vis struct #builder #generics #where_clause {
+                #(#bucket_idents: Option<Vec<f64>>,)*
+                __field_const_labels: Vec<(String, String, String)>,
+                __storage: std::marker::PhantomData<fn() -> #name #ty_generics>,
+            }
+
+            impl #impl_generics Default for #builder #ty_generics #where_clause {
+                fn default() -> Self {
+                    Self {
+                        #(#bucket_idents: None,)*
+                        __field_const_labels: Vec::new(),
+                        __storage: std::marker::PhantomData,
+                    }
+                }
+            }
+
+            #[allow(
+                clippy::vec_init_then_push,
+                clippy::redundant_clone,
+                clippy::let_and_return,
+                unused,
+                unused_mut
+            )]
+            impl #impl_generics #builder #ty_generics #where_clause {
+                #(#setters)*
+
+                #inline
+                fn field_const_label(mut self, field: &str, name: &str, value: &str) -> Self {
+                    self.__field_const_labels.push(
+                        (field.to_string(), name.to_string(), value.to_string())
+                    );
+                    self
+                }
+
+                #inline
+                fn build_unregistered(
+                    self, #(#label_params,)*
+                ) -> #krate::Result<#name #ty_generics> {
+                    let mut const_labels = std::collections::HashMap::new();
+                    #(const_labels.insert(#labels.to_string(), #label_values);)*
+                    #fixed_labels
+                    for (field, name, _) in &self.__field_const_labels {
+                        if ![#(#metric_fields,)*].contains(&field.as_str()) {
+                            return Err(#krate::Error::Msg(format!(
+                                "unknown metric field {:?}", field
+                            )));
+                        }
+                        if const_labels.contains_key(name) {
+                            return Err(#krate::Error::Msg(format!(
+                                "const label {:?} of field {:?} is already defined", name, field
+                            )));
+                        }
+                    }
+                    Ok(#built)
+                }
+
+                #inline
+                fn build(
+                    self, registry: &(impl #krate::MetricRegistry + ?Sized), #(#label_params,)*
+                ) -> Result<#name #ty_generics, #krate::MetricStorageError> {
+                    let metrics = self.build_unregistered(#(#label_idents,)*)
+                        .map_err(#storage_error)?;
+                    <#name #ty_generics as #krate::MetricStorage>::register_fields(
+                        &metrics, registry
+                    )?;
+                    Ok(metrics)
+                }
+            }
+
+            #[allow(unused)]
+            impl #impl_generics #name #ty_generics #where_clause {
+                #inline
+                fn builder() -> #builder #ty_generics {
+                    Default::default()
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let try_from = if labels.is_empty() {
+        let mut try_from_generics = generics.clone();
+        try_from_generics
+            .params
+            .insert(0, syn::parse_quote! { '__registry });
+        let (try_from_generics, _, _) = try_from_generics.split_for_impl();
+        quote! {
+            impl #try_from_generics std::convert::TryFrom<&'__registry #krate::Registry>
+                for #name #ty_generics #where_clause
+            {
+                type Error = #krate::MetricStorageError;
+
+                fn try_from(
+                    registry: &'__registry #krate::Registry
+                ) -> Result<Self, #krate::MetricStorageError> {
+                    Self::new(registry)
+                }
+            }
+        }
+    } else {
+        quote! {}
     };
 
     Ok(quote! {
+        #try_from
+
+        #owned_registry
+
+        #builder
+
         #[allow(
             clippy::vec_init_then_push,
             clippy::redundant_clone,
@@ -77,23 +865,68 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
             unused,
             unused_mut
         )]
-        impl prometheus_metric_storage::MetricStorage for #name {
+        impl #impl_generics #krate::MetricStorage for #name #ty_generics #where_clause {
             fn const_labels() -> &'static [&'static str] {
                 &[#(#labels,)*]
             }
 
+            fn metric_names() -> &'static [&'static str] {
+                &[#(#metric_names,)*]
+            }
+
+            fn field_labels() -> &'static [(&'static str, &'static [&'static str])] {
+                &[#(#field_labels,)*]
+            }
+
             fn from_const_labels_unregistered(
                 const_labels: std::collections::HashMap<String, String>
-            ) -> prometheus_metric_storage::Result<Self> {
+            ) -> #krate::Result<Self> {
+                #fixed_labels
                 Ok(#init)
             }
 
-            fn register(
-                &self, registry: &prometheus_metric_storage::Registry
-            ) -> prometheus_metric_storage::Result<()> {
+            fn register<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> #krate::Result<()> {
+                Ok(<Self as #krate::MetricStorage>::register_fields(self, registry)?)
+            }
+
+            fn register_fields<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> Result<(), #krate::MetricStorageError> {
                 #reg
                 Ok(())
             }
+
+            fn unregister<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> #krate::Result<()> {
+                #unreg
+                Ok(())
+            }
+
+            fn descs(&self) -> Vec<(Option<#krate::MetricKind>, #krate::Desc)> {
+                let mut descs = Vec::new();
+                #descs
+                descs
+            }
+
+            fn histogram_buckets(&self) -> Vec<(String, Vec<f64>)> {
+                let mut buckets = Vec::new();
+                #histogram_buckets
+                buckets
+            }
+
+            fn field_descs(&self, field: &str) -> Option<Vec<#krate::Desc>> {
+                #field_descs
+                None
+            }
+
+            fn snapshot(&self) -> Vec<#krate::MetricFamily> {
+                let mut families = Vec::new();
+                #snapshot
+                families
+            }
         }
 
         #[allow(
@@ -103,132 +936,1437 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
             unused,
             unused_mut
         )]
-        impl #name {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #inline
             fn new_unregistered(
-                #(#label_idents: impl Into<String>,)*
-            ) -> prometheus_metric_storage::Result<Self> {
+                #(#label_params,)*
+            ) -> #krate::Result<Self> {
+                let mut const_labels = std::collections::HashMap::new();
+                #(const_labels.insert(#labels.to_string(), #label_values);)*
+
+                <Self as #krate::MetricStorage>::from_const_labels_unregistered(const_labels)
+            }
+
+            #inline
+            fn from_parts(#(#part_idents: #part_types,)*) -> Self {
+                #from_parts
+            }
+
+            #inline
+            fn new(
+                registry: &(impl #krate::MetricRegistry + ?Sized), #(#label_params,)*
+            ) -> Result<Self, #krate::MetricStorageError> {
+                let metrics = Self::new_unregistered(#(#label_idents,)*).map_err(#storage_error)?;
+                <Self as #krate::MetricStorage>::register_fields(&metrics, registry)?;
+                Ok(metrics)
+            }
+
+            #rebind
+
+            #inline
+            fn instance(
+                registry: &#krate::StorageRegistry<impl #krate::MetricRegistry>, #(#label_params,)*
+            ) -> Result<&Self, #krate::MetricStorageError>
+            where
+                Self: Send + Sync + 'static,
+            {
                 let mut const_labels = std::collections::HashMap::new();
-                #(const_labels.insert(#labels.to_string(), #label_idents.into());)*
+                #(const_labels.insert(#labels.to_string(), #label_values);)*
+
+                registry.get_or_create_storage::<Self>(const_labels).map_err(#registry_error)
+            }
+
+            #inline
+            fn register_into_group(
+                &self, registry: &#krate::StorageRegistry<impl #krate::MetricRegistry>, group: &str
+            ) -> Result<(), #krate::MetricStorageError> {
+                registry.register_in_group(self, group)
+            }
+
+            #with_defaults
+
+            #(#observers)*
+
+            #(#bucket_getters)*
+
+            #(#per_variant_getters)*
+
+            #(#lazy_getters)*
+
+            #(#typed_getters)*
+
+            #(#enablers)*
+        }
+    })
+}
+
+fn expand_enum(
+    name: Ident,
+    generics: Generics,
+    input: DataEnum,
+    config: &StructConfig,
+) -> Result<TokenStream> {
+    let krate = &config.krate;
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let labels = &config.labels;
+    let label_idents: Vec<_> = labels
+        .iter()
+        .map(|l| Ident::new(l, Span::call_site()))
+        .collect();
+    let label_params: Vec<_> = labels.iter().map(|l| config.label_param(l)).collect();
+    let label_values: Vec<_> = labels.iter().map(|l| config.label_value(l)).collect();
+
+    let mut default_variant = None;
+    let mut variants = Vec::new();
+    for variant in &input.variants {
+        if parse_variant_attrs(&variant.attrs)? {
+            if default_variant.is_some() {
+                return Err(Error::new(variant.span(), "default variant is redefined"));
+            }
+            default_variant = Some(&variant.ident);
+        }
+
+        let fields = match &variant.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .map(|field| MetricField::parse(field.ident.to_token_stream(), field, config))
+                .collect::<Result<Vec<_>>>()?,
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(Error::new(
+                    variant.span(),
+                    "MetricStorage enums can only have struct-like or unit variants",
+                ))
+            }
+        };
+
+        if fields.iter().any(|field| field.optional) {
+            return Err(Error::new(
+                variant.span(),
+                "`optional` fields can only be used in structs",
+            ));
+        }
+
+        if fields.iter().any(|field| !field.label_types.is_empty()) {
+            return Err(Error::new(
+                variant.span(),
+                "field-level `label_types` can only be used in structs",
+            ));
+        }
+
+        variants.push((&variant.ident, fields));
+    }
+
+    let arms = |body: &dyn Fn(&MetricField) -> TokenStream| {
+        let arms = variants.iter().map(|(variant, fields)| {
+            let members = fields.iter().map(|field| &field.member);
+            let bodies = fields.iter().map(body);
+            quote! {
+                Self::#variant { #(#members,)* } => { #(#bodies)* }
+            }
+        });
+        quote! {
+            match self {
+                #(#arms)*
+            }
+        }
+    };
+
+    let reg = arms(&|field| field.register(config, &field.member));
+    let unreg = arms(&|field| field.unregister(krate, &field.member));
+    let descs = arms(&|field| field.descs(krate, &field.member));
+    let histogram_buckets = arms(&|field| field.histogram_buckets(krate, &field.member));
+    let field_descs = arms(&|field| field.field_descs(krate, &field.member));
+    let snapshot = arms(&|field| field.snapshot(krate, &field.member));
+
+    let mut metric_names = Vec::new();
+    let mut field_labels = Vec::new();
+    for (_, fields) in &variants {
+        for field in fields {
+            if let Some(name) = &field.name {
+                if !metric_names.contains(&name) {
+                    metric_names.push(name);
+                    field_labels.extend(field.field_labels());
+                }
+            }
+        }
+    }
+
+    let fixed_labels = config.fixed_labels_init();
+    let storage_error = config.storage_error();
+    let registry_error = config.registry_error();
+    let variant_init = |variant: &Ident| {
+        let (_, fields) = variants.iter().find(|(v, _)| *v == variant).unwrap();
+        let members = fields.iter().map(|field| &field.member);
+        let inits = fields.iter().map(|field| &field.init);
+        quote! {
+            #fixed_labels
+            Ok(Self::#variant { #(#members: #inits,)* })
+        }
+    };
+
+    let constructors = variants.iter().map(|(variant, _)| {
+        let snake = to_snake_case(&variant.to_string());
+        let new = Ident::new(&format!("new_{}", snake), variant.span());
+        let new_unregistered = Ident::new(&format!("new_unregistered_{}", snake), variant.span());
+        let init = variant_init(variant);
+
+        quote! {
+            fn #new_unregistered(
+                #(#label_params,)*
+            ) -> #krate::Result<Self> {
+                let mut const_labels = std::collections::HashMap::<String, String>::new();
+                #(const_labels.insert(#labels.to_string(), #label_values);)*
+
+                #init
+            }
+
+            fn #new(
+                registry: &(impl #krate::MetricRegistry + ?Sized), #(#label_params,)*
+            ) -> Result<Self, #krate::MetricStorageError> {
+                let metrics = Self::#new_unregistered(#(#label_idents,)*).map_err(#storage_error)?;
+                <Self as #krate::MetricStorage>::register_fields(&metrics, registry)?;
+                Ok(metrics)
+            }
+        }
+    });
+
+    let (default_init, default_constructors) = match default_variant {
+        Some(variant) => {
+            let new_unregistered = Ident::new(
+                &format!("new_unregistered_{}", to_snake_case(&variant.to_string())),
+                variant.span(),
+            );
+            let constructors = quote! {
+                fn new_unregistered(
+                    #(#label_params,)*
+                ) -> #krate::Result<Self> {
+                    Self::#new_unregistered(#(#label_idents,)*)
+                }
+
+                fn new(
+                    registry: &(impl #krate::MetricRegistry + ?Sized), #(#label_params,)*
+                ) -> Result<Self, #krate::MetricStorageError> {
+                    let metrics =
+                        Self::new_unregistered(#(#label_idents,)*).map_err(#storage_error)?;
+                    <Self as #krate::MetricStorage>::register_fields(&metrics, registry)?;
+                    Ok(metrics)
+                }
+
+                fn instance(
+                    registry: &#krate::StorageRegistry<impl #krate::MetricRegistry>, #(#label_params,)*
+                ) -> Result<&Self, #krate::MetricStorageError>
+                where
+                    Self: Send + Sync + 'static,
+                {
+                    let mut const_labels = std::collections::HashMap::new();
+                    #(const_labels.insert(#labels.to_string(), #label_values);)*
+
+                    registry.get_or_create_storage::<Self>(const_labels).map_err(#registry_error)
+                }
+            };
+            (variant_init(variant), constructors)
+        }
+        None => {
+            let message = format!("metric storage {} has no default variant", name);
+            let init = quote! {
+                Err(#krate::Error::Msg(#message.to_string()))
+            };
+            (init, quote! {})
+        }
+    };
+
+    Ok(quote! {
+        #[allow(
+            clippy::vec_init_then_push,
+            clippy::redundant_clone,
+            clippy::let_and_return,
+            unused,
+            unused_mut
+        )]
+        impl #impl_generics #krate::MetricStorage for #name #ty_generics #where_clause {
+            fn const_labels() -> &'static [&'static str] {
+                &[#(#labels,)*]
+            }
+
+            fn metric_names() -> &'static [&'static str] {
+                &[#(#metric_names,)*]
+            }
+
+            fn field_labels() -> &'static [(&'static str, &'static [&'static str])] {
+                &[#(#field_labels,)*]
+            }
+
+            fn from_const_labels_unregistered(
+                const_labels: std::collections::HashMap<String, String>
+            ) -> #krate::Result<Self> {
+                #default_init
+            }
+
+            fn register<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> #krate::Result<()> {
+                Ok(<Self as #krate::MetricStorage>::register_fields(self, registry)?)
+            }
+
+            fn register_fields<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> Result<(), #krate::MetricStorageError> {
+                #reg
+                Ok(())
+            }
+
+            fn unregister<__R: #krate::MetricRegistry + ?Sized>(
+                &self, registry: &__R
+            ) -> #krate::Result<()> {
+                #unreg
+                Ok(())
+            }
+
+            fn descs(&self) -> Vec<(Option<#krate::MetricKind>, #krate::Desc)> {
+                let mut descs = Vec::new();
+                #descs
+                descs
+            }
+
+            fn histogram_buckets(&self) -> Vec<(String, Vec<f64>)> {
+                let mut buckets = Vec::new();
+                #histogram_buckets
+                buckets
+            }
+
+            fn field_descs(&self, field: &str) -> Option<Vec<#krate::Desc>> {
+                #field_descs
+                None
+            }
+
+            fn snapshot(&self) -> Vec<#krate::MetricFamily> {
+                let mut families = Vec::new();
+                #snapshot
+                families
+            }
+        }
+
+        #[allow(
+            clippy::vec_init_then_push,
+            clippy::redundant_clone,
+            clippy::let_and_return,
+            unused,
+            unused_mut
+        )]
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#constructors)*
+
+            #default_constructors
+        }
+    })
+}
+
+/// Parse `#[metric(...)]` attributes of an enum variant,
+/// return `true` if the variant is marked as default.
+fn parse_variant_attrs(attrs: &[syn::Attribute]) -> Result<bool> {
+    let mut is_default = false;
+
+    for attr in attrs {
+        if !attr.path.is_ident("metric") {
+            continue;
+        }
+
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            _ => {
+                return Err(Error::new(
+                    attr.path.span(),
+                    "value for the `metric` attribute should be a list: `metric(...)`",
+                ))
+            }
+        };
+
+        for attr in list.nested {
+            match attr {
+                NestedMeta::Meta(attr) if attr.path().is_ident("default") => {
+                    is_default = MetricAttrs::parse_flag("default", attr, is_default)?
+                }
+                attr => return Err(Error::new(attr.span(), "unexpected parameter")),
+            }
+        }
+    }
+
+    Ok(is_default)
+}
+
+/// Convert a `CamelCase` identifier to `snake_case`.
+fn to_snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Case conversion for field names, from the struct-level `rename_all` option.
+#[derive(Clone, Copy)]
+enum RenameRule {
+    SnakeCase,
+    ScreamingSnakeCase,
+}
+
+impl RenameRule {
+    fn apply(self, name: &str) -> String {
+        match self {
+            RenameRule::SnakeCase => to_snake_case(name),
+            RenameRule::ScreamingSnakeCase => to_snake_case(name).to_uppercase(),
+        }
+    }
+}
+
+impl StructConfig {
+    /// Constructor parameter for the given const label.
+    fn label_param(&self, label: &str) -> TokenStream {
+        let ident = Ident::new(label, Span::call_site());
+        match self.label_types.iter().find(|(name, _)| name == label) {
+            Some((_, ty)) => quote! { #ident: #ty },
+            None => quote! { #ident: impl Into<String> },
+        }
+    }
+
+    /// Expression that converts constructor parameter
+    /// for the given const label to a `String`.
+    fn label_value(&self, label: &str) -> TokenStream {
+        let krate = &self.krate;
+        let ident = Ident::new(label, Span::call_site());
+        match self.label_types.iter().find(|(name, _)| name == label) {
+            Some(_) => quote! { #krate::MetricLabel::as_str(&#ident).to_string() },
+            None => quote! { #ident.into() },
+        }
+    }
+
+    /// Closure that wraps a prometheus error into a storage error.
+    fn storage_error(&self) -> TokenStream {
+        let krate = &self.krate;
+        let name = &self.name;
+        quote! {
+            |source| #krate::MetricStorageError::Storage { storage: #name, source }
+        }
+    }
+
+    /// Closure that renames storage errors returned by `StorageRegistry`
+    /// after this storage, and keeps errors that name a field.
+    fn registry_error(&self) -> TokenStream {
+        let krate = &self.krate;
+        let storage_error = self.storage_error();
+        quote! {
+            |err| match err {
+                #krate::MetricStorageError::Storage { source, .. } => (#storage_error)(source),
+                err => err,
+            }
+        }
+    }
+
+    /// Statements that add labels from the struct-level `const_labels`
+    /// and `const_labels_fn` options to the `const_labels` map.
+    fn fixed_labels_init(&self) -> TokenStream {
+        let (names, values): (Vec<_>, Vec<_>) = self.fixed_labels.iter().cloned().unzip();
+        let labels_fn = self.fixed_labels_fn.as_ref().map(|labels_fn| {
+            quote! {
+                for (name, value) in #labels_fn() {
+                    const_labels.entry(name).or_insert(value);
+                }
+            }
+        });
+        quote! {
+            let mut const_labels = const_labels;
+            #(const_labels.insert(#names.to_string(), #values.to_string());)*
+            #labels_fn
+        }
+    }
+}
+
+/// Struct-level settings that affect how fields are initialized.
+struct StructConfig {
+    /// Name of the storage type.
+    name: String,
+    krate: Path,
+    namespace: String,
+    /// Separators between namespace, subsystem and name, from the struct-level
+    /// `namespace_separator` and `separator` options. If set, full names
+    /// are built by the derive instead of prometheus.
+    separators: Option<(String, String)>,
+    /// Expression that evaluates to the subsystem string.
+    subsystem: TokenStream,
+    /// Function that computes final metric names.
+    name_fn: Option<Path>,
+    /// Const labels of the struct.
+    labels: Vec<String>,
+    /// Types of const labels, from the struct-level `label_types` option.
+    label_types: Vec<(String, Path)>,
+    /// Const labels with values, from the struct-level `const_labels` option.
+    fixed_labels: Vec<(String, String)>,
+    /// Function that computes const labels at runtime, from the struct-level
+    /// `const_labels_fn` option.
+    fixed_labels_fn: Option<Path>,
+    /// Metric names for fields, from the struct-level `rename` option.
+    renames: Vec<(Ident, String)>,
+    /// Case conversion for field names, from the struct-level `rename_all`
+    /// option.
+    rename_all: Option<RenameRule>,
+    /// Buckets for histograms without their own, from the struct-level
+    /// `buckets` option.
+    default_buckets: Option<Vec<f64>>,
+    max_help_length: Option<usize>,
+}
+
+/// A single metric field of a storage.
+struct MetricField {
+    /// Field name or index.
+    member: TokenStream,
+
+    /// Metric name without namespace and subsystem, if it is known
+    /// at compile time.
+    name: Option<String>,
+
+    /// Field-level variable labels of the metric.
+    labels: Vec<String>,
+
+    /// Expression that initializes the field.
+    init: TokenStream,
+
+    /// Whether the field holds an `OptionalMetric` and needs an enabler.
+    optional: bool,
+
+    /// Expression that initializes the field using buckets from
+    /// the `buckets` variable, used by the generated builder.
+    init_with_buckets: Option<TokenStream>,
+
+    /// Expression that evaluates to the field's `Option<MetricKind>`.
+    kind: TokenStream,
+
+    /// Name of the observation group this field belongs to.
+    group: Option<Ident>,
+
+    /// Label type for a set of per-variant metrics, if `per` was given.
+    per: Option<Path>,
+
+    /// Whether the field holds a `LazyMetric` and needs an accessor.
+    lazy: bool,
+
+    /// Types of variable labels, from the field-level `label_types` option.
+    /// If not empty, the field needs a typed accessor.
+    label_types: Vec<(String, Path)>,
+
+    /// Histogram buckets, if they were configured.
+    buckets: Option<Vec<f64>>,
+
+    /// Struct-level default buckets, used if `buckets` are not configured.
+    default_buckets: Option<Vec<f64>>,
+
+    /// What this field holds.
+    role: FieldRole,
+}
+
+/// Kind of a storage field.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FieldRole {
+    /// A collector, either configured by metric options or external.
+    Metric,
+    /// A nested storage, marked with `flatten`.
+    Flatten,
+    /// A non-metric field, marked with `skip`.
+    Skip,
+}
+
+impl MetricField {
+    fn parse(member: TokenStream, field: &Field, config: &StructConfig) -> Result<Self> {
+        let krate = &config.krate;
+
+        let attrs = MetricAttrs::parse(&field.attrs, false)?;
+        if [attrs.external, attrs.flatten, attrs.skip]
+            .iter()
+            .filter(|flag| **flag)
+            .count()
+            > 1
+        {
+            return Err(Error::new(
+                field.span(),
+                "only one of `external`, `flatten` and `skip` can be used",
+            ));
+        }
+        if attrs.external || attrs.flatten || attrs.skip {
+            return Self::parse_special(member, field, attrs, config);
+        }
+
+        let MetricAttrs {
+            name,
+            name_span,
+            help,
+            help_span,
+            help_from,
+            labels,
+            buckets,
+            group,
+            per,
+            sanitize_name,
+            positive_buckets,
+            optional,
+            lazy,
+            init_values,
+            subsystem,
+            label_types,
+            ..
+        } = attrs;
+
+        let subsystem = match subsystem {
+            Some(subsystem) => quote! { #subsystem.to_string() },
+            None => config.subsystem.clone(),
+        };
+
+        if let Some(span) = lazy {
+            if field.ident.is_none() {
+                return Err(Error::new(
+                    span,
+                    "`lazy` can only be used with named fields",
+                ));
+            }
+            if per.is_some() || optional.is_some() || init_values.is_some() || group.is_some() {
+                return Err(Error::new(
+                    span,
+                    "`lazy` can't be used together with `per`, `optional`, \
+                     `init_values` or `group`",
+                ));
+            }
+        }
+
+        if let Some(per) = &per {
+            if field.ident.is_none() {
+                return Err(Error::new(
+                    per.span(),
+                    "`per` can only be used with named fields",
+                ));
+            }
+            if init_values.is_some() || group.is_some() {
+                return Err(Error::new(
+                    per.span(),
+                    "`per` can't be used together with `init_values` or `group`",
+                ));
+            }
+        }
+
+        let ty = &field.ty;
+        if let Some(span) = optional {
+            if field.ident.is_none() {
+                return Err(Error::new(
+                    span,
+                    "`optional` can only be used with named fields",
+                ));
+            }
+            if per.is_some() || init_values.is_some() || group.is_some() {
+                return Err(Error::new(
+                    span,
+                    "`optional` can't be used together with `per`, `init_values` or `group`",
+                ));
+            }
+            if !is_optional_metric(&field.ty) {
+                return Err(Error::new(
+                    field.ty.span(),
+                    "`optional` fields should be of type `OptionalMetric<...>`",
+                ));
+            }
+        }
+
+        let rename = config
+            .renames
+            .iter()
+            .find(|(ident, _)| Some(ident) == field.ident.as_ref())
+            .map(|(_, name)| name.clone());
+        let name = match (name, rename) {
+            (Some(_), Some(_)) => {
+                return Err(Error::new(
+                    field.span(),
+                    "field has both `name` and an entry in struct-level `rename`",
+                ))
+            }
+            (name, rename) => name.or(rename),
+        };
+        let name = name.or_else(|| {
+            let name = field.ident.as_ref()?.to_string();
+            Some(match config.rename_all {
+                Some(rule) => rule.apply(&name),
+                None => name,
+            })
+        });
+        let name = match name {
+            Some(name) if sanitize_name => Some(sanitize(&name)),
+            name => name,
+        };
+        let name = match name {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                return Err(Error::new(
+                    field.span(),
+                    "metric name is required, consider adding `#[metric(name = \"...\")]`",
+                ))
+            }
+        };
+
+        // With `name_fn`, the final name is up to the function.
+        if config.name_fn.is_none() && !is_valid_name(&name) {
+            return Err(Error::new(
+                name_span.unwrap_or_else(|| field.span()),
+                format!(
+                    "`{}` is not a valid metric name, it should match \
+                     `[a-zA-Z_:][a-zA-Z0-9_:]*`; consider adding `sanitize_name`",
+                    name
+                ),
+            ));
+        }
+
+        let help = match help_from {
+            Some(_) if help_span.is_some() => {
+                return Err(Error::new(
+                    help_span.unwrap(),
+                    "`help` and `help_from` can't be used together",
+                ))
+            }
+            // Help messages from constants are only known at runtime,
+            // so they can't be checked here.
+            Some(help_from) => help_from.to_token_stream(),
+            None => {
+                let help = match (help, help_span) {
+                    (Some(help), Some(span)) => Some(
+                        expand_help(&help, &name).map_err(|message| Error::new(span, message))?,
+                    ),
+                    (help, _) => help,
+                };
+
+                let help = match help {
+                    Some(help) if !help.is_empty() => help,
+                    _ => {
+                        return Err(Error::new(
+                            field.span(),
+                            "metric help message is required, consider adding a docstring",
+                        ))
+                    }
+                };
+
+                if let Some(max_help_length) = config.max_help_length {
+                    if help.chars().count() > max_help_length {
+                        return Err(Error::new(
+                            field.span(),
+                            format!(
+                                "metric help message is longer than {} characters",
+                                max_help_length
+                            ),
+                        ));
+                    }
+                }
+
+                if cfg!(feature = "strict") && help.chars().any(char::is_control) {
+                    return Err(Error::new(
+                        field.span(),
+                        "metric help message should not contain control characters",
+                    ));
+                }
+
+                help.to_token_stream()
+            }
+        };
+
+        if let Some(buckets) = &buckets {
+            if positive_buckets {
+                if let Some(bucket) = buckets.iter().find(|bucket| **bucket <= 0.0) {
+                    return Err(Error::new(
+                        field.span(),
+                        format!(
+                            "bucket {:?} is not positive, but `positive_buckets` is set",
+                            bucket
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let labels = labels.unwrap_or_default();
+
+        for (name, ty) in &label_types {
+            if !labels.contains(name) {
+                return Err(Error::new(ty.span(), format!("no such label `{}`", name)));
+            }
+        }
+        if let Some((_, ty)) = label_types.first() {
+            if field.ident.is_none() {
+                return Err(Error::new(
+                    ty.span(),
+                    "`label_types` can only be used with named fields",
+                ));
+            }
+            if per.is_some() || optional.is_some() || lazy.is_some() {
+                return Err(Error::new(
+                    ty.span(),
+                    "`label_types` can't be used together with `per`, `optional` or `lazy`",
+                ));
+            }
+        }
+
+        if let Some(label) = labels
+            .iter()
+            .find(|label| config.fixed_labels.iter().any(|(name, _)| name == *label))
+        {
+            return Err(Error::new(
+                field.span(),
+                format!(
+                    "label `{}` is already defined in struct-level `const_labels`",
+                    label
+                ),
+            ));
+        }
+
+        if let Some(label) = labels.iter().find(|label| config.labels.contains(label)) {
+            return Err(Error::new(
+                field.span(),
+                format!(
+                    "label `{}` is already defined in struct-level `labels`",
+                    label
+                ),
+            ));
+        }
+
+        let init_values = match init_values {
+            Some((_, values)) if labels.len() == 1 => values,
+            Some((span, _)) => {
+                return Err(Error::new(
+                    span,
+                    "`init_values` can only be used with metrics that have exactly one label",
+                ))
+            }
+            None => Vec::new(),
+        };
+
+        let base_name = name.clone();
+        let metric_labels = labels.clone();
+        let namespace = &config.namespace;
+        let (namespace, subsystem, name) = match &config.name_fn {
+            Some(name_fn) => (
+                quote! { "".to_string() },
+                quote! { "".to_string() },
+                quote! { #name_fn(#namespace, &#subsystem, #name) },
+            ),
+            None => match &config.separators {
+                Some((namespace_separator, separator)) => {
+                    let prefix = if namespace.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{}{}", namespace, namespace_separator)
+                    };
+                    (
+                        quote! { "".to_string() },
+                        quote! { "".to_string() },
+                        quote! {
+                            {
+                                let subsystem: String = #subsystem;
+                                if subsystem.is_empty() {
+                                    format!("{}{}", #prefix, #name)
+                                } else {
+                                    format!("{}{}{}{}", #prefix, subsystem, #separator, #name)
+                                }
+                            }
+                        },
+                    )
+                }
+                None => (
+                    quote! { #namespace.to_string() },
+                    subsystem,
+                    quote! { #name.to_string() },
+                ),
+            },
+        };
+
+        let opts = quote_spanned! { field.span() =>
+            #krate::Opts {
+                namespace: #namespace,
+                subsystem: #subsystem,
+                name: #name,
+                help: (#help).to_string(),
+                const_labels: const_labels.clone(),
+                variable_labels: {
+                    let mut labels = Vec::new();
+                    #(labels.push(#labels.to_string());)*
+                    labels
+                }
+            }
+        };
+
+        let init = if let Some(buckets) = &buckets {
+            quote_spanned! { field.span() =>
+                #krate::HistMetricInit::init(
+                    #opts,
+                    {
+                        let mut buckets = Vec::new();
+                        #(buckets.push(#buckets);)*
+                        buckets
+                    }
+                )?
+            }
+        } else if let Some(buckets) = &config.default_buckets {
+            quote_spanned! { field.span() =>
+                #krate::MetricInit::init_with_default_buckets(#opts, &[#(#buckets),*])?
+            }
+        } else {
+            quote! {
+                #krate::MetricInit::init(#opts)?
+            }
+        };
+
+        let init_with_buckets = quote_spanned! { field.span() =>
+            #krate::MetricInit::init_with_default_buckets(#opts, &buckets)?
+        };
+
+        let with_init_values = |init: TokenStream| {
+            if init_values.is_empty() {
+                init
+            } else {
+                quote_spanned! { field.span() =>
+                    {
+                        let metric: #ty = #init;
+                        #(metric.with_label_values(&[#init_values]);)*
+                        metric
+                    }
+                }
+            }
+        };
+        let init = with_init_values(init);
+        let init_with_buckets = Some(with_init_values(init_with_buckets));
+
+        Ok(Self {
+            member,
+            name: Some(base_name),
+            labels: metric_labels,
+            init,
+            optional: optional.is_some(),
+            init_with_buckets,
+            kind: quote! { <#ty as #krate::MetricInit>::kind() },
+            group,
+            per,
+            lazy: lazy.is_some(),
+            label_types,
+            buckets,
+            default_buckets: config.default_buckets.clone(),
+            role: FieldRole::Metric,
+        })
+    }
+
+    /// Entry of the `field_labels` array for this field.
+    fn field_labels(&self) -> Option<TokenStream> {
+        let name = self.name.as_ref()?;
+        let labels = &self.labels;
+        Some(quote! { (#name, &[#(#labels,)*]) })
+    }
+
+    /// Statement that adds the field to `registry`. `target` is an expression
+    /// that evaluates to a reference to the field.
+    fn register(&self, config: &StructConfig, target: &TokenStream) -> TokenStream {
+        let krate = &config.krate;
+        let storage = &config.name;
+        let field = self.member.to_string();
+        match self.role {
+            FieldRole::Metric => self.if_enabled(krate, target, |target| {
+                quote! {
+                    #krate::MetricRegistry::register(registry, Box::new(Clone::clone(#target)))
+                        .map_err(|source| #krate::MetricStorageError::Register {
+                            storage: #storage,
+                            field: #field,
+                            source,
+                        })?;
+                }
+            }),
+            FieldRole::Flatten => quote! {
+                #krate::MetricStorage::register_fields(#target, registry)?;
+            },
+            FieldRole::Skip => quote! {},
+        }
+    }
+
+    /// Statement that removes the field from `registry`.
+    fn unregister(&self, krate: &Path, target: &TokenStream) -> TokenStream {
+        match self.role {
+            FieldRole::Metric => self.if_enabled(krate, target, |target| {
+                quote! {
+                    #krate::MetricRegistry::unregister(registry, Box::new(Clone::clone(#target)))?;
+                }
+            }),
+            FieldRole::Flatten => quote! { #krate::MetricStorage::unregister(#target, registry)?; },
+            FieldRole::Skip => quote! {},
+        }
+    }
+
+    /// Statement that appends the field's descriptors to `descs`.
+    fn descs(&self, krate: &Path, target: &TokenStream) -> TokenStream {
+        match self.role {
+            FieldRole::Metric => {}
+            FieldRole::Flatten => {
+                return quote! { descs.extend(#krate::MetricStorage::descs(#target)); }
+            }
+            FieldRole::Skip => return quote! {},
+        }
+
+        let kind = &self.kind;
+        self.if_enabled(krate, target, |target| {
+            quote! {
+                let kind = #kind;
+                for desc in #krate::Collector::desc(#target) {
+                    descs.push((kind, desc.clone()));
+                }
+            }
+        })
+    }
+
+    /// Statement that appends the field's histogram buckets to `buckets`.
+    fn histogram_buckets(&self, krate: &Path, target: &TokenStream) -> TokenStream {
+        match self.role {
+            FieldRole::Metric => {}
+            FieldRole::Flatten => {
+                return quote! {
+                    buckets.extend(#krate::MetricStorage::histogram_buckets(#target));
+                }
+            }
+            FieldRole::Skip => return quote! {},
+        }
+
+        let field_buckets = self.buckets_expr(krate);
+        self.if_enabled(krate, target, |target| {
+            quote! {
+                if let Some(field_buckets) = #field_buckets {
+                    for desc in #krate::Collector::desc(#target) {
+                        buckets.push((desc.fq_name.clone(), field_buckets.clone()));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Statement that returns the field's descriptors if its name
+    /// is equal to `field`.
+    fn field_descs(&self, krate: &Path, target: &TokenStream) -> TokenStream {
+        let name = self.member.to_string();
+        match self.role {
+            FieldRole::Metric if self.optional => quote! {
+                if field == #name {
+                    return Some(match #krate::OptionalMetric::get(#target) {
+                        Some(metric) => {
+                            #krate::Collector::desc(metric).into_iter().cloned().collect()
+                        }
+                        None => Vec::new(),
+                    });
+                }
+            },
+            FieldRole::Metric => quote! {
+                if field == #name {
+                    return Some(#krate::Collector::desc(#target).into_iter().cloned().collect());
+                }
+            },
+            FieldRole::Flatten => quote! {
+                if field == #name {
+                    let descs = #krate::MetricStorage::descs(#target);
+                    return Some(descs.into_iter().map(|(_, desc)| desc).collect());
+                }
+            },
+            FieldRole::Skip => quote! {},
+        }
+    }
 
-                <Self as prometheus_metric_storage::MetricStorage>::from_const_labels_unregistered(const_labels)
+    /// Statement that appends the field's current values to `families`.
+    fn snapshot(&self, krate: &Path, target: &TokenStream) -> TokenStream {
+        match self.role {
+            FieldRole::Metric => self.if_enabled(krate, target, |target| {
+                quote! { families.extend(#krate::Collector::collect(#target)); }
+            }),
+            FieldRole::Flatten => {
+                quote! { families.extend(#krate::MetricStorage::snapshot(#target)); }
             }
+            FieldRole::Skip => quote! {},
+        }
+    }
 
-            fn new(
-                registry: &prometheus_metric_storage::Registry, #(#label_idents: impl Into<String>,)*
-            ) -> prometheus_metric_storage::Result<Self> {
-                let metrics = Self::new_unregistered(#(#label_idents,)*)?;
-                <Self as prometheus_metric_storage::MetricStorage>::register(&metrics, registry)?;
-                Ok(metrics)
+    /// Wrap statements produced by `body` so that they're skipped
+    /// if the field is `optional` and is not enabled.
+    fn if_enabled(
+        &self,
+        krate: &Path,
+        target: &TokenStream,
+        body: impl Fn(&TokenStream) -> TokenStream,
+    ) -> TokenStream {
+        if !self.optional {
+            return body(target);
+        }
+
+        let body = body(&quote! { metric });
+        quote! {
+            if let Some(metric) = #krate::OptionalMetric::get(#target) {
+                #body
             }
+        }
+    }
 
-            fn instance(
-                registry: &prometheus_metric_storage::StorageRegistry, #(#label_idents: impl Into<String>,)*
-            ) -> prometheus_metric_storage::Result<&Self> {
-                let mut const_labels = std::collections::HashMap::new();
-                #(const_labels.insert(#labels.to_string(), #label_idents.into());)*
+    /// Expression that evaluates to the field's `Option<Vec<f64>>` buckets.
+    fn buckets_expr(&self, krate: &Path) -> TokenStream {
+        if let Some(buckets) = &self.buckets {
+            return quote! { Some(vec![#(#buckets),*]) };
+        }
 
-                registry.get_or_create_storage::<Self>(const_labels)
+        let default_buckets = match &self.default_buckets {
+            Some(buckets) => quote! { vec![#(#buckets),*] },
+            None => quote! { #krate::DEFAULT_BUCKETS.to_vec() },
+        };
+        let kind = &self.kind;
+        quote! {
+            match #kind {
+                Some(kind) if kind.is_histogram() => Some(#default_buckets),
+                _ => None,
             }
         }
-    })
-}
+    }
 
-fn initializers(
-    fields: impl Iterator<Item = Field>,
-    subsystem: String,
-) -> Result<Vec<TokenStream>> {
-    fields
-        .map(|field| {
-            let MetricAttrs {
-                name,
-                help,
-                labels,
-                buckets,
-                ..
-            } = MetricAttrs::parse(&field.attrs, false)?;
-
-            let name = name.or_else(|| field.ident.as_ref().map(|ident| ident.to_string()));
-            let name = match name {
-                Some(name) if !name.is_empty() => name,
-                _ => {
-                    return Err(Error::new(
-                        field.span(),
-                        "metric name is required, consider adding `#[metric(name = \"...\")]`",
-                    ))
-                }
-            };
+    /// Parse a field that is not configured by metric options:
+    /// an external collector, a nested storage or a skipped field.
+    fn parse_special(
+        member: TokenStream,
+        field: &Field,
+        attrs: MetricAttrs,
+        config: &StructConfig,
+    ) -> Result<Self> {
+        let krate = &config.krate;
 
-            let help = match help {
-                Some(help) if !help.is_empty() => help,
-                _ => {
-                    return Err(Error::new(
-                        field.span(),
-                        "metric help message is required, consider adding a docstring",
-                    ))
-                }
-            };
+        let role = if attrs.flatten {
+            FieldRole::Flatten
+        } else if attrs.skip {
+            FieldRole::Skip
+        } else {
+            FieldRole::Metric
+        };
 
-            let labels = labels.unwrap_or_default();
-
-            let opts = quote_spanned! { field.span() =>
-                prometheus_metric_storage::Opts {
-                    namespace: "".to_string(),
-                    subsystem: #subsystem.to_string(),
-                    name: #name.to_string(),
-                    help: #help.to_string(),
-                    const_labels: const_labels.clone(),
-                    variable_labels: {
-                        let mut labels = Vec::new();
-                        #(labels.push(#labels.to_string());)*
-                        labels
-                    }
+        let has_metric_options = attrs.name.is_some()
+            || attrs.labels.is_some()
+            || attrs.buckets.is_some()
+            || attrs.group.is_some()
+            || attrs.per.is_some()
+            || attrs.help_from.is_some()
+            || attrs.init_values.is_some()
+            || attrs.optional.is_some()
+            || attrs.lazy.is_some()
+            || attrs.subsystem.is_some()
+            || !attrs.label_types.is_empty()
+            || attrs.sanitize_name
+            || attrs.positive_buckets;
+        if has_metric_options {
+            let message = match role {
+                FieldRole::Metric => {
+                    "external fields are initialized via `Default`, \
+                     they can't have metric options"
+                }
+                FieldRole::Flatten => {
+                    "flattened fields are configured by their own type, \
+                     they can't have metric options"
                 }
+                FieldRole::Skip => "skipped fields are not metrics, they can't have metric options",
             };
+            return Err(Error::new(field.span(), message));
+        }
 
-            if let Some(buckets) = buckets {
-                Ok(quote_spanned! { field.span() =>
-                    prometheus_metric_storage::HistMetricInit::init(
-                        #opts,
-                        {
-                            let mut buckets = Vec::new();
-                            #(buckets.push(#buckets);)*
-                            buckets
+        let ty = &field.ty;
+        let init = if role == FieldRole::Flatten {
+            let message = format!(
+                "nested storage {} requires const label {{}}, \
+                 which is not defined by the outer storage",
+                ty.to_token_stream()
+            );
+            quote_spanned! { field.span() =>
+                {
+                    for label in <#ty as #krate::MetricStorage>::const_labels() {
+                        if !const_labels.contains_key(*label) {
+                            return Err(#krate::Error::Msg(format!(#message, label)));
                         }
+                    }
+                    <#ty as #krate::MetricStorage>::from_const_labels_unregistered(
+                        const_labels.clone()
                     )?
-                })
-            } else {
-                Ok(quote! {
-                    prometheus_metric_storage::MetricInit::init(#opts)?
-                })
+                }
             }
+        } else {
+            quote_spanned! { ty.span() => <#ty as ::std::default::Default>::default() }
+        };
+
+        Ok(Self {
+            member,
+            name: None,
+            labels: Vec::new(),
+            init,
+            optional: false,
+            init_with_buckets: None,
+            kind: quote! { None::<#krate::MetricKind> },
+            group: None,
+            per: None,
+            lazy: false,
+            label_types: Vec::new(),
+            buckets: None,
+            default_buckets: None,
+            role,
         })
-        .collect()
+    }
+}
+
+/// Check whether the given type is `OptionalMetric<T>`.
+fn is_optional_metric(ty: &syn::Type) -> bool {
+    let path = match ty {
+        syn::Type::Path(ty) if ty.qself.is_none() => &ty.path,
+        _ => return false,
+    };
+    match path.segments.last() {
+        Some(segment) => {
+            segment.ident == "OptionalMetric"
+                && matches!(
+                    &segment.arguments,
+                    syn::PathArguments::AngleBracketed(args) if args.args.len() == 1
+                )
+        }
+        None => false,
+    }
+}
+
+/// Replace `{name}` placeholders in an explicitly given help message
+/// with the metric name. Braces are escaped by doubling them.
+fn expand_help(template: &str, name: &str) -> std::result::Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                let mut is_closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        is_closed = true;
+                        break;
+                    }
+                    placeholder.push(c);
+                }
+                if !is_closed {
+                    return Err(
+                        "unmatched `{` in help message, use `{{` to escape braces".to_string()
+                    );
+                }
+                if placeholder != "name" {
+                    return Err(format!(
+                        "unknown placeholder `{{{}}}` in help message, \
+                         use `{{{{` and `}}}}` to escape braces",
+                        placeholder
+                    ));
+                }
+                result.push_str(name);
+            }
+            '}' => {
+                return Err("unmatched `}` in help message, use `}}` to escape braces".to_string())
+            }
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Check that `name` matches `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+fn is_valid_name(name: &str) -> bool {
+    name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    })
+}
+
+/// Check that `name` matches `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn is_valid_label_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()))
+}
+
+/// Replace characters that aren't allowed in metric names with underscores.
+///
+/// Runs of underscores in the result are collapsed into a single one,
+/// regardless of whether they come from replaced characters or from
+/// the original name.
+fn sanitize(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+
+    for (i, c) in name.chars().enumerate() {
+        let is_valid =
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit());
+        if is_valid && c != '_' {
+            result.push(c);
+        } else if c.is_ascii_digit() {
+            result.push('_');
+            result.push(c);
+        } else if !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+
+    result
+}
+
+/// A single argument of the `#[metric(...)]` attribute.
+enum MetricArg {
+    Meta(NestedMeta),
+    RangeBuckets(RangeBuckets),
+}
+
+impl Parse for MetricArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let fork = input.fork();
+        match fork.parse() {
+            Ok(range) => {
+                input.advance_to(&fork);
+                Ok(MetricArg::RangeBuckets(range))
+            }
+            Err(_) => Ok(MetricArg::Meta(input.parse()?)),
+        }
+    }
+}
+
+/// Evenly spaced buckets given as an inclusive range:
+/// `buckets(start..=end, count = n)`.
+///
+/// Only the syntax is checked when parsing, values are validated
+/// by [`MetricAttrs::parse_range_buckets`].
+struct RangeBuckets {
+    span: Span,
+    /// Range start and whether it's negated.
+    start: (bool, Lit),
+    /// Range end and whether it's negated.
+    end: (bool, Lit),
+    count: Lit,
+}
+
+impl RangeBuckets {
+    fn parse_bound(input: ParseStream) -> Result<(bool, Lit)> {
+        let is_negative = input.parse::<Option<Token![-]>>()?.is_some();
+        Ok((is_negative, input.parse()?))
+    }
 }
 
-fn registrators<I: Iterator<Item = T>, T: ToTokens>(ident: I) -> TokenStream {
-    quote! { #(registry.register(Box::new(self.#ident.clone()))?;)* }
+impl Parse for RangeBuckets {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let ident: Ident = input.parse()?;
+        if ident != "buckets" {
+            return Err(Error::new(ident.span(), "expected `buckets`"));
+        }
+
+        let content;
+        syn::parenthesized!(content in input);
+        let start = Self::parse_bound(&content)?;
+        content.parse::<Token![..=]>()?;
+        let end = Self::parse_bound(&content)?;
+        content.parse::<Token![,]>()?;
+        let count_ident: Ident = content.parse()?;
+        if count_ident != "count" {
+            return Err(Error::new(count_ident.span(), "expected `count`"));
+        }
+        content.parse::<Token![=]>()?;
+        let count = content.parse()?;
+        content.parse::<Option<Token![,]>>()?;
+        if !content.is_empty() {
+            return Err(content.error("unexpected parameter"));
+        }
+
+        Ok(Self {
+            span: ident.span(),
+            start,
+            end,
+            count,
+        })
+    }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default)]
 struct MetricAttrs {
+    krate: Option<Path>,
+    name_fn: Option<Path>,
+    renames: Vec<(Ident, String)>,
+    rename_all: Option<RenameRule>,
+    namespace: Option<String>,
+    separator: Option<String>,
+    namespace_separator: Option<String>,
     subsystem: Option<String>,
+    subsystem_from_module: Option<Span>,
+    inline: bool,
+    /// Span of the `owned_registry` flag, if it was given.
+    owned_registry: Option<Span>,
+    /// Span of the `builder` flag, if it was given.
+    builder: Option<Span>,
+    max_help_length: Option<usize>,
+    max_labels: Option<usize>,
     name: Option<String>,
+    /// Span of the `name` value, if metric name was given explicitly.
+    name_span: Option<Span>,
     help: Option<String>,
+    /// Span of the `help` attribute, if help message was given explicitly.
+    help_span: Option<Span>,
+    /// Expression that evaluates to the help message, from `help_from`.
+    help_from: Option<syn::Expr>,
     labels: Option<Vec<String>>,
+    labels_span: Option<Span>,
+    label_defaults: Vec<(String, String)>,
+    label_types: Vec<(String, Path)>,
+    fixed_labels: Vec<(String, String, Span)>,
+    fixed_labels_fn: Option<Path>,
     buckets: Option<Vec<f64>>,
+    buckets_attr: Option<&'static str>,
+    group: Option<Ident>,
+    per: Option<Path>,
+    sanitize_name: bool,
+    positive_buckets: bool,
+    optional: Option<Span>,
+    lazy: Option<Span>,
+    external: bool,
+    flatten: bool,
+    skip: bool,
+    init_values: Option<(Span, Vec<String>)>,
 }
 
 impl MetricAttrs {
     fn parse(attrs: &[syn::Attribute], is_struct_level: bool) -> Result<Self> {
         let mut result = Self::default();
 
-        let mut doc = None;
+        let mut doc = Vec::new();
 
         for attr in attrs {
             if attr.path.is_ident("metric") {
-                let list = match attr.parse_meta()? {
-                    Meta::List(list) => list,
-                    _ => {
+                // Ranges in `buckets(start..=end, count = n)` are not valid
+                // meta items, so arguments are parsed with a custom parser.
+                let args = match attr.parse_meta() {
+                    Ok(Meta::List(_)) | Err(_) => {
+                        attr.parse_args_with(Punctuated::<MetricArg, Token![,]>::parse_terminated)?
+                    }
+                    Ok(_) => {
                         return Err(Error::new(
                             attr.path.span(),
                             "value for the `metric` attribute should be a list: `metric(...)`",
@@ -236,47 +2374,305 @@ impl MetricAttrs {
                     }
                 };
 
-                for attr in list.nested {
+                for attr in args {
                     let attr = match attr {
-                        NestedMeta::Meta(attr) => attr,
-                        NestedMeta::Lit(lit) => {
+                        MetricArg::Meta(NestedMeta::Meta(attr)) => attr,
+                        MetricArg::Meta(NestedMeta::Lit(lit)) => {
                             return Err(Error::new(lit.span(), "expected a named parameter"))
                         }
+                        MetricArg::RangeBuckets(range) => {
+                            result.parse_range_buckets(range)?;
+                            continue;
+                        }
                     };
 
                     let path = attr.path();
-                    if is_struct_level && path.is_ident("subsystem") {
+                    if is_struct_level && path.is_ident("crate") {
+                        result.parse_krate(attr)?
+                    } else if is_struct_level && path.is_ident("namespace") {
+                        result.parse_namespace(attr)?
+                    } else if is_struct_level && path.is_ident("separator") {
+                        result.separator = Some(Self::parse_separator(
+                            "separator",
+                            attr,
+                            result.separator.is_some(),
+                        )?)
+                    } else if is_struct_level && path.is_ident("namespace_separator") {
+                        result.namespace_separator = Some(Self::parse_separator(
+                            "namespace_separator",
+                            attr,
+                            result.namespace_separator.is_some(),
+                        )?)
+                    } else if path.is_ident("subsystem") {
                         result.parse_subsystem(attr)?
+                    } else if is_struct_level && path.is_ident("subsystem_from_module") {
+                        let span = path.span();
+                        let is_set = result.subsystem_from_module.is_some();
+                        Self::parse_flag("subsystem_from_module", attr, is_set)?;
+                        result.subsystem_from_module = Some(span);
+                    } else if is_struct_level && path.is_ident("name_fn") {
+                        result.parse_name_fn(attr)?
+                    } else if path.is_ident("label_types") {
+                        result.parse_label_types(attr)?
+                    } else if is_struct_level && path.is_ident("const_labels") {
+                        result.parse_fixed_labels(attr)?
+                    } else if is_struct_level && path.is_ident("const_labels_fn") {
+                        result.parse_fixed_labels_fn(attr)?
+                    } else if is_struct_level && path.is_ident("rename") {
+                        result.parse_renames(attr)?
+                    } else if is_struct_level && path.is_ident("rename_all") {
+                        result.parse_rename_all(attr)?
+                    } else if is_struct_level && path.is_ident("owned_registry") {
+                        let span = path.span();
+                        let is_set = result.owned_registry.is_some();
+                        Self::parse_flag("owned_registry", attr, is_set)?;
+                        result.owned_registry = Some(span);
+                    } else if is_struct_level && path.is_ident("builder") {
+                        let span = path.span();
+                        let is_set = result.builder.is_some();
+                        Self::parse_flag("builder", attr, is_set)?;
+                        result.builder = Some(span);
+                    } else if is_struct_level && path.is_ident("inline") {
+                        result.inline = Self::parse_flag("inline", attr, result.inline)?
+                    } else if is_struct_level && path.is_ident("max_help_length") {
+                        result.parse_max_help_length(attr)?
+                    } else if is_struct_level && path.is_ident("max_labels") {
+                        result.parse_max_labels(attr)?
                     } else if !is_struct_level && path.is_ident("name") {
                         result.parse_name(attr)?
                     } else if !is_struct_level && path.is_ident("help") {
                         result.parse_help(attr)?
+                    } else if !is_struct_level && path.is_ident("help_from") {
+                        result.parse_help_from(attr)?
                     } else if path.is_ident("labels") {
-                        result.parse_labels(attr)?
-                    } else if !is_struct_level && path.is_ident("buckets") {
+                        result.parse_labels(attr, is_struct_level)?
+                    } else if path.is_ident("buckets") {
                         result.parse_buckets(attr)?
+                    } else if !is_struct_level && path.is_ident("exponential_buckets") {
+                        result.parse_exponential_buckets(attr)?
+                    } else if !is_struct_level && path.is_ident("linear_buckets") {
+                        result.parse_linear_buckets(attr)?
+                    } else if !is_struct_level && path.is_ident("group") {
+                        result.parse_group(attr)?
+                    } else if !is_struct_level && path.is_ident("per") {
+                        result.parse_per(attr)?
+                    } else if !is_struct_level && path.is_ident("init_values") {
+                        result.parse_init_values(attr)?
+                    } else if !is_struct_level && path.is_ident("positive_buckets") {
+                        result.positive_buckets =
+                            Self::parse_flag("positive_buckets", attr, result.positive_buckets)?
+                    } else if !is_struct_level && path.is_ident("external") {
+                        result.external = Self::parse_flag("external", attr, result.external)?
+                    } else if !is_struct_level && path.is_ident("flatten") {
+                        result.flatten = Self::parse_flag("flatten", attr, result.flatten)?
+                    } else if !is_struct_level && path.is_ident("skip") {
+                        result.skip = Self::parse_flag("skip", attr, result.skip)?
+                    } else if !is_struct_level && path.is_ident("sanitize_name") {
+                        result.sanitize_name =
+                            Self::parse_flag("sanitize_name", attr, result.sanitize_name)?
+                    } else if !is_struct_level && path.is_ident("lazy") {
+                        let span = attr.path().span();
+                        let is_set = result.lazy.is_some();
+                        Self::parse_flag("lazy", attr, is_set)?;
+                        result.lazy = Some(span);
+                    } else if !is_struct_level && path.is_ident("optional") {
+                        let span = attr.path().span();
+                        let is_set = result.optional.is_some();
+                        Self::parse_flag("optional", attr, is_set)?;
+                        result.optional = Some(span);
                     } else {
                         return Err(Error::new(path.span(), "unexpected parameter"));
                     }
                 }
-            } else if doc.is_none() && attr.path.is_ident("doc") {
-                doc = match attr.parse_meta()? {
-                    Meta::NameValue(kv) => match kv.lit {
-                        Lit::Str(s) => Some(s.value().trim().to_string()),
-                        _ => None,
-                    },
-                    _ => None,
-                };
+            } else if attr.path.is_ident("doc") {
+                // Only `#[doc = "..."]` is a doc comment. Other forms,
+                // such as `#[doc(hidden)]`, don't affect the help message.
+                if let Meta::NameValue(kv) = attr.parse_meta()? {
+                    if let Lit::Str(s) = kv.lit {
+                        doc.extend(s.value().lines().map(|line| line.trim().to_string()));
+                    }
+                }
             }
         }
 
-        if result.help.is_none() {
-            result.help = doc;
+        if result.help.is_none() && !doc.is_empty() {
+            // Multi-line documentation is collapsed into a single line.
+            doc.retain(|line| !line.is_empty());
+            result.help = Some(doc.join(" "));
         }
 
         Ok(result)
     }
 
+    fn parse_krate(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("crate", meta.path().span(), self.krate.is_some())?;
+
+        self.krate = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_name_fn(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("name_fn", meta.path().span(), self.name_fn.is_some())?;
+
+        self.name_fn = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_fixed_labels_fn(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "const_labels_fn",
+            meta.path().span(),
+            self.fixed_labels_fn.is_some(),
+        )?;
+
+        self.fixed_labels_fn = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_renames(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("rename", meta.path().span(), !self.renames.is_empty())?;
+
+        for rename in Self::meta_to_list(meta)?.nested {
+            let kv = match rename {
+                NestedMeta::Meta(Meta::NameValue(kv)) => kv,
+                rename => return Err(Error::new(rename.span(), "expected `field = \"name\"`")),
+            };
+            let field = match kv.path.get_ident() {
+                Some(ident) => ident.clone(),
+                None => return Err(Error::new(kv.path.span(), "expected a field name")),
+            };
+            if self.renames.iter().any(|(other, _)| *other == field) {
+                return Err(Error::new(field.span(), "duplicate field"));
+            }
+            self.renames.push((field, Self::value_to_string(kv.lit)?));
+        }
+
+        Ok(())
+    }
+
+    fn parse_rename_all(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("rename_all", meta.path().span(), self.rename_all.is_some())?;
+
+        let lit = Self::meta_to_value(meta)?;
+        let span = lit.span();
+        self.rename_all = Some(match Self::value_to_string(lit)?.as_str() {
+            "snake_case" => RenameRule::SnakeCase,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnakeCase,
+            _ => {
+                return Err(Error::new(
+                    span,
+                    "expected `\"snake_case\"` or `\"SCREAMING_SNAKE_CASE\"`",
+                ))
+            }
+        });
+
+        Ok(())
+    }
+
+    fn parse_label_types(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "label_types",
+            meta.path().span(),
+            !self.label_types.is_empty(),
+        )?;
+
+        for label in Self::meta_to_list(meta)?.nested {
+            let kv = match label {
+                NestedMeta::Meta(Meta::NameValue(kv)) => kv,
+                label => return Err(Error::new(label.span(), "expected `label = \"Type\"`")),
+            };
+            let name = match kv.path.get_ident() {
+                Some(ident) => ident.to_string(),
+                None => return Err(Error::new(kv.path.span(), "expected a label name")),
+            };
+            if self.label_types.iter().any(|(other, _)| *other == name) {
+                return Err(Error::new(kv.path.span(), "duplicate label"));
+            }
+            let ty = match kv.lit {
+                Lit::Str(s) => s.parse()?,
+                lit => return Err(Error::new(lit.span(), "expected a string")),
+            };
+            self.label_types.push((name, ty));
+        }
+
+        Ok(())
+    }
+
+    fn parse_fixed_labels(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "const_labels",
+            meta.path().span(),
+            !self.fixed_labels.is_empty(),
+        )?;
+
+        for label in Self::meta_to_list(meta)?.nested {
+            let kv = match label {
+                NestedMeta::Meta(Meta::NameValue(kv)) => kv,
+                label => return Err(Error::new(label.span(), "expected `label = \"value\"`")),
+            };
+            let span = kv.path.span();
+            let name = match kv.path.get_ident() {
+                Some(ident) => ident.to_string(),
+                None => return Err(Error::new(span, "expected a label name")),
+            };
+            if self.fixed_labels.iter().any(|(other, _, _)| *other == name) {
+                return Err(Error::new(span, "duplicate label"));
+            }
+            if name.starts_with("__") {
+                return Err(Error::new(
+                    span,
+                    "label names starting with `__` are reserved for internal use",
+                ));
+            }
+            self.fixed_labels
+                .push((name, Self::value_to_string(kv.lit)?, span));
+        }
+
+        Ok(())
+    }
+
+    fn parse_namespace(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("namespace", meta.path().span(), self.namespace.is_some())?;
+
+        self.namespace = Some(Self::value_to_string(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
+    fn parse_separator(name: &str, meta: Meta, is_set: bool) -> Result<String> {
+        Self::check_none(name, meta.path().span(), is_set)?;
+
+        let lit = Self::meta_to_value(meta)?;
+        let span = lit.span();
+        let separator = Self::value_to_string(lit)?;
+        let is_valid = separator
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+        if separator.is_empty() || !is_valid {
+            return Err(Error::new(
+                span,
+                format!(
+                    "`{}` should only contain characters allowed in metric names",
+                    name
+                ),
+            ));
+        }
+
+        Ok(separator)
+    }
+
     fn parse_subsystem(&mut self, meta: Meta) -> Result<()> {
         Self::check_none("subsystem", meta.path().span(), self.subsystem.is_some())?;
 
@@ -285,10 +2681,32 @@ impl MetricAttrs {
         Ok(())
     }
 
+    fn parse_max_help_length(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "max_help_length",
+            meta.path().span(),
+            self.max_help_length.is_some(),
+        )?;
+
+        self.max_help_length = Some(Self::value_to_int(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
+    fn parse_max_labels(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("max_labels", meta.path().span(), self.max_labels.is_some())?;
+
+        self.max_labels = Some(Self::value_to_int(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
     fn parse_name(&mut self, meta: Meta) -> Result<()> {
         Self::check_none("name", meta.path().span(), self.name.is_some())?;
 
-        self.name = Some(Self::value_to_string(Self::meta_to_value(meta)?)?);
+        let value = Self::meta_to_value(meta)?;
+        self.name_span = Some(value.span());
+        self.name = Some(Self::value_to_string(value)?);
 
         Ok(())
     }
@@ -296,21 +2714,64 @@ impl MetricAttrs {
     fn parse_help(&mut self, meta: Meta) -> Result<()> {
         Self::check_none("help", meta.path().span(), self.help.is_some())?;
 
+        self.help_span = Some(meta.path().span());
         self.help = Some(Self::value_to_string(Self::meta_to_value(meta)?)?);
 
         Ok(())
     }
 
-    fn parse_labels(&mut self, meta: Meta) -> Result<()> {
+    fn parse_help_from(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("help_from", meta.path().span(), self.help_from.is_some())?;
+
+        self.help_from = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_labels(&mut self, meta: Meta, is_struct_level: bool) -> Result<()> {
         Self::check_none("labels", meta.path().span(), self.labels.is_some())?;
+        self.labels_span = Some(meta.path().span());
 
         let mut labels = Vec::new();
         for label in Self::meta_to_list(meta)?.nested {
             let label_span = label.span();
-            let value = Self::value_to_string(Self::nested_meta_to_value(label)?)?;
+            let (value, default) = match label {
+                NestedMeta::Meta(Meta::NameValue(kv)) if is_struct_level => {
+                    let name = match kv.path.get_ident() {
+                        Some(ident) => ident.to_string(),
+                        None => return Err(Error::new(kv.path.span(), "expected a label name")),
+                    };
+                    (name, Some(Self::value_to_string(kv.lit)?))
+                }
+                label => (
+                    Self::value_to_string(Self::nested_meta_to_value(label)?)?,
+                    None,
+                ),
+            };
             if labels.contains(&value) {
                 return Err(Error::new(label_span, "duplicate label"));
             }
+            if value.starts_with("__") {
+                return Err(Error::new(
+                    label_span,
+                    "label names starting with `__` are reserved for internal use",
+                ));
+            }
+            if !is_valid_label_name(&value) {
+                return Err(Error::new(
+                    label_span,
+                    format!(
+                        "`{}` is not a valid label name, it should match `[a-zA-Z_][a-zA-Z0-9_]*`",
+                        value
+                    ),
+                ));
+            }
+            if let Some(default) = default {
+                self.label_defaults.push((value.clone(), default));
+            }
             labels.push(value)
         }
         self.labels = Some(labels);
@@ -318,18 +2779,257 @@ impl MetricAttrs {
         Ok(())
     }
 
+    fn parse_init_values(&mut self, meta: Meta) -> Result<()> {
+        let span = meta.path().span();
+        Self::check_none("init_values", span, self.init_values.is_some())?;
+
+        let mut values = Vec::new();
+        for value in Self::meta_to_list(meta)?.nested {
+            let value_span = value.span();
+            let value = Self::value_to_string(Self::nested_meta_to_value(value)?)?;
+            if values.contains(&value) {
+                return Err(Error::new(value_span, "duplicate label value"));
+            }
+            values.push(value)
+        }
+        self.init_values = Some((span, values));
+
+        Ok(())
+    }
+
     fn parse_buckets(&mut self, meta: Meta) -> Result<()> {
-        Self::check_none("buckets", meta.path().span(), self.buckets.is_some())?;
+        self.check_buckets("buckets", meta.path().span())?;
 
         let mut buckets = Vec::new();
-        for label in Self::meta_to_list(meta)?.nested {
-            buckets.push(Self::value_to_float(Self::nested_meta_to_value(label)?)?)
+        let mut inf_span = None;
+        for bucket in Self::meta_to_list(meta)?.nested {
+            let bucket_span = bucket.span();
+            if inf_span.is_some() {
+                return Err(Error::new(
+                    bucket_span,
+                    "`inf` should be the last bucket in the list",
+                ));
+            }
+            if let NestedMeta::Meta(Meta::Path(path)) = &bucket {
+                if path.is_ident("inf") {
+                    inf_span = Some(bucket_span);
+                    continue;
+                }
+            }
+            let value = Self::value_to_float(Self::nested_meta_to_value(bucket)?)?;
+            if !value.is_finite() {
+                return Err(Error::new(
+                    bucket_span,
+                    "bucket bounds should be finite, use `inf` to denote the `+Inf` bucket",
+                ));
+            }
+            if let Some(prev) = buckets.last() {
+                if value <= *prev {
+                    return Err(Error::new(
+                        bucket_span,
+                        format!(
+                            "buckets should be listed in strictly ascending order, \
+                             but {:?} follows {:?}",
+                            value, prev
+                        ),
+                    ));
+                }
+            }
+            buckets.push(value)
+        }
+        if let (Some(span), true) = (inf_span, buckets.is_empty()) {
+            return Err(Error::new(
+                span,
+                "expected at least one finite bucket before `inf`",
+            ));
+        }
+        self.buckets = Some(buckets);
+
+        Ok(())
+    }
+
+    fn parse_exponential_buckets(&mut self, meta: Meta) -> Result<()> {
+        let span = meta.path().span();
+        self.check_buckets("exponential_buckets", span)?;
+
+        let params = Self::parse_bucket_params(meta, &["start", "factor", "count"])?;
+        let (start, factor, count) = match params.as_slice() {
+            [start, factor, count] => (start, factor, count),
+            _ => unreachable!(),
+        };
+
+        let count = Self::value_to_int(count.clone())?;
+        if count == 0 {
+            return Err(Error::new(span, "`count` should be greater than zero"));
+        }
+        let start = Self::value_to_float(start.clone())?;
+        if start <= 0.0 || !start.is_finite() {
+            return Err(Error::new(span, "`start` should be greater than zero"));
+        }
+        let factor = Self::value_to_float(factor.clone())?;
+        if factor <= 1.0 || !factor.is_finite() {
+            return Err(Error::new(span, "`factor` should be greater than one"));
+        }
+
+        let mut buckets = Vec::with_capacity(count);
+        let mut next = start;
+        for _ in 0..count {
+            if !next.is_finite() {
+                return Err(Error::new(span, "bucket bounds overflow"));
+            }
+            buckets.push(next);
+            next *= factor;
+        }
+        self.buckets = Some(buckets);
+
+        Ok(())
+    }
+
+    fn parse_linear_buckets(&mut self, meta: Meta) -> Result<()> {
+        let span = meta.path().span();
+        self.check_buckets("linear_buckets", span)?;
+
+        let params = Self::parse_bucket_params(meta, &["start", "width", "count"])?;
+        let (start, width, count) = match params.as_slice() {
+            [start, width, count] => (start, width, count),
+            _ => unreachable!(),
+        };
+
+        let count = Self::value_to_int(count.clone())?;
+        if count == 0 {
+            return Err(Error::new(span, "`count` should be greater than zero"));
+        }
+        let start = Self::value_to_float(start.clone())?;
+        if !start.is_finite() {
+            return Err(Error::new(span, "`start` should be finite"));
+        }
+        let width = Self::value_to_float(width.clone())?;
+        if width <= 0.0 || !width.is_finite() {
+            return Err(Error::new(span, "`width` should be greater than zero"));
+        }
+
+        let mut buckets = Vec::with_capacity(count);
+        let mut next = start;
+        for _ in 0..count {
+            if !next.is_finite() {
+                return Err(Error::new(span, "bucket bounds overflow"));
+            }
+            buckets.push(next);
+            next += width;
+        }
+        self.buckets = Some(buckets);
+
+        Ok(())
+    }
+
+    fn parse_range_buckets(&mut self, range: RangeBuckets) -> Result<()> {
+        self.check_buckets("buckets", range.span)?;
+
+        let bound = |(is_negative, lit): (bool, Lit)| -> Result<f64> {
+            let span = lit.span();
+            let value = Self::value_to_float(lit)?;
+            if !value.is_finite() {
+                return Err(Error::new(span, "range bounds should be finite"));
+            }
+            Ok(if is_negative { -value } else { value })
+        };
+        let start = bound(range.start)?;
+        let end = bound(range.end)?;
+        if start >= end {
+            return Err(Error::new(
+                range.span,
+                "range start should be less than range end",
+            ));
         }
+        let count_span = range.count.span();
+        let count = Self::value_to_int(range.count)?;
+        if count < 2 {
+            return Err(Error::new(count_span, "`count` should be at least 2"));
+        }
+
+        let buckets = (0..count)
+            .map(|i| start + (end - start) * i as f64 / (count - 1) as f64)
+            .collect();
         self.buckets = Some(buckets);
 
         Ok(())
     }
 
+    /// Parse a list of `key = value` parameters. Every name from `names`
+    /// should be given exactly once. Values are returned in the same order
+    /// as `names`.
+    fn parse_bucket_params(meta: Meta, names: &[&str]) -> Result<Vec<Lit>> {
+        let list = Self::meta_to_list(meta)?;
+        let list_span = list.path.span();
+
+        let mut values: Vec<Option<Lit>> = vec![None; names.len()];
+        for param in list.nested {
+            let kv = match param {
+                NestedMeta::Meta(Meta::NameValue(kv)) => kv,
+                param => return Err(Error::new(param.span(), "expected a named parameter")),
+            };
+            let index = names
+                .iter()
+                .position(|name| kv.path.is_ident(name))
+                .ok_or_else(|| Error::new(kv.path.span(), "unexpected parameter"))?;
+            Self::check_none(names[index], kv.path.span(), values[index].is_some())?;
+            values[index] = Some(kv.lit);
+        }
+
+        names
+            .iter()
+            .zip(values)
+            .map(|(name, value)| {
+                value.ok_or_else(|| Error::new(list_span, format!("missing parameter `{}`", name)))
+            })
+            .collect()
+    }
+
+    fn check_buckets(&mut self, name: &'static str, span: Span) -> Result<()> {
+        match self.buckets_attr.replace(name) {
+            Some(prev) if prev == name => Self::check_none(name, span, true),
+            Some(prev) => Err(Error::new(
+                span,
+                format!("`{}` can't be used together with `{}`", name, prev),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_group(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("group", meta.path().span(), self.group.is_some())?;
+
+        self.group = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_per(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("per", meta.path().span(), self.per.is_some())?;
+
+        self.per = Some(match Self::meta_to_value(meta)? {
+            Lit::Str(s) => s.parse()?,
+            lit => return Err(Error::new(lit.span(), "expected a string")),
+        });
+
+        Ok(())
+    }
+
+    fn parse_flag(name: &str, meta: Meta, is_set: bool) -> Result<bool> {
+        Self::check_none(name, meta.path().span(), is_set)?;
+
+        match meta {
+            Meta::Path(_) => Ok(true),
+            _ => Err(Error::new(
+                meta.path().span(),
+                format!("`{}` doesn't accept any values", name),
+            )),
+        }
+    }
+
     fn meta_to_value(meta: Meta) -> Result<Lit> {
         match meta {
             Meta::NameValue(kv) => Ok(kv.lit),
@@ -358,6 +3058,13 @@ impl MetricAttrs {
         }
     }
 
+    fn value_to_int(lit: Lit) -> Result<usize> {
+        match lit {
+            Lit::Int(i) => i.base10_parse(),
+            _ => Err(Error::new(lit.span(), "expected an integer")),
+        }
+    }
+
     fn value_to_float(lit: Lit) -> Result<f64> {
         match lit {
             Lit::Int(i) => i.base10_parse(),
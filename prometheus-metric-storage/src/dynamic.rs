@@ -0,0 +1,300 @@
+//! Metric storage that is defined at runtime.
+
+use crate::{
+    check_naming_conventions, Error, HistMetricInit, MetricInit, MetricKind, MetricRegistry, Opts,
+    Result,
+};
+use prometheus::core::Collector;
+use std::collections::HashMap;
+
+/// Description of a single metric in a [`DynamicStorage`].
+#[derive(Clone, Debug)]
+pub struct MetricDescriptor {
+    /// Name of the metric.
+    pub name: String,
+
+    /// Kind of the metric.
+    pub kind: MetricKind,
+
+    /// Help message of the metric.
+    pub help: String,
+
+    /// Labels of a multidimensional metric.
+    pub labels: Vec<String>,
+
+    /// Histogram buckets. If not given, default buckets are used.
+    pub buckets: Option<Vec<f64>>,
+}
+
+impl MetricDescriptor {
+    /// Create a new metric description.
+    pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, kind: MetricKind, help: S2) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            help: help.into(),
+            labels: Vec::new(),
+            buckets: None,
+        }
+    }
+
+    /// Set labels of a multidimensional metric.
+    pub fn labels<S: Into<String>>(mut self, labels: impl IntoIterator<Item = S>) -> Self {
+        self.labels = labels.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set histogram buckets.
+    pub fn buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+}
+
+/// A single metric in a [`DynamicStorage`].
+#[derive(Clone, Debug)]
+pub enum DynamicMetric {
+    /// A [`prometheus::Counter`].
+    Counter(prometheus::Counter),
+    /// A [`prometheus::Gauge`].
+    Gauge(prometheus::Gauge),
+    /// A [`prometheus::Histogram`].
+    Histogram(prometheus::Histogram),
+    /// A [`prometheus::CounterVec`].
+    CounterVec(prometheus::CounterVec),
+    /// A [`prometheus::GaugeVec`].
+    GaugeVec(prometheus::GaugeVec),
+    /// A [`prometheus::HistogramVec`].
+    HistogramVec(prometheus::HistogramVec),
+}
+
+impl DynamicMetric {
+    fn new(descriptor: MetricDescriptor, const_labels: &HashMap<String, String>) -> Result<Self> {
+        if descriptor.buckets.is_some() && !descriptor.kind.is_histogram() {
+            return Err(Error::Msg(format!(
+                "metric {} is not a histogram, it can't have buckets",
+                descriptor.name
+            )));
+        }
+
+        if !descriptor.labels.is_empty() && !descriptor.kind.is_vec() {
+            return Err(Error::Msg(format!(
+                "metric {} is not multidimensional, it can't have labels",
+                descriptor.name
+            )));
+        }
+
+        let opts = Opts {
+            namespace: "".to_string(),
+            subsystem: "".to_string(),
+            name: descriptor.name,
+            help: descriptor.help,
+            const_labels: const_labels.clone(),
+            variable_labels: descriptor.labels,
+        };
+
+        Ok(match (descriptor.kind, descriptor.buckets) {
+            (MetricKind::Counter, _) => DynamicMetric::Counter(MetricInit::init(opts)?),
+            (MetricKind::Gauge, _) => DynamicMetric::Gauge(MetricInit::init(opts)?),
+            (MetricKind::Histogram, None) => DynamicMetric::Histogram(MetricInit::init(opts)?),
+            (MetricKind::Histogram, Some(buckets)) => {
+                DynamicMetric::Histogram(HistMetricInit::init(opts, buckets)?)
+            }
+            (MetricKind::CounterVec, _) => DynamicMetric::CounterVec(MetricInit::init(opts)?),
+            (MetricKind::GaugeVec, _) => DynamicMetric::GaugeVec(MetricInit::init(opts)?),
+            (MetricKind::HistogramVec, None) => {
+                DynamicMetric::HistogramVec(MetricInit::init(opts)?)
+            }
+            (MetricKind::HistogramVec, Some(buckets)) => {
+                DynamicMetric::HistogramVec(HistMetricInit::init(opts, buckets)?)
+            }
+        })
+    }
+
+    /// Return kind of this metric.
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            DynamicMetric::Counter(_) => MetricKind::Counter,
+            DynamicMetric::Gauge(_) => MetricKind::Gauge,
+            DynamicMetric::Histogram(_) => MetricKind::Histogram,
+            DynamicMetric::CounterVec(_) => MetricKind::CounterVec,
+            DynamicMetric::GaugeVec(_) => MetricKind::GaugeVec,
+            DynamicMetric::HistogramVec(_) => MetricKind::HistogramVec,
+        }
+    }
+
+    fn collector(&self) -> Box<dyn Collector> {
+        match self {
+            DynamicMetric::Counter(m) => Box::new(m.clone()),
+            DynamicMetric::Gauge(m) => Box::new(m.clone()),
+            DynamicMetric::Histogram(m) => Box::new(m.clone()),
+            DynamicMetric::CounterVec(m) => Box::new(m.clone()),
+            DynamicMetric::GaugeVec(m) => Box::new(m.clone()),
+            DynamicMetric::HistogramVec(m) => Box::new(m.clone()),
+        }
+    }
+}
+
+/// A metric storage that's defined at runtime.
+///
+/// This is an alternative to the derive macro for cases when metric
+/// definitions are not known at compile time, for example, when they're
+/// loaded from config:
+///
+/// ```
+/// # use prometheus_metric_storage::{DynamicStorage, MetricDescriptor, MetricKind};
+/// let registry = prometheus::Registry::default();
+///
+/// let storage = DynamicStorage::new(
+///     &registry,
+///     vec![
+///         MetricDescriptor::new("requests", MetricKind::Counter, "Number of requests."),
+///         MetricDescriptor::new("duration_seconds", MetricKind::HistogramVec, "Duration.")
+///             .labels(vec!["endpoint"])
+///             .buckets(vec![0.1, 1.0]),
+///     ],
+/// ).unwrap();
+///
+/// storage.counter("requests").unwrap().inc();
+///
+/// let families = registry.gather();
+/// assert_eq!(families.len(), 1);
+/// assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 1.0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DynamicStorage {
+    metrics: HashMap<String, DynamicMetric>,
+}
+
+impl DynamicStorage {
+    /// Create metrics from the given descriptions, and register them
+    /// in the given registry.
+    pub fn new<R: MetricRegistry + ?Sized>(
+        registry: &R,
+        descriptors: impl IntoIterator<Item = MetricDescriptor>,
+    ) -> Result<Self> {
+        let storage = Self::new_unregistered(descriptors)?;
+        storage.register(registry)?;
+        Ok(storage)
+    }
+
+    /// Create metrics from the given descriptions
+    /// without registering them in any registry.
+    pub fn new_unregistered(
+        descriptors: impl IntoIterator<Item = MetricDescriptor>,
+    ) -> Result<Self> {
+        Self::from_const_labels_unregistered(descriptors, HashMap::new())
+    }
+
+    /// Create metrics from the given descriptions, adding the given
+    /// const labels to each of them.
+    ///
+    /// This function does not register the created metrics in any registry.
+    pub fn from_const_labels_unregistered(
+        descriptors: impl IntoIterator<Item = MetricDescriptor>,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Self> {
+        let mut metrics = HashMap::new();
+
+        for descriptor in descriptors {
+            let name = descriptor.name.clone();
+            if metrics.contains_key(&name) {
+                return Err(Error::Msg(format!("metric {} is defined twice", name)));
+            }
+            metrics.insert(name, DynamicMetric::new(descriptor, &const_labels)?);
+        }
+
+        Ok(Self { metrics })
+    }
+
+    /// Register all metrics from this storage in the given registry.
+    pub fn register<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()> {
+        for metric in self.metrics.values() {
+            registry.register(metric.collector())?;
+        }
+        Ok(())
+    }
+
+    /// Check that all metrics from this storage follow prometheus naming
+    /// conventions, then register them in the given registry.
+    ///
+    /// See [`MetricStorage::register_strict`] for details.
+    ///
+    /// [`MetricStorage::register_strict`]: crate::MetricStorage::register_strict
+    pub fn register_strict<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()> {
+        for metric in self.metrics.values() {
+            for desc in metric.collector().desc() {
+                check_naming_conventions(Some(metric.kind()), desc)?;
+            }
+        }
+        self.register(registry)
+    }
+
+    /// Unregister all metrics of this storage from the given registry.
+    pub fn unregister<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()> {
+        for metric in self.metrics.values() {
+            registry.unregister(metric.collector())?;
+        }
+        Ok(())
+    }
+
+    /// Find a metric by name.
+    pub fn get(&self, name: &str) -> Option<&DynamicMetric> {
+        self.metrics.get(name)
+    }
+
+    /// Iterate over all metrics in this storage, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &DynamicMetric)> {
+        self.metrics
+            .iter()
+            .map(|(name, metric)| (name.as_str(), metric))
+    }
+
+    /// Find a counter by name.
+    pub fn counter(&self, name: &str) -> Option<&prometheus::Counter> {
+        match self.get(name)? {
+            DynamicMetric::Counter(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Find a gauge by name.
+    pub fn gauge(&self, name: &str) -> Option<&prometheus::Gauge> {
+        match self.get(name)? {
+            DynamicMetric::Gauge(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Find a histogram by name.
+    pub fn histogram(&self, name: &str) -> Option<&prometheus::Histogram> {
+        match self.get(name)? {
+            DynamicMetric::Histogram(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Find a counter vector by name.
+    pub fn counter_vec(&self, name: &str) -> Option<&prometheus::CounterVec> {
+        match self.get(name)? {
+            DynamicMetric::CounterVec(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Find a gauge vector by name.
+    pub fn gauge_vec(&self, name: &str) -> Option<&prometheus::GaugeVec> {
+        match self.get(name)? {
+            DynamicMetric::GaugeVec(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Find a histogram vector by name.
+    pub fn histogram_vec(&self, name: &str) -> Option<&prometheus::HistogramVec> {
+        match self.get(name)? {
+            DynamicMetric::HistogramVec(m) => Some(m),
+            _ => None,
+        }
+    }
+}
@@ -83,9 +83,9 @@
 //! metrics.requests_duration_seconds.observe(0.25);
 //! ```
 //!
-//! Field names become metric names, and first line of each of the field's
-//! documentation becomes metric's help message. Additional configuration
-//! can be done via the [`#[metric(...)]`](#configuring-metrics) attribute.
+//! Field names become metric names, and each of the field's documentation
+//! becomes metric's help message. Additional configuration can be done
+//! via the [`#[metric(...)]`](#configuring-metrics) attribute.
 //!
 //! So, the code above will report the following metrics:
 //!
@@ -105,12 +105,14 @@
 //!
 //! The derive macro will automatically generate implementation
 //! for the [`MetricStorage`] trait. On top of it, it will generate
-//! three more methods:
+//! several more methods:
 //!
-//! - <code>fn new(registry: &[Registry], ...) -> [`Result`]\<Self\></code>:
+//! - <code>fn new(registry: &impl [MetricRegistry], ...) -> [Result]\<Self, [MetricStorageError]\></code>:
 //!
 //!   Creates a new instance of a metric storage and registers all of its metrics
-//!   in the given registry via [`MetricStorage::register`].
+//!   in the given registry via [`MetricStorage::register`]. Usually, this is
+//!   a [`Registry`], but custom registries are supported
+//!   via the [`MetricRegistry`] trait.
 //!
 //!   This method accepts a reference to a registry, and const labels,
 //!   if storage defines any (see section on [configuring metrics](#configuring-metrics),
@@ -118,20 +120,234 @@
 //!
 //!   Const label parameters should implement <code>[Into]\<[String]\></code>,
 //!   they are listed in the same order as they appear
-//!   in the `#[metric(labels(...))]` attribute.
+//!   in the `#[metric(labels(...))]` attribute. Thus, label values
+//!   can be passed as `&str`, `String` or `&String`:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("tenant", "region"))]
+//!   struct LabeledMetrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!
+//!   let tenant = format!("tenant-{}", 1);
+//!   let region = String::from("eu");
+//!
+//!   let registry = prometheus::Registry::new();
+//!   let metrics = LabeledMetrics::new(&registry, tenant.clone(), region.clone()).unwrap();
+//!   metrics.requests.inc();
+//!   # let labels = registry.gather()[0].get_metric()[0].get_label().to_vec();
+//!   # assert_eq!(labels[0].get_value(), "eu");
+//!   # assert_eq!(labels[1].get_value(), "tenant-1");
+//!
+//!   let storage_registry = StorageRegistry::default();
+//!   let metrics = LabeledMetrics::instance(&storage_registry, &tenant, &region).unwrap();
+//!   let same = LabeledMetrics::instance(&storage_registry, tenant, "eu").unwrap();
+//!   assert!(std::ptr::eq(metrics, same));
+//!   ```
+//!
+//!   If some metric can't be registered, the returned [`MetricStorageError`]
+//!   names the field that holds it.
+//!
+//!   Prometheus identifies a registered metric by its full name and the values
+//!   of its const labels. Thus, storages with different const label values
+//!   can share a registry, while creating a second storage with the same
+//!   values fails with [`Error::AlreadyReg`]:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("tenant"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       #[metric(labels("code"))]
+//!       requests: prometheus::IntCounterVec,
+//!   }
+//!
+//!   let registry = prometheus::Registry::new();
+//!
+//!   let foo = Metrics::new(&registry, "foo").unwrap();
+//!   let bar = Metrics::new(&registry, "bar").unwrap();
+//!   foo.requests.with_label_values(&["200"]).inc();
+//!   bar.requests.with_label_values(&["500"]).inc();
+//!   assert_eq!(registry.gather()[0].get_metric().len(), 2);
+//!
+//!   let err = Metrics::new(&registry, "foo").map(drop).unwrap_err();
+//!   assert!(matches!(err.prometheus_error(), Some(prometheus::Error::AlreadyReg)));
+//!   ```
+//!
+//!   [`Registry`] does not give access to already registered collectors,
+//!   so `new` can't reuse them. Use [`StorageRegistry`] to share storages
+//!   between call sites that create them independently.
 //!
 //! - <code>fn new_unregistered(...) -> [Result]\<Self\></code>:
 //!
 //!   Same as `new`, but doesn't add metrics to any registry. You can use
 //!   [`MetricStorage::register`] to register metrics later.
 //!
-//! - <code>fn instance(registry: &[StorageRegistry], ...) -> [Result]\<&Self\></code>:
+//! - <code>fn from_parts(...) -> Self</code>:
+//!
+//!   Assembles a storage from already constructed metrics, without creating
+//!   new ones. Parameters are named after the struct's fields
+//!   (`field_0`, `field_1`, etc. for tuple structs), and listed in the same
+//!   order as fields are declared.
+//!
+//!   This is useful in tests, as it allows injecting metrics that share
+//!   their values with some other code:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   #     /// Number of requests that are currently inflight.
+//!   #     inflight: prometheus::IntGauge,
+//!   # }
+//!   let inflight = prometheus::IntGauge::new("inflight", "-").unwrap();
+//!   let metrics = Metrics::from_parts(inflight.clone());
+//!   metrics.inflight.inc();
+//!   assert_eq!(inflight.get(), 1);
+//!   ```
+//!
+//! - <code>fn rebind(&self, registry: &impl [MetricRegistry]) -> [Result]\<Self, [MetricStorageError]\></code>:
+//!
+//!   Creates a copy of this storage that shares all metrics with the original,
+//!   and registers it in another registry. This allows exposing the same
+//!   metrics in several scraping scopes:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   #     /// Number of requests that are currently inflight.
+//!   #     inflight: prometheus::IntGauge,
+//!   # }
+//!   # fn main() -> prometheus::Result<()> {
+//!   let public = prometheus::Registry::default();
+//!   let internal = prometheus::Registry::default();
+//!
+//!   let metrics = Metrics::new(&public)?;
+//!   let rebound = metrics.rebind(&internal)?;
+//!
+//!   metrics.inflight.inc();
+//!   assert_eq!(rebound.inflight.get(), 1);
+//!   # assert_eq!(public.gather()[0].get_metric()[0].get_gauge().get_value(), 1.0);
+//!   # assert_eq!(internal.gather()[0].get_metric()[0].get_gauge().get_value(), 1.0);
+//!   # Ok(())
+//!   # }
+//!   ```
+//!
+//! - <code>fn instance(registry: &[StorageRegistry], ...) -> [Result]\<&Self, [MetricStorageError]\></code>:
 //!
 //!   Looks up storage with the given const label values in a [`StorageRegistry`],
 //!   creates one if it's not found.
 //!
 //!   See [`StorageRegistry::get_or_create_storage`] for more info.
 //!
+//! - <code>fn register_into_group(&self, registry: &[StorageRegistry], group: &[str]) -> [Result]\<(), [MetricStorageError]\></code>:
+//!
+//!   Registers all metrics of this storage in the given registry, and adds
+//!   them to the given group, so that they can be gathered separately.
+//!
+//!   See [`StorageRegistry::register_in_group`] for more info.
+//!
+//! Additionally, storages without const labels implement
+//! <code>[TryFrom]\<&[Registry]\></code>, which is equivalent to calling `new`:
+//!
+//! ```
+//! # use prometheus_metric_storage::MetricStorage;
+//! # use std::convert::TryInto;
+//! # #[derive(MetricStorage)]
+//! # struct Metrics {
+//! #     /// Number of requests that are currently inflight.
+//! #     inflight: prometheus::IntGauge,
+//! # }
+//! # fn main() -> prometheus::Result<()> {
+//! let registry = prometheus::Registry::default();
+//! let metrics: Metrics = (&registry).try_into()?;
+//! # assert_eq!(registry.gather()[0].get_name(), "inflight");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Enums
+//!
+//! The derive macro can also be used on enums with struct-like
+//! or unit variants. Each variant represents an alternative set
+//! of metrics, and only metrics of the active variant are registered.
+//!
+//! For every variant, methods `new_<variant>` and `new_unregistered_<variant>`
+//! are generated, variant names are converted to snake case. If a variant
+//! is marked with `#[metric(default)]`, methods `new`, `new_unregistered`
+//! and `instance` are generated as well, and create this variant.
+//! Otherwise, [`MetricStorage::from_const_labels`] returns an error.
+//!
+//! ```
+//! # use prometheus_metric_storage::MetricStorage;
+//! #[derive(MetricStorage)]
+//! enum TransportMetrics {
+//!     #[metric(default)]
+//!     Http {
+//!         /// Number of processed HTTP requests.
+//!         http_requests: prometheus::IntCounter,
+//!     },
+//!     Grpc {
+//!         /// Number of processed gRPC calls.
+//!         grpc_calls: prometheus::IntCounter,
+//!     },
+//!     Disabled,
+//! }
+//!
+//! # fn main() -> prometheus::Result<()> {
+//! let registry = prometheus::Registry::default();
+//! let metrics = TransportMetrics::new_grpc(&registry)?;
+//! if let TransportMetrics::Grpc { grpc_calls } = &metrics {
+//!     grpc_calls.inc();
+//! }
+//!
+//! let families = registry.gather();
+//! assert_eq!(families.len(), 1);
+//! assert_eq!(families[0].get_name(), "grpc_calls");
+//! # assert!(matches!(TransportMetrics::new_unregistered()?, TransportMetrics::Http { .. }));
+//! # assert!(TransportMetrics::new_disabled(&registry)?.descs().is_empty());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Generic storages
+//!
+//! Storages can have generic parameters, lifetimes and where-clauses,
+//! they're copied to the generated implementations. Fields that depend
+//! on generic parameters, such as [`PhantomData`], should be marked
+//! as [`skip`](#configuring-metrics). To be used with [`StorageRegistry`],
+//! a storage should be `Send + Sync + 'static`:
+//!
+//! ```
+//! # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+//! # use std::marker::PhantomData;
+//! trait Backend: Send + Sync + 'static {}
+//!
+//! struct Postgres;
+//! impl Backend for Postgres {}
+//!
+//! #[derive(MetricStorage)]
+//! #[metric(labels("backend"))]
+//! struct Metrics<B: Backend> {
+//!     /// Number of queries.
+//!     queries: prometheus::IntCounter,
+//!     #[metric(skip)]
+//!     backend: PhantomData<B>,
+//! }
+//!
+//! let registry = StorageRegistry::default();
+//! let metrics = Metrics::<Postgres>::instance(&registry, "postgres").unwrap();
+//! metrics.queries.inc();
+//! # assert_eq!(registry.gather()[0].get_metric()[0].get_counter().get_value(), 1.0);
+//! ```
+//!
+//! [`PhantomData`]: std::marker::PhantomData
+//!
 //! # Configuring metrics
 //!
 //! Additional configuration can be done via the `#[metric(...)]` attribute.
@@ -157,6 +373,185 @@
 //!   See the [`subsystem`] field of the [`prometheus::Opts`] struct for more
 //!   info on components that constitute a metric name.
 //!
+//! - **namespace** — a string that will be prepended to each metrics' name,
+//!   before the subsystem.
+//!
+//!   For example, consider the following storage:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(namespace = "myapp", subsystem = "http")]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests_total: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # assert_eq!(registry.gather()[0].get_name(), "myapp_http_requests_total");
+//!   ```
+//!
+//!   Here, the metric will be named `myapp_http_requests_total`.
+//!   Namespace and subsystem can be used independently.
+//!
+//!   See the [`namespace`] field of the [`prometheus::Opts`] struct for more
+//!   info.
+//!
+//! - **separator** and **namespace_separator** — strings that join parts
+//!   of metric names.
+//!
+//!   By default, namespace, subsystem and name are joined with `_`.
+//!   `separator` replaces the underscore between subsystem and name,
+//!   and also between namespace and subsystem, unless `namespace_separator`
+//!   is given. Separators can only contain characters that are allowed
+//!   in metric names:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(namespace = "myapp", subsystem = "http", namespace_separator = ":")]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests_total: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # assert_eq!(registry.gather()[0].get_name(), "myapp:http_requests_total");
+//!   ```
+//!
+//!   Here, the metric will be named `myapp:http_requests_total`. Parts that
+//!   are empty are skipped together with their separators:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(namespace = "myapp", separator = "__", namespace_separator = ":")]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests_total: prometheus::IntCounter,
+//!       /// Number of open connections.
+//!       #[metric(subsystem = "tcp")]
+//!       connections: prometheus::IntGauge,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   let families = registry.gather();
+//!   assert_eq!(families[0].get_name(), "myapp:requests_total");
+//!   assert_eq!(families[1].get_name(), "myapp:tcp__connections");
+//!   ```
+//!
+//!   These settings can't be combined with `name_fn`.
+//!
+//! - **subsystem_from_module** — a flag that sets subsystem to the name
+//!   of the module in which the struct is defined.
+//!
+//!   This keeps metric names in sync with code organization:
+//!
+//!   ```
+//!   mod http {
+//!       # use prometheus_metric_storage::MetricStorage;
+//!       #[derive(MetricStorage)]
+//!       #[metric(subsystem_from_module)]
+//!       pub struct Metrics {
+//!           /// Number of processed requests.
+//!           pub requests: prometheus::IntCounter,
+//!       }
+//!       # impl Metrics { pub fn create(r: &prometheus::Registry) -> Self { Self::new(r).unwrap() } }
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = http::Metrics::create(&registry);
+//!   # metrics.requests.inc();
+//!   # assert_eq!(registry.gather()[0].get_name(), "http_requests");
+//!   ```
+//!
+//!   Here, the metric will be named `http_requests`. Note that for structs
+//!   defined at the top level of a crate, the crate name is used.
+//!   This setting can't be combined with `subsystem`.
+//!
+//! - **name_fn** — a path to a function that computes final metric names.
+//!
+//!   The function accepts namespace, subsystem and metric name
+//!   (which is either the field name or the value of the field-level `name`
+//!   option), and returns the full metric name. This allows implementing
+//!   organization-wide naming policies:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   fn make_name(namespace: &str, subsystem: &str, name: &str) -> String {
+//!       [namespace, &subsystem.to_uppercase(), name]
+//!           .iter()
+//!           .filter(|part| !part.is_empty())
+//!           .copied()
+//!           .collect::<Vec<_>>()
+//!           .join("_")
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   #[metric(subsystem = "http", name_fn = "make_name")]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # metrics.requests.inc();
+//!   # assert_eq!(registry.gather()[0].get_name(), "HTTP_requests");
+//!   ```
+//!
+//!   Here, the metric will be named `HTTP_requests`.
+//!
+//! - **rename** — a mapping from field names to metric names.
+//!
+//!   This is equivalent to the field-level `name` option, but allows renaming
+//!   multiple fields in one place. This is convenient when adopting this crate
+//!   on top of existing metrics:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(rename(requests = "http_requests_total", connections = "active_connections"))]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests: prometheus::IntCounter,
+//!
+//!       /// Number of open connections.
+//!       connections: prometheus::IntGauge,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   # assert_eq!(families[0].get_name(), "active_connections");
+//!   # assert_eq!(families[1].get_name(), "http_requests_total");
+//!   ```
+//!
+//!   Fields listed here can't have the `name` option.
+//!
+//! - **rename_all** — a case conversion that derives metric names
+//!   from field names: `"snake_case"` or `"SCREAMING_SNAKE_CASE"`.
+//!
+//!   Fields with the `name` option, or listed in `rename`,
+//!   are not converted:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(rename_all = "snake_case")]
+//!   #[allow(non_snake_case)]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requestCount: prometheus::IntCounter,
+//!
+//!       /// Number of open connections.
+//!       #[metric(name = "openConnections")]
+//!       openConnections: prometheus::IntGauge,
+//!   }
+//!
+//!   assert_eq!(Metrics::metric_names(), &["request_count", "openConnections"]);
+//!   ```
+//!
 //! - **labels** — a list of const labels that will be added to each metric.
 //!
 //!   These labels should be provided during the storage initialization.
@@ -194,475 +589,5691 @@
 //!   See the [`const_labels`] field of the [`prometheus::Opts`] struct for more
 //!   info on different label settings.
 //!
-//! On the field level, the following options are available:
+//!   Labels can have default values, which is useful when rolling out
+//!   a new label gradually. For storages with such labels, the derive macro
+//!   generates `new_with_defaults`, `new_unregistered_with_defaults`
+//!   and `instance_with_defaults` methods. They only accept labels
+//!   that don't have defaults:
 //!
-//! - **name** — a string that overrides metric name derived from the field name.
+//!   ```
+//!   # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("url", region = "unknown"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
 //!
-//!   This is useful for tuple structs:
+//!   # let registry = StorageRegistry::default();
+//!   let metrics = Metrics::instance_with_defaults(&registry, "https://google.com/").unwrap();
+//!   metrics.requests.inc();
+//!
+//!   // Same storage as the one above.
+//!   let metrics = Metrics::instance(&registry, "https://google.com/", "unknown").unwrap();
+//!   assert_eq!(metrics.requests.get(), 1);
 //!
+//!   # let families = registry.gather();
+//!   # let labels = families[0].get_metric()[0].get_label();
+//!   # assert_eq!(labels[0].get_name(), "region");
+//!   # assert_eq!(labels[0].get_value(), "unknown");
 //!   ```
-//!   # use prometheus_metric_storage_derive::MetricStorage;
-//!   #[derive(MetricStorage)]
-//!   struct Metrics (
-//!       #[metric(name = "requests", help = "Number of successful requests.")]
-//!       prometheus::IntCounter
-//!   );
+//!
+//! - **label_types** — a mapping from names of labels to types
+//!   of their values.
+//!
+//!   Constructors accept values of these labels as the given types
+//!   instead of strings. The types should implement [`MetricLabel`],
+//!   which can be derived for enums without fields:
+//!
 //!   ```
+//!   # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+//!   #[derive(MetricLabel)]
+//!   enum Region {
+//!       Europe,
+//!       NorthAmerica,
+//!   }
 //!
-//!   Note that this setting does not override `subsystem` configuration.
-//!   That is, `subsystem` will still be prepended to metric's name.
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("region"), label_types(region = "Region"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
 //!
-//! - **help** — a string that overrides help message derived
-//!   from documentation.
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, Region::Europe).unwrap();
+//!   ```
 //!
-//! - **labels** — a list of strings that will be used as labels for
-//!   multidimensional (`Vec`) metrics. Order of labels will be preserved,
-//!   so you can rely on it in functions such as [`MetricVec::with_label_values`].
+//! - **const_labels** — a mapping from label names to values that will be
+//!   added to each metric.
 //!
-//!   Example:
+//!   Unlike `labels`, values of these labels are fixed at compile time,
+//!   so they're not passed to constructors. Both options can be used
+//!   together, as long as label names don't collide:
 //!
 //!   ```
 //!   # use prometheus_metric_storage_derive::MetricStorage;
-//!   # #[derive(MetricStorage)]
-//!   # struct Metrics {
-//!   # /// -
-//!   #[metric(labels("url", "status"))]
-//!   requests_finished: prometheus::IntCounterVec,
-//!   # }
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("url"), const_labels(version = "1.2.3", build = "release"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, "https://google.com/").unwrap();
+//!   # metrics.requests.inc();
+//!   # let families = registry.gather();
+//!   # let labels = families[0].get_metric()[0].get_label();
+//!   # let labels: Vec<_> = labels.iter().map(|l| (l.get_name(), l.get_value())).collect();
+//!   # assert_eq!(
+//!   #     labels,
+//!   #     vec![("build", "release"), ("url", "https://google.com/"), ("version", "1.2.3")],
+//!   # );
 //!   ```
 //!
-//! - **buckets** — a list of floating point numbers used as histogram
-//!   bucket bounds. Numbers should be listed in ascending order.
+//! - **const_labels_fn** — a path to a function that returns
+//!   a `HashMap<String, String>` of labels that will be added to each metric.
 //!
-//!   Example:
+//!   This is useful for labels that are only known at runtime,
+//!   such as a host name. The function is called every time
+//!   a storage is created:
 //!
 //!   ```
 //!   # use prometheus_metric_storage_derive::MetricStorage;
-//!   # #[derive(MetricStorage)]
-//!   # struct Metrics {
-//!   # /// -
-//!   #[metric(buckets(0.1, 0.2, 0.5, 1, 2, 4, 8))]
-//!   requests_duration_seconds: prometheus::Histogram,
-//!   # }
+//!   # use std::collections::HashMap;
+//!   fn host_labels() -> HashMap<String, String> {
+//!       let mut labels = HashMap::new();
+//!       labels.insert("host".to_string(), "web-1".to_string());
+//!       labels
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   #[metric(const_labels(version = "1.2.3"), const_labels_fn = "host_labels")]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!
+//!   let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   # metrics.requests.inc();
+//!   let families = registry.gather();
+//!   let labels = families[0].get_metric()[0].get_label();
+//!   assert_eq!(labels[0].get_name(), "host");
+//!   assert_eq!(labels[0].get_value(), "web-1");
+//!   # assert_eq!(labels[1].get_name(), "version");
 //!   ```
 //!
-//! # Supporting custom collectors
+//!   Labels returned by the function don't override labels that are
+//!   already set via `labels` or `const_labels`.
 //!
-//! If your project uses custom [collectors], metric storage will not be able
-//! to instantiate them by default. You'll have to implement [`MetricInit`]
-//! and possibly [`HistMetricInit`] for each of the collector you wish to use.
+//! - **crate** — a path to this crate, used by the generated code.
 //!
-//! # Metric storage registry
+//!   By default, the generated code refers to `prometheus_metric_storage`.
+//!   If you re-export or rename this crate, specify the path to it explicitly:
 //!
-//! When registering a metric storage, there's a requirement
-//! that a single metric should not be registered twice within
-//! the same registry. In practice, this means that, once a storage has been
-//! created and registered, it should not be created again:
+//!   ```
+//!   mod my_reexport {
+//!       pub use prometheus_metric_storage::*;
+//!   }
 //!
-//! ```should_panic
-//! # use prometheus_metric_storage::MetricStorage;
-//! # #[derive(MetricStorage)]
-//! # struct Metrics {
-//! #     /// -
-//! #     pieces_of_stuff_processed: prometheus::IntCounter,
-//! # }
-//! fn do_stuff() {
-//! # /*
-//!     ...
-//! # */
+//!   # extern crate prometheus_metric_storage as renamed;
+//!   # use renamed::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(crate = "my_reexport")]
+//!   struct Metrics {
+//!       /// Number of requests that are currently inflight.
+//!       inflight: prometheus::IntGauge,
+//!   }
+//!   # fn main() {
+//!   # let registry = prometheus::Registry::default();
+//!   # Metrics::new(&registry).unwrap().inflight.inc();
+//!   # assert_eq!(registry.gather()[0].get_name(), "inflight");
+//!   # }
+//!   ```
 //!
-//!     let metrics = Metrics::new(prometheus::default_registry()).unwrap();
-//!     metrics.pieces_of_stuff_processed.inc();
-//! }
+//! - **max_help_length** — max number of characters in help messages.
 //!
-//! # /*
-//! ...
-//! # */
+//!   Some exporters have trouble with very long help messages. If this setting
+//!   is given, a help message that's longer than the limit will cause
+//!   a compilation error.
 //!
-//! # fn main() {
-//! // The first call will work just fine.
-//! do_stuff();
+//!   With the `strict` feature enabled, this limit defaults to 1024
+//!   characters. Additionally, help messages are checked not to contain
+//!   control characters.
 //!
-//! // The second call will panic, though, because the metrics
-//! // were already registered.
-//! do_stuff();
-//! # }
-//! ```
+//! - **max_labels** — max number of struct-level labels, defaults to 10.
 //!
-//! There are two approaches to solve this issue.
+//!   Every struct-level label becomes a parameter of `new`
+//!   and `new_unregistered`, so a long list of labels leads to constructors
+//!   that are easy to call with arguments in the wrong order. Storages
+//!   with more labels than the limit cause a compilation error. Either
+//!   build const labels as a map and use `from_const_labels_unregistered`,
+//!   or raise the limit explicitly:
 //!
-//! The first one is to create a static variable within the `do_stuff`'s body.
-//! As was pointed out in the [section about motivation](#motivation),
-//! the code will have to rely on the [default registry], so this is not
-//! suitable for libraries.
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"), max_labels = 11)]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!   ```
 //!
-//! The second one is to have `do_stuff` accept a reference to `Metrics`.
-//! This solution complicates component's public API, exposes implementation
-//! details of metric collection.
+//! - **inline** — a flag that marks all generated inherent methods,
+//!   such as constructors, group observers and bucket getters,
+//!   with `#[inline]`.
 //!
-//! To give a better way of dealing with the situation, this crate provides
-//! [`StorageRegistry`]: a wrapper around the default registry that keeps track
-//! of all created storages, and makes sure that a single storage
-//! is only registered once:
+//!   Use it for storages that are used on hot paths across crate boundaries,
+//!   so that the optimizer can see through the generated wrappers:
 //!
-//! ```
-//! # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
-//! # #[derive(MetricStorage)]
-//! # struct Metrics {
-//! #     /// -
-//! #     pieces_of_stuff_processed: prometheus::IntCounter,
-//! # }
-//! fn do_stuff(registry: &StorageRegistry) {
-//! # /*
-//!     ...
-//! # */
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(inline)]
+//!   struct Metrics {
+//!       /// Processing time of each request in seconds.
+//!       #[metric(buckets(0.1, 1), group = "latency")]
+//!       requests_duration_seconds: prometheus::Histogram,
+//!   }
 //!
-//!     let metrics = Metrics::instance(registry).unwrap();
-//!     metrics.pieces_of_stuff_processed.inc();
-//! }
+//!   let metrics = Metrics::new_unregistered().unwrap();
+//!   metrics.observe_all_latency(0.5, &[]);
+//!   assert_eq!(metrics.requests_duration_seconds_buckets(), vec![0.1, 1.0]);
+//!   ```
 //!
-//! # /*
-//! ...
-//! # */
+//! - **buckets** — default buckets for all histograms in the storage.
 //!
-//! # fn main() {
-//! let registry = StorageRegistry::default();
+//!   Histograms that specify their own buckets, including
+//!   `exponential_buckets` and `linear_buckets`, ignore the default.
+//!   Other metrics are not affected:
 //!
-//! // The first call will work just fine.
-//! do_stuff(&registry);
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(buckets(0.01, 0.1, 1))]
+//!   struct Metrics {
+//!       /// Time spent in the database, in seconds.
+//!       db_duration_seconds: prometheus::Histogram,
+//!       /// Time spent rendering responses, in seconds.
+//!       #[metric(buckets(0.5, 5))]
+//!       render_duration_seconds: prometheus::Histogram,
+//!       /// Number of requests.
+//!       requests_total: prometheus::IntCounter,
+//!   }
 //!
-//! // The second call will also work, because `StorageRegistry`
-//! // makes sure not to register storages twice.
-//! do_stuff(&registry);
-//! # }
-//! ```
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.db_duration_seconds.observe(0.05);
+//!   metrics.render_duration_seconds.observe(0.05);
+//!   # let families = registry.gather();
+//!   # let bounds = |i: usize| -> Vec<f64> {
+//!   #     families[i].get_metric()[0].get_histogram().get_bucket()
+//!   #         .iter().map(|b| b.get_upper_bound()).collect()
+//!   # };
+//!   # assert_eq!(families[0].get_name(), "db_duration_seconds");
+//!   # assert_eq!(bounds(0), vec![0.01, 0.1, 1.0]);
+//!   # assert_eq!(families[1].get_name(), "render_duration_seconds");
+//!   # assert_eq!(bounds(1), vec![0.5, 5.0]);
+//!   # assert_eq!(
+//!   #     metrics.histogram_buckets(),
+//!   #     vec![
+//!   #         ("db_duration_seconds".to_string(), vec![0.01, 0.1, 1.0]),
+//!   #         ("render_duration_seconds".to_string(), vec![0.5, 5.0]),
+//!   #     ]
+//!   # );
+//!   ```
 //!
-//! [static metrics]: prometheus#static-metrics
-//! [default registry]: prometheus::default_registry
-//! [collectors]: prometheus::core::Collector
-//! [`subsystem`]: prometheus::Opts#structfield.subsystem
-//! [`const_labels`]: prometheus::Opts#structfield.const_labels
-//! [`MetricVec::with_label_values`]: prometheus::core::MetricVec::with_label_values
+//! - **owned_registry** — a flag that generates a wrapper struct
+//!   named `<Storage>WithRegistry`, which creates its own [`Registry`]
+//!   and registers metrics in it.
+//!
+//!   This is useful for self-contained subsystems that export their
+//!   metrics separately from the rest of the application. The wrapper
+//!   has the same visibility as the storage. It provides `new`, which
+//!   accepts the same labels as the storage's `new`, and methods
+//!   `metrics`, `registry` and `gather`:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(owned_registry, labels("shard"))]
+//!   struct CacheMetrics {
+//!       /// Number of cache hits.
+//!       hits_total: prometheus::IntCounter,
+//!       /// Number of cache misses.
+//!       misses_total: prometheus::IntCounter,
+//!   }
+//!
+//!   let cache = CacheMetricsWithRegistry::new("0").unwrap();
+//!   cache.metrics().hits_total.inc();
+//!
+//!   let families = cache.gather();
+//!   assert_eq!(families.len(), 2);
+//!   assert_eq!(families[0].get_name(), "hits_total");
+//!   # assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 1.0);
+//!   # assert_eq!(families[0].get_metric()[0].get_label()[0].get_value(), "0");
+//!   # assert_eq!(cache.registry().gather().len(), 2);
+//!   ```
+//!
+//! - **builder** — a flag that generates a builder struct
+//!   named `<Storage>Builder`, which allows overriding histogram buckets
+//!   and adding const labels at runtime, for example, when they come
+//!   from a config file.
+//!
+//!   The builder is created by `<Storage>::builder()`. For every histogram
+//!   field, it has a method `with_<field>_buckets` that accepts buckets
+//!   for this field. Such methods can only be called for fields whose type
+//!   implements [`HistMetricInit`].
+//!   Fields without an override use buckets from their attributes.
+//!   The builder's `build` method accepts the same parameters as
+//!   the storage's `new`, there's also `build_unregistered`
+//!   that mirrors `new_unregistered`:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(builder)]
+//!   struct Metrics {
+//!       /// Processing time of each request in seconds.
+//!       #[metric(buckets(0.1, 1))]
+//!       requests_duration_seconds: prometheus::Histogram,
+//!       /// Time spent in the database in seconds.
+//!       #[metric(buckets(0.1, 1))]
+//!       db_duration_seconds: prometheus::Histogram,
+//!   }
+//!
+//!   let registry = prometheus::Registry::new();
+//!   let metrics = Metrics::builder()
+//!       .with_requests_duration_seconds_buckets(vec![0.5, 5.0, 50.0])
+//!       .build(&registry)
+//!       .unwrap();
+//!   metrics.requests_duration_seconds.observe(1.0);
+//!
+//!   let bounds = |family: &prometheus::proto::MetricFamily| -> Vec<f64> {
+//!       family.get_metric()[0]
+//!           .get_histogram()
+//!           .get_bucket()
+//!           .iter()
+//!           .map(|bucket| bucket.get_upper_bound())
+//!           .collect()
+//!   };
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families[0].get_name(), "db_duration_seconds");
+//!   assert_eq!(bounds(&families[0]), vec![0.1, 1.0]);
+//!   assert_eq!(families[1].get_name(), "requests_duration_seconds");
+//!   assert_eq!(bounds(&families[1]), vec![0.5, 5.0, 50.0]);
+//!   ```
+//!
+//!   The builder's method `field_const_label` adds a const label
+//!   to a single field. It accepts the field's name, and the label's name
+//!   and value. `build` fails if there's no such field, or if the label
+//!   is already defined for the whole storage:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(builder, labels("tenant"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!       /// Number of errors.
+//!       errors: prometheus::IntCounter,
+//!   }
+//!
+//!   let registry = prometheus::Registry::new();
+//!   let metrics = Metrics::builder()
+//!       .field_const_label("errors", "severity", "high")
+//!       .build(&registry, "foo")
+//!       .unwrap();
+//!   metrics.errors.inc();
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families[0].get_name(), "errors");
+//!   let labels = families[0].get_metric()[0].get_label();
+//!   assert_eq!(labels[0].get_name(), "severity");
+//!   assert_eq!(labels[0].get_value(), "high");
+//!   assert_eq!(labels[1].get_name(), "tenant");
+//!   assert_eq!(labels[1].get_value(), "foo");
+//!
+//!   assert_eq!(families[1].get_name(), "requests");
+//!   assert_eq!(families[1].get_metric()[0].get_label().len(), 1);
+//!
+//!   let builder = Metrics::builder().field_const_label("latency", "severity", "high");
+//!   assert!(builder.build_unregistered("foo").is_err());
+//!
+//!   let builder = Metrics::builder().field_const_label("errors", "tenant", "bar");
+//!   assert!(builder.build_unregistered("foo").is_err());
+//!   ```
+//!
+//! On the field level, the following options are available:
+//!
+//! - **name** — a string that overrides metric name derived from the field name.
+//!
+//!   This is useful for tuple structs:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics (
+//!       #[metric(name = "requests", help = "Number of successful requests.")]
+//!       prometheus::IntCounter
+//!   );
+//!   ```
+//!
+//!   Note that this setting does not override `subsystem` configuration.
+//!   That is, `subsystem` will still be prepended to metric's name.
+//!   Use the field-level `subsystem` to change it.
+//!
+//!   Metric names are checked at compile time: a name that doesn't match
+//!   `[a-zA-Z_:][a-zA-Z0-9_:]*` causes a compilation error, unless
+//!   `sanitize_name` is given, or names are computed by `name_fn`.
+//!
+//! - **subsystem** — a string that overrides the struct-level subsystem
+//!   for this metric.
+//!
+//!   This is useful when a few metrics of a storage logically belong
+//!   to another subsystem:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(subsystem = "http")]
+//!   struct Metrics {
+//!       /// Number of HTTP requests.
+//!       requests: prometheus::IntCounter,
+//!
+//!       /// Number of gRPC calls.
+//!       #[metric(subsystem = "grpc", name = "requests")]
+//!       grpc_requests: prometheus::IntCounter,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   # assert_eq!(families[0].get_name(), "grpc_requests");
+//!   # assert_eq!(families[1].get_name(), "http_requests");
+//!   ```
+//!
+//!   Here, metrics will be named `http_requests` and `grpc_requests`.
+//!   This setting also overrides `subsystem_from_module`.
+//!
+//! - **sanitize_name** — a flag that makes metric name valid by replacing
+//!   disallowed characters with underscores, and collapsing runs
+//!   of underscores into a single one.
+//!
+//!   This is convenient when names come from external identifiers:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// -
+//!       #[metric(name = "foo-bar.baz", sanitize_name)]
+//!       foo: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # assert_eq!(registry.gather()[0].get_name(), "foo_bar_baz");
+//!   ```
+//!
+//!   Here, the metric will be named `foo_bar_baz`. Names such as `foo_-bar`
+//!   and `foo-_bar` also become `foo_bar`:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// -
+//!       #[metric(name = "foo_-bar", sanitize_name)]
+//!       foo: prometheus::IntCounter,
+//!       /// -
+//!       #[metric(name = "baz-_qux", sanitize_name)]
+//!       baz: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   let families = registry.gather();
+//!   assert_eq!(families[0].get_name(), "baz_qux");
+//!   assert_eq!(families[1].get_name(), "foo_bar");
+//!   ```
+//!
+//!   Note that sanitization can produce identical names from different
+//!   inputs, such as `foo-bar` and `foo.bar`, leading to registration errors.
+//!
+//! - **help** — a string that overrides help message derived
+//!   from documentation. Help message can't be empty.
+//!
+//!   This setting can be applied conditionally via `cfg_attr`. When
+//!   the condition is not met, help message is derived from documentation:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       #[cfg_attr(debug_assertions, metric(help = "Number of requests, including retries."))]
+//!       requests: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # let help = registry.gather()[0].get_help().to_string();
+//!   # if cfg!(debug_assertions) {
+//!   #     assert_eq!(help, "Number of requests, including retries.");
+//!   # } else {
+//!   #     assert_eq!(help, "Number of requests.");
+//!   # }
+//!   ```
+//!
+//!   Explicitly given help messages can refer to the metric name
+//!   via the `{name}` placeholder. It's replaced with the name of the metric,
+//!   without namespace and subsystem. Literal braces are escaped
+//!   by doubling them:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       #[metric(help = "Total {name} processed")]
+//!       items: prometheus::IntCounter,
+//!       #[metric(help = "Total {name} processed, see {{docs}}")]
+//!       batches: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   # assert_eq!(families[0].get_help(), "Total batches processed, see {docs}");
+//!   # assert_eq!(families[1].get_help(), "Total items processed");
+//!   ```
+//!
+//!   Help messages derived from documentation are not templated.
+//!   If documentation spans several lines, they're trimmed and joined
+//!   with spaces, and blank lines are dropped. Explicitly given help
+//!   messages are used as is:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of requests
+//!       ///
+//!       ///   that were **rejected**.
+//!       rejected: prometheus::IntCounter,
+//!   }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   assert_eq!(
+//!       metrics.descs()[0].1.help,
+//!       "Number of requests that were **rejected**.",
+//!   );
+//!   ```
+//!
+//! - **help_from** — an expression that evaluates to the help message,
+//!   such as an associated constant of a trait.
+//!
+//!   This allows reusing metric definitions in generic storages,
+//!   where each type parameter describes its own metrics:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # use std::marker::PhantomData;
+//!   trait Queue {
+//!       const DEPTH_HELP: &'static str;
+//!   }
+//!
+//!   struct Emails;
+//!   impl Queue for Emails {
+//!       const DEPTH_HELP: &'static str = "Number of emails waiting to be sent.";
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics<Q: Queue> {
+//!       #[metric(help_from = "<Q as Queue>::DEPTH_HELP")]
+//!       depth: prometheus::IntGauge,
+//!       #[metric(skip)]
+//!       queue: PhantomData<Q>,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::<Emails>::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   assert_eq!(families[0].get_help(), "Number of emails waiting to be sent.");
+//!   ```
+//!
+//!   Such help messages are only known at runtime, so they're not
+//!   templated and not checked against `max_help_length`. This option
+//!   can't be used together with `help`; documentation is ignored.
+//!
+//! - **labels** — a list of strings that will be used as labels for
+//!   multidimensional (`Vec`) metrics. Order of labels will be preserved,
+//!   so you can rely on it in functions such as [`MetricVec::with_label_values`].
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(labels("url", "status"))]
+//!   requests_finished: prometheus::IntCounterVec,
+//!   # }
+//!   ```
+//!
+//!   Field labels can't repeat labels from the struct-level `labels`
+//!   option, as they would clash with const labels of the same metric.
+//!
+//! - **init_values** — a list of label values for which child metrics
+//!   are created right away.
+//!
+//!   Child metrics of multidimensional metrics are usually created on first
+//!   use, so they're missing from scrapes until then. With this setting,
+//!   they're created by the constructor, so that zero-valued series
+//!   are exported immediately. This setting can only be used with metrics
+//!   that have exactly one label.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(labels("status"), init_values("200", "404", "500"))]
+//!   requests_finished: prometheus::IntCounterVec,
+//!   # }
+//!   # let registry = prometheus::Registry::default();
+//!   # Metrics::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   # let series = families[0].get_metric();
+//!   # assert_eq!(series.len(), 3);
+//!   # assert!(series.iter().all(|s| s.get_counter().get_value() == 0.0));
+//!   ```
+//!
+//!   Label names starting with `__` are reserved for internal use
+//!   by Prometheus, and will be rejected at compile time, as well as
+//!   names that don't match `[a-zA-Z_][a-zA-Z0-9_]*`. This applies
+//!   to the struct-level `labels(...)` as well.
+//!
+//! - **buckets** — a list of floating point numbers used as histogram
+//!   bucket bounds. Numbers should be listed in strictly ascending order,
+//!   this is checked at compile time.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(buckets(0.1, 0.2, 0.5, 1, 2, 4, 8))]
+//!   requests_duration_seconds: prometheus::Histogram,
+//!   # }
+//!   ```
+//!
+//!   For every field with configured buckets, a method `<field>_buckets()`
+//!   is generated. It returns the configured bucket bounds, so that tests
+//!   can check bucket layout. For tuple structs, fields are named
+//!   `field_0`, `field_1`, etc.:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   #     /// -
+//!   #     #[metric(buckets(0.1, 0.2, 0.5, 1, 2, 4, 8))]
+//!   #     requests_duration_seconds: prometheus::Histogram,
+//!   # }
+//!   # let metrics = Metrics::new_unregistered().unwrap();
+//!   assert_eq!(
+//!       metrics.requests_duration_seconds_buckets(),
+//!       vec![0.1, 0.2, 0.5, 1.0, 2.0, 4.0, 8.0],
+//!   );
+//!   ```
+//!
+//!   Prometheus always adds the `+Inf` bucket after the last finite one,
+//!   so bucket bounds should be finite. To make this explicit, the list
+//!   can be terminated with the `inf` keyword. It doesn't add a new bucket,
+//!   it only documents that the implicit `+Inf` bucket comes last:
+//!
+//!   ```
+//!   # use prometheus::Encoder;
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(buckets(0.1, 0.5, 1, inf))]
+//!   requests_duration_seconds: prometheus::Histogram,
+//!   # }
+//!   # let registry = prometheus::Registry::default();
+//!   # let metrics = Metrics::new(&registry).unwrap();
+//!   # metrics.requests_duration_seconds.observe(0.3);
+//!   # metrics.requests_duration_seconds.observe(10.0);
+//!   # let mut text = Vec::new();
+//!   # prometheus::TextEncoder::new().encode(&registry.gather(), &mut text).unwrap();
+//!   # let text = String::from_utf8(text).unwrap();
+//!   # assert_eq!(text.matches("le=\"+Inf\"").count(), 1);
+//!   # assert!(text.contains("requests_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+//!   # assert!(text.contains("requests_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+//!   # assert!(text.contains("requests_duration_seconds_bucket{le=\"1\"} 1\n"));
+//!   # assert!(text.contains("requests_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+//!   ```
+//!
+//!   Alternatively, buckets can be given as an inclusive range and a number
+//!   of evenly spaced bounds in it, including both ends. Count should be
+//!   at least 2:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Size of a batch.
+//!       #[metric(buckets(0.0..=10.0, count = 11))]
+//!       batch_size: prometheus::Histogram,
+//!   }
+//!
+//!   let metrics = Metrics::new_unregistered().unwrap();
+//!   assert_eq!(
+//!       metrics.batch_size_buckets(),
+//!       vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0],
+//!   );
+//!   ```
+//!
+//! - **exponential_buckets** — generate histogram buckets
+//!   that grow exponentially, same as [`prometheus::exponential_buckets`].
+//!
+//!   Buckets are computed at compile time. Requires `start > 0`,
+//!   `factor > 1` and `count > 0`. Can't be used together with `buckets`.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(exponential_buckets(start = 0.005, factor = 2, count = 4))]
+//!   requests_duration_seconds: prometheus::Histogram,
+//!   # }
+//!   # let metrics = Metrics::new_unregistered().unwrap();
+//!   # assert_eq!(
+//!   #     metrics.requests_duration_seconds_buckets(),
+//!   #     prometheus::exponential_buckets(0.005, 2.0, 4).unwrap(),
+//!   # );
+//!   ```
+//!
+//! - **linear_buckets** — generate histogram buckets of equal width,
+//!   same as [`prometheus::linear_buckets`].
+//!
+//!   Buckets are computed at compile time. Requires `width > 0`
+//!   and `count > 0`. Can't be used together with `buckets`
+//!   or `exponential_buckets`.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(labels("endpoint"), linear_buckets(start = 0, width = 0.5, count = 4))]
+//!   requests_duration_seconds: prometheus::HistogramVec,
+//!   # }
+//!   # let metrics = Metrics::new_unregistered().unwrap();
+//!   # assert_eq!(
+//!   #     metrics.requests_duration_seconds_buckets(),
+//!   #     prometheus::linear_buckets(0.0, 0.5, 4).unwrap(),
+//!   # );
+//!   # let histogram = metrics.requests_duration_seconds.with_label_values(&["/"]);
+//!   # histogram.observe(0.7);
+//!   # histogram.observe(1.2);
+//!   # use prometheus::core::Collector;
+//!   # let families = histogram.collect();
+//!   # let buckets = families[0].get_metric()[0].get_histogram().get_bucket();
+//!   # let counts: Vec<_> = buckets.iter().map(|b| b.get_cumulative_count()).collect();
+//!   # assert_eq!(counts, vec![0, 0, 1, 2]);
+//!   ```
+//!
+//! - **positive_buckets** — a flag that requires all bucket bounds
+//!   to be greater than zero.
+//!
+//!   Histograms of durations or sizes never observe non-positive values,
+//!   so a `0` or a negative bound is usually a copy-paste mistake.
+//!   With this flag, such bounds cause a compilation error:
+//!
+//!   ```compile_fail
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(buckets(0.0, 0.1, 0.5, 1), positive_buckets)]
+//!   requests_duration_seconds: prometheus::Histogram,
+//!   # }
+//!   ```
+//!
+//! - **group** — name of a group of histograms that record the same
+//!   observations.
+//!
+//!   For every group, a method `observe_all_<group>(value, label_values)`
+//!   is generated. It observes the value in every histogram of the group.
+//!   For histogram vectors, label values select the child histogram,
+//!   scalar histograms ignore them.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Processing time of each request in seconds.
+//!       #[metric(group = "durations")]
+//!       requests_duration_seconds: prometheus::Histogram,
+//!
+//!       /// Processing time of each request in seconds, by endpoint.
+//!       #[metric(group = "durations", labels("endpoint"))]
+//!       endpoint_requests_duration_seconds: prometheus::HistogramVec,
+//!   }
+//!
+//!   # let metrics = Metrics::new_unregistered().unwrap();
+//!   metrics.observe_all_durations(0.25, &["/api"]);
+//!   # assert_eq!(metrics.requests_duration_seconds.get_sample_count(), 1);
+//!   # assert_eq!(
+//!   #     metrics
+//!   #         .endpoint_requests_duration_seconds
+//!   #         .with_label_values(&["/api"])
+//!   #         .get_sample_count(),
+//!   #     1
+//!   # );
+//!   ```
+//!
+//! - **external** — a flag that marks a field as an external collector.
+//!
+//!   External collectors are created via [`Default`] and registered
+//!   along with other metrics. They're responsible for their own names,
+//!   help messages and labels, so other options can't be used with them,
+//!   and neither subsystem nor const labels are applied to them.
+//!
+//!   This is useful for bridging pre-aggregated data, such as values
+//!   pulled from an external system, into a metric storage:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # use prometheus::core::{Collector, Desc};
+//!   # use prometheus::proto::MetricFamily;
+//!   #[derive(Clone)]
+//!   struct QueueDepth(prometheus::IntGauge);
+//!
+//!   impl Default for QueueDepth {
+//!       fn default() -> Self {
+//!           QueueDepth(prometheus::IntGauge::new("queue_depth", "Depth of the queue.").unwrap())
+//!       }
+//!   }
+//!
+//!   impl Collector for QueueDepth {
+//!       fn desc(&self) -> Vec<&Desc> {
+//!           self.0.desc()
+//!       }
+//!
+//!       fn collect(&self) -> Vec<MetricFamily> {
+//!           // Pull the value from an external system here.
+//!           self.0.set(42);
+//!           self.0.collect()
+//!       }
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       #[metric(external)]
+//!       queue_depth: QueueDepth,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   # let families = registry.gather();
+//!   # assert_eq!(families[0].get_name(), "queue_depth");
+//!   # assert_eq!(families[0].get_metric()[0].get_gauge().get_value(), 42.0);
+//!   ```
+//!
+//! - **flatten** — a flag that embeds another metric storage.
+//!
+//!   This allows splitting a large storage into logical modules.
+//!   The nested storage is created, registered and unregistered along with
+//!   the outer one, and its metrics are listed by [`MetricStorage::descs`].
+//!   It receives all const labels of the outer storage, so every const label
+//!   of the nested storage should also be declared by the outer one;
+//!   otherwise, `new` returns an error. Metric options can't be used
+//!   with flattened fields, the nested storage configures its own metrics.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("service"))]
+//!   struct DbMetrics {
+//!       /// Number of queries.
+//!       queries_total: prometheus::IntCounter,
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("service", "instance"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests_total: prometheus::IntCounter,
+//!       #[metric(flatten)]
+//!       db: DbMetrics,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, "api", "0").unwrap();
+//!   metrics.db.queries_total.inc();
+//!   # let families = registry.gather();
+//!   # assert_eq!(families.len(), 2);
+//!   # assert_eq!(families[0].get_name(), "queries_total");
+//!   # assert_eq!(families[0].get_metric()[0].get_label().len(), 2);
+//!   # assert_eq!(metrics.descs().len(), 2);
+//!   # metrics.unregister(&registry).unwrap();
+//!   # assert!(registry.gather().is_empty());
+//!   ```
+//!
+//!   Storages with flattened fields don't get the `rebind` method,
+//!   as nested storages are not required to implement [`Clone`].
+//!
+//! - **skip** — a flag that marks a field that's not a metric.
+//!
+//!   Skipped fields are initialized via [`Default`], and ignored when
+//!   registering metrics. This allows keeping helper data, such as
+//!   configuration, next to metrics that use it:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # use std::sync::Arc;
+//!   #[derive(Default)]
+//!   struct Config {
+//!       slow_request_threshold_seconds: f64,
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of slow requests.
+//!       slow_requests: prometheus::IntCounter,
+//!       #[metric(skip)]
+//!       config: Arc<Config>,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   if 0.5 > metrics.config.slow_request_threshold_seconds {
+//!       metrics.slow_requests.inc();
+//!   }
+//!   # assert_eq!(registry.gather().len(), 1);
+//!   # assert_eq!(metrics.descs().len(), 1);
+//!   ```
+//!
+//!   Use `from_parts` to initialize skipped fields with values other
+//!   than the default ones. Storages with skipped fields don't get
+//!   the `rebind` method.
+//!
+//! - **per** — for fields of type [`PerVariant`], a label type
+//!   that derives [`MetricLabel`].
+//!
+//!   A [`PerVariant`] field holds one metric for each variant of the
+//!   label enum. Each metric is registered under its own name, suffixed
+//!   with the variant's label value. This is a compile-time alternative
+//!   to metric vectors. The storage gets an accessor method named after
+//!   the field:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::{MetricLabel, MetricStorage, PerVariant};
+//!   #[derive(MetricLabel)]
+//!   enum Method {
+//!       Get,
+//!       Post,
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       #[metric(per = "Method")]
+//!       requests: PerVariant<Method, prometheus::IntCounter>,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.requests(Method::Get).inc();
+//!   metrics.requests(Method::Get).inc();
+//!   metrics.requests(Method::Post).inc();
+//!
+//!   assert_eq!(metrics.requests(Method::Get).get(), 2);
+//!   assert_eq!(metrics.requests(Method::Post).get(), 1);
+//!   # let families = registry.gather();
+//!   # assert_eq!(families.len(), 2);
+//!   # assert_eq!(families[0].get_name(), "requests_get");
+//!   # assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 2.0);
+//!   # assert_eq!(families[1].get_name(), "requests_post");
+//!   # assert_eq!(families[1].get_metric()[0].get_counter().get_value(), 1.0);
+//!   # assert_eq!(metrics.descs().len(), 2);
+//!   ```
+//!
+//! - **label_types** — for vector metrics, a mapping from names of labels
+//!   to types that implement [`MetricLabel`].
+//!
+//!   Unlike `per`, all label values share
+//!   a single metric vector. The storage gets an accessor method named
+//!   after the field. It accepts values of the field's labels
+//!   in the same order as they appear in the `labels` option, typed labels
+//!   take their type, and the rest take `&str`. The accessor returns
+//!   the child metric for the given label values:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+//!   #[derive(MetricLabel)]
+//!   enum RequestKind {
+//!       Read,
+//!       Write,
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Processing time of each request in seconds.
+//!       #[metric(labels("kind", "shard"), label_types(kind = "RequestKind"))]
+//!       requests_duration_seconds: prometheus::HistogramVec,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.requests_duration_seconds(RequestKind::Read, "0").observe(0.5);
+//!   metrics.requests_duration_seconds(RequestKind::Write, "0").observe(1.5);
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families.len(), 1);
+//!   let labels = families[0].get_metric()[0].get_label();
+//!   assert_eq!(labels[0].get_name(), "kind");
+//!   assert_eq!(labels[0].get_value(), "read");
+//!   # assert_eq!(families[0].get_metric()[1].get_label()[0].get_value(), "write");
+//!   # assert_eq!(families[0].get_metric()[1].get_histogram().get_sample_sum(), 1.5);
+//!   ```
+//!
+//! - **optional** — a flag for fields of type [`OptionalMetric`] that holds
+//!   metrics that are only created on demand, such as expensive histograms
+//!   behind a feature flag.
+//!
+//!   Constructors don't create such metrics, and the storage gets a method
+//!   `enable_{field}` that creates the metric and registers it
+//!   in the given registry. Const labels are remembered when the storage
+//!   is created, so this method only accepts a registry. It takes `&self`,
+//!   so that storages shared by a [`StorageRegistry`] can enable
+//!   their metrics, too. Enabling a metric twice does nothing:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::{MetricStorage, OptionalMetric};
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("tenant"))]
+//!   struct Metrics {
+//!       /// Number of requests.
+//!       requests: prometheus::IntCounter,
+//!
+//!       /// Size of each request in bytes.
+//!       #[metric(optional, buckets(64, 1024, 16384))]
+//!       request_size_bytes: OptionalMetric<prometheus::Histogram>,
+//!   }
+//!
+//!   let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, "foo").unwrap();
+//!   assert!(metrics.request_size_bytes.get().is_none());
+//!   assert_eq!(registry.gather().len(), 1);
+//!
+//!   metrics.enable_request_size_bytes(&registry).unwrap();
+//!   metrics.enable_request_size_bytes(&registry).unwrap();
+//!   metrics.request_size_bytes.get().unwrap().observe(512.0);
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families.len(), 2);
+//!   assert_eq!(families[0].get_name(), "request_size_bytes");
+//!   assert_eq!(families[0].get_metric()[0].get_label()[0].get_value(), "foo");
+//!   assert_eq!(families[0].get_metric()[0].get_histogram().get_sample_count(), 1);
+//!   # assert_eq!(metrics.descs().len(), 2);
+//!   # assert_eq!(metrics.field_descs("request_size_bytes").unwrap().len(), 1);
+//!   # metrics.unregister(&registry).unwrap();
+//!   # assert!(registry.gather().is_empty());
+//!   ```
+//!
+//!   Methods of [`MetricStorage`], such as `register` and `descs`, skip
+//!   fields that are not enabled. Optional fields can't be used in enums,
+//!   nor together with `per`, `group` and `init_values`.
+//!
+//! - **lazy** — a flag for fields of type [`LazyMetric`] that holds
+//!   metrics that are created on first access.
+//!
+//!   The storage gets an accessor method named after the field. Lazy fields
+//!   are exported only after they're accessed:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::{LazyMetric, MetricStorage};
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Processing time of each request in seconds.
+//!       #[metric(lazy, buckets(0.1, 1))]
+//!       duration_seconds: LazyMetric<prometheus::Histogram>,
+//!   }
+//!
+//!   let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   assert!(registry.gather().is_empty());
+//!
+//!   metrics.duration_seconds().observe(0.5);
+//!   assert_eq!(registry.gather()[0].get_name(), "duration_seconds");
+//!   # assert_eq!(metrics.histogram_buckets()[0].1, vec![0.1, 1.0]);
+//!   ```
+//!
+//!   See [`LazyMetric`] for the trade-offs. Lazy fields can't be used
+//!   together with `per`, `optional`, `group` and `init_values`.
+//!
+//! # Supporting custom collectors
+//!
+//! If your project uses custom [collectors], metric storage will not be able
+//! to instantiate them by default. You'll have to implement [`MetricInit`]
+//! and possibly [`HistMetricInit`] for each of the collector you wish to use.
+//! To use custom histograms in groups, implement [`Observe`] as well.
+//! Alternatively, collectors that don't need metric options can be
+//! used as [external](#configuring-metrics) fields.
+//!
+//! # Metric storage registry
+//!
+//! When registering a metric storage, there's a requirement
+//! that a single metric should not be registered twice within
+//! the same registry. In practice, this means that, once a storage has been
+//! created and registered, it should not be created again:
+//!
+//! ```should_panic
+//! # use prometheus_metric_storage::MetricStorage;
+//! # #[derive(MetricStorage)]
+//! # struct Metrics {
+//! #     /// -
+//! #     pieces_of_stuff_processed: prometheus::IntCounter,
+//! # }
+//! fn do_stuff() {
+//! # /*
+//!     ...
+//! # */
+//!
+//!     let metrics = Metrics::new(prometheus::default_registry()).unwrap();
+//!     metrics.pieces_of_stuff_processed.inc();
+//! }
+//!
+//! # /*
+//! ...
+//! # */
+//!
+//! # fn main() {
+//! // The first call will work just fine.
+//! do_stuff();
+//!
+//! // The second call will panic, though, because the metrics
+//! // were already registered.
+//! do_stuff();
+//! # }
+//! ```
+//!
+//! There are two approaches to solve this issue.
+//!
+//! The first one is to create a static variable within the `do_stuff`'s body.
+//! As was pointed out in the [section about motivation](#motivation),
+//! the code will have to rely on the [default registry], so this is not
+//! suitable for libraries.
+//!
+//! The second one is to have `do_stuff` accept a reference to `Metrics`.
+//! This solution complicates component's public API, exposes implementation
+//! details of metric collection.
+//!
+//! To give a better way of dealing with the situation, this crate provides
+//! [`StorageRegistry`]: a wrapper around the default registry that keeps track
+//! of all created storages, and makes sure that a single storage
+//! is only registered once:
+//!
+//! ```
+//! # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+//! # #[derive(MetricStorage)]
+//! # struct Metrics {
+//! #     /// -
+//! #     pieces_of_stuff_processed: prometheus::IntCounter,
+//! # }
+//! fn do_stuff(registry: &StorageRegistry) {
+//! # /*
+//!     ...
+//! # */
+//!
+//!     let metrics = Metrics::instance(registry).unwrap();
+//!     metrics.pieces_of_stuff_processed.inc();
+//! }
+//!
+//! # /*
+//! ...
+//! # */
+//!
+//! # fn main() {
+//! let registry = StorageRegistry::default();
+//!
+//! // The first call will work just fine.
+//! do_stuff(&registry);
+//!
+//! // The second call will also work, because `StorageRegistry`
+//! // makes sure not to register storages twice.
+//! do_stuff(&registry);
+//! # }
+//! ```
+//!
+//! # Cargo features
+//!
+//! - **protobuf** — enables the `protobuf` feature of the `prometheus` crate.
+//!   It's off by default, so that crates that only use the text format
+//!   don't depend on `protobuf`. Neither this crate nor the generated code
+//!   rely on protobuf-only APIs.
+//! - **serde** — implements serialization for [`RegistryConfig`], and enables
+//!   `MetricStorage::metadata_json`.
+//! - **strict** — enables additional compile-time checks of help messages,
+//!   see [`max_help_length`](#configuring-metrics).
+//!
+//! [static metrics]: prometheus#static-metrics
+//! [default registry]: prometheus::default_registry
+//! [collectors]: prometheus::core::Collector
+//! [`subsystem`]: prometheus::Opts#structfield.subsystem
+//! [`namespace`]: prometheus::Opts#structfield.namespace
+//! [`const_labels`]: prometheus::Opts#structfield.const_labels
+//! [`MetricVec::with_label_values`]: prometheus::core::MetricVec::with_label_values
+
+#![deny(missing_docs)]
+
+#[cfg(doctest)]
+mod test_readme {
+    #[doc = include_str!("../../README.md")]
+    mod test_readme_impl {}
+}
+
+#[cfg(doctest)]
+mod test_derive {
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{instrument_metrics, MetricStorage};
+    /// # #[derive(MetricStorage)]
+    /// # struct Metrics {
+    /// #     /// -
+    /// #     requests: prometheus::IntCounter,
+    /// # }
+    /// #[instrument_metrics(counter = requests)]
+    /// fn handle() {}
+    /// ```
+    mod instrument_without_storage {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(lazy, optional)]
+    ///     requests: prometheus_metric_storage::OptionalMetric<
+    ///         prometheus_metric_storage::LazyMetric<prometheus::IntCounter>,
+    ///     >,
+    /// }
+    /// ```
+    mod lazy_with_optional {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(optional)]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod optional_not_optional_metric {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// enum Metrics {
+    ///     Enabled {
+    ///         /// -
+    ///         #[metric(optional)]
+    ///         requests: prometheus_metric_storage::OptionalMetric<prometheus::IntCounter>,
+    ///     },
+    /// }
+    /// ```
+    mod optional_in_enum {}
+
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{instrument_metrics, MetricStorage};
+    /// # #[derive(MetricStorage)]
+    /// # struct Metrics {
+    /// #     /// -
+    /// #     requests: prometheus::IntCounter,
+    /// # }
+    /// #[instrument_metrics(storage = Metrics::new_unregistered().unwrap())]
+    /// fn handle() {}
+    /// ```
+    mod instrument_without_metrics {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.1, 1e999))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod infinite_bucket {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.1, inf, 1))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod inf_not_last {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.1, 0.05, 0.2))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod unsorted_buckets {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(1, 2, 2, 4))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod duplicate_int_buckets {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(exponential_buckets(start = 0.1, factor = 1, count = 5))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod exponential_buckets_factor {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(exponential_buckets(start = 0, factor = 2, count = 5))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod exponential_buckets_start {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(exponential_buckets(start = 0.1, factor = 2, count = 0))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod exponential_buckets_count {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.1, 1), exponential_buckets(start = 0.1, factor = 2, count = 5))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod exponential_buckets_conflict {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(linear_buckets(start = 0, width = 0, count = 5))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod linear_buckets_width {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(linear_buckets(start = 0, width = 1, count = 0))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod linear_buckets_count {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(
+    ///         linear_buckets(start = 0, width = 1, count = 5),
+    ///         exponential_buckets(start = 0.1, factor = 2, count = 5),
+    ///     )]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod linear_buckets_conflict {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("__internal"))]
+    /// struct Metrics {}
+    /// ```
+    mod reserved_const_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels(region = "unknown"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod variable_label_default {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("region"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("region"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod variable_label_duplicates_const_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(external, labels("url"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod external_with_options {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Nested {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(flatten, labels("url"))]
+    ///     nested: Nested,
+    /// }
+    /// ```
+    mod flatten_with_labels {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Nested {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(flatten, buckets(0.1, 1))]
+    ///     nested: Nested,
+    /// }
+    /// ```
+    mod flatten_with_buckets {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    ///     #[metric(skip)]
+    ///     started: std::time::Instant,
+    /// }
+    /// ```
+    mod skip_without_default {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(skip, name = "requests")]
+    ///     requests: u64,
+    /// }
+    /// ```
+    mod skip_with_options {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(owned_registry)]
+    /// enum Metrics {
+    ///     Enabled {
+    ///         /// -
+    ///         requests: prometheus::IntCounter,
+    ///     },
+    /// }
+    /// ```
+    mod owned_registry_on_enum {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(builder)]
+    /// enum Metrics {
+    ///     Enabled {
+    ///         /// -
+    ///         requests: prometheus::Histogram,
+    ///     },
+    /// }
+    /// ```
+    mod builder_on_enum {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(subsystem = "http", separator = "-")]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod invalid_separator {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(namespace = "myapp", namespace_separator = "")]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod empty_namespace_separator {}
+
+    /// ```compile_fail
+    /// fn make_name(namespace: &str, subsystem: &str, name: &str) -> String {
+    ///     format!("{}{}{}", namespace, subsystem, name)
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(separator = ":", name_fn = "make_name")]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod separator_with_name_fn {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(builder)]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    ///     /// -
+    ///     duration: prometheus::Histogram,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    /// Metrics::builder()
+    ///     .with_duration_buckets(vec![1.0])
+    ///     .build(&registry)
+    ///     .unwrap();
+    /// ```
+    ///
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(builder)]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    ///     /// -
+    ///     duration: prometheus::Histogram,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    /// Metrics::builder()
+    ///     .with_requests_buckets(vec![1.0])
+    ///     .build(&registry)
+    ///     .unwrap();
+    /// ```
+    mod builder_buckets_on_counter {}
+
+    /// ```
+    /// # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+    /// #[derive(MetricLabel)]
+    /// enum Kind {
+    ///     Read,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("kind"), label_types(kind = "Kind"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+    /// #[derive(MetricLabel)]
+    /// enum Kind {
+    ///     Read,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("kind"), label_types(shard = "Kind"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod field_label_types_unknown_label {}
+
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+    /// #[derive(MetricLabel)]
+    /// enum Kind {
+    ///     Read,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// enum Metrics {
+    ///     Enabled {
+    ///         /// -
+    ///         #[metric(labels("kind"), label_types(kind = "Kind"))]
+    ///         requests: prometheus::IntCounterVec,
+    ///     },
+    /// }
+    /// ```
+    mod field_label_types_in_enum {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(name = "my-metric")]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod invalid_metric_name {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(rename_all = "camelCase")]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod unknown_rename_all {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.0..=10.0, count = 1))]
+    ///     batch_size: prometheus::Histogram,
+    /// }
+    /// ```
+    mod range_buckets_single_count {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(10.0..=0.0, count = 3))]
+    ///     batch_size: prometheus::Histogram,
+    /// }
+    /// ```
+    mod range_buckets_reversed {}
+
+    /// ```
+    /// use prometheus_metric_storage::MetricStorage;
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(buckets(-1..=1, count = 3))]
+    /// struct Metrics {
+    ///     /// -
+    ///     delta: prometheus::Histogram,
+    /// }
+    ///
+    /// let metrics = Metrics::new_unregistered().unwrap();
+    /// assert_eq!(metrics.histogram_buckets()[0].1, vec![-1.0, 0.0, 1.0]);
+    /// ```
+    mod range_buckets_struct_level {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(rename(requests = "0requests"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod invalid_renamed_metric_name {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(buckets(0.1, 1), exponential_buckets(start = 1, factor = 2, count = 3))]
+    /// struct Metrics {
+    ///     /// -
+    ///     latency: prometheus::Histogram,
+    /// }
+    /// ```
+    mod struct_level_exponential_buckets {}
+
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{MetricLabel, PerVariant};
+    /// #[derive(MetricLabel)]
+    /// enum Method {
+    ///     Get,
+    /// }
+    ///
+    /// #[derive(MetricLabel)]
+    /// enum Status {
+    ///     Ok,
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(per = "Status")]
+    ///     requests: PerVariant<Method, prometheus::IntCounter>,
+    /// }
+    /// ```
+    mod per_with_wrong_label {}
+
+    /// ```compile_fail
+    /// # use prometheus_metric_storage::{MetricLabel, PerVariant};
+    /// #[derive(MetricLabel)]
+    /// enum Method {
+    ///     Get,
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(per = "Method", labels("code"), init_values("200"))]
+    ///     requests: PerVariant<Method, prometheus::IntCounterVec>,
+    /// }
+    /// ```
+    mod per_with_init_values {}
+
+    /// ```
+    /// use prometheus_metric_storage::MetricStorage;
+    /// use std::convert::TryFrom;
+    /// use std::marker::PhantomData;
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct Metrics<'a, T>
+    /// where
+    ///     T: Default,
+    /// {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    ///     #[metric(skip)]
+    ///     marker: PhantomData<&'a T>,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// enum Modes<T> {
+    ///     #[metric(default)]
+    ///     Enabled {
+    ///         /// -
+    ///         requests: prometheus::IntCounter,
+    ///         #[metric(skip)]
+    ///         marker: PhantomData<T>,
+    ///     },
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::<u8>::try_from(&registry).unwrap();
+    /// metrics.requests.inc();
+    /// assert!(Modes::<u8>::new_unregistered().is_ok());
+    /// ```
+    mod generics {}
+
+    /// ```
+    /// use prometheus_metric_storage::MetricStorage;
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("region"))]
+    /// struct Nested {
+    ///     /// -
+    ///     #[metric(buckets(0.1, 1))]
+    ///     duration_seconds: prometheus::Histogram,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(flatten)]
+    ///     nested: Nested,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("region"))]
+    /// enum Modes {
+    ///     #[metric(default)]
+    ///     Full {
+    ///         #[metric(flatten)]
+    ///         nested: Nested,
+    ///     },
+    /// }
+    ///
+    /// match Metrics::new_unregistered() {
+    ///     Err(prometheus::Error::Msg(msg)) => assert!(msg.contains("region")),
+    ///     _ => panic!("expected an error"),
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let modes = Modes::new(&registry, "eu").unwrap();
+    /// assert_eq!(modes.histogram_buckets()[0].1, vec![0.1, 1.0]);
+    /// assert_eq!(registry.gather().len(), 1);
+    /// modes.unregister(&registry).unwrap();
+    /// assert!(registry.gather().is_empty());
+    /// ```
+    mod flatten_missing_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(rename(requests = "http_requests_total"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(name = "requests_total")]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod rename_and_name {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(rename(reqests = "http_requests_total"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod rename_unknown_field {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(subsystem = "http", subsystem_from_module)]
+    /// struct Metrics {}
+    /// ```
+    mod subsystem_and_subsystem_from_module {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(namespace = "myapp", namespace = "other")]
+    /// struct Metrics {}
+    /// ```
+    mod redefined_namespace {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(namespace = "myapp")]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests_total: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    /// assert_eq!(registry.gather()[0].get_name(), "myapp_requests_total");
+    /// ```
+    mod namespace_without_subsystem {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(help = "")]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod empty_help {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(max_help_length = 10)]
+    /// struct Metrics {
+    ///     /// Number of requests that were processed.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod long_help {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(help = "Total {count} processed")]
+    ///     items: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod help_unknown_placeholder {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(help = "Total {name processed")]
+    ///     items: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod help_unmatched_brace {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// Total {items} processed.
+    ///     items: prometheus::IntCounter,
+    /// }
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    /// assert_eq!(registry.gather()[0].get_help(), "Total {items} processed.");
+    /// ```
+    mod doc_help_not_templated {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod too_many_labels {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("version"), const_labels(version = "1.2.3"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod const_label_duplicates_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricLabel)]
+    /// enum Region {
+    ///     Europe,
+    /// }
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("region"), label_types(region = "Region"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// Metrics::new(&registry, "europe").unwrap();
+    /// ```
+    mod label_type_mismatch {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("region"), label_types(zone = "String"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod label_type_unknown_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricLabel)]
+    /// enum Region {
+    ///     Custom(String),
+    /// }
+    /// ```
+    mod label_with_fields {}
+
+    /// ```
+    /// use prometheus_metric_storage::{MetricLabel, MetricStorage, StorageRegistry};
+    ///
+    /// #[derive(MetricLabel)]
+    /// enum Region {
+    ///     Europe,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("region", zone = "a"), label_types(region = "Region"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("region"), label_types(region = "Region"))]
+    /// enum Modes {
+    ///     #[metric(default)]
+    ///     Enabled {
+    ///         /// -
+    ///         requests: prometheus::IntCounter,
+    ///     },
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// Metrics::instance_with_defaults(&registry, Region::Europe).unwrap();
+    /// Modes::new_unregistered_enabled(Region::Europe).unwrap();
+    /// assert!(Metrics::instance(&registry, Region::Europe, "a").is_ok());
+    /// ```
+    mod label_types_with_defaults {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(const_labels(version = "1.2.3"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("version"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod variable_label_duplicates_fixed_label {}
+
+    /// ```
+    /// use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(const_labels(version = "1.2.3"))]
+    /// enum Modes {
+    ///     #[metric(default)]
+    ///     Enabled {
+    ///         /// -
+    ///         requests: prometheus::IntCounter,
+    ///     },
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// Modes::instance(&registry).unwrap();
+    /// let labels = registry.gather()[0].get_metric()[0].get_label().to_vec();
+    /// assert_eq!(labels[0].get_name(), "version");
+    /// assert_eq!(labels[0].get_value(), "1.2.3");
+    /// ```
+    mod enum_fixed_labels {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("url", "status"), init_values("200"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod init_values_with_many_labels {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     #[cfg_attr(not(debug_assertions), metric(help = "Number of requests, including retries."))]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    /// let help = registry.gather()[0].get_help().to_string();
+    /// if cfg!(debug_assertions) {
+    ///     assert_eq!(help, "Number of requests.");
+    /// } else {
+    ///     assert_eq!(help, "Number of requests, including retries.");
+    /// }
+    /// ```
+    mod inactive_cfg_attr_help {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// pub struct Metrics {
+    ///     #[doc(hidden)]
+    ///     #[metric(help = "Number of internal retries.")]
+    ///     pub retries: prometheus::IntCounter,
+    ///
+    ///     #[doc(hidden)]
+    ///     /// Number of requests.
+    ///     pub requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    /// let families = registry.gather();
+    /// assert_eq!(families.len(), 2);
+    /// assert_eq!(families[0].get_name(), "requests");
+    /// assert_eq!(families[0].get_help(), "Number of requests.");
+    /// assert_eq!(families[1].get_name(), "retries");
+    /// assert_eq!(families[1].get_help(), "Number of internal retries.");
+    /// ```
+    mod doc_hidden_field {}
+
+    /// ```
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(buckets(0.1, 0.5, 1, inf), positive_buckets)]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    /// ```
+    mod positive_buckets {}
+
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+    /// #[derive(MetricStorage)]
+    /// struct RegistryMetrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// for _ in 0..20 {
+    ///     let registry = StorageRegistry::default();
+    ///     let barrier = std::sync::Barrier::new(16);
+    ///
+    ///     let addresses: Vec<usize> = std::thread::scope(|scope| {
+    ///         let handles: Vec<_> = (0..16)
+    ///             .map(|_| {
+    ///                 scope.spawn(|| {
+    ///                     barrier.wait();
+    ///                     let metrics = RegistryMetrics::instance(&registry).unwrap();
+    ///                     metrics.requests.inc();
+    ///                     metrics as *const RegistryMetrics as usize
+    ///                 })
+    ///             })
+    ///             .collect();
+    ///         handles.into_iter().map(|h| h.join().unwrap()).collect()
+    ///     });
+    ///
+    ///     assert!(addresses.iter().all(|&address| address == addresses[0]));
+    ///
+    ///     let families = registry.gather();
+    ///     assert_eq!(families.len(), 1);
+    ///     assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 16.0);
+    /// }
+    /// ```
+    mod concurrent_instance {}
+
+    /// ```
+    /// use prometheus_metric_storage::MetricStorage;
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("service"))]
+    /// enum Metrics {
+    ///     Active {
+    ///         /// -
+    ///         #[metric(buckets(0.1, 1))]
+    ///         duration_seconds: prometheus::Histogram,
+    ///     },
+    ///     Disabled,
+    /// }
+    ///
+    /// let metrics = Metrics::new_unregistered_active("api").unwrap();
+    /// assert_eq!(metrics.histogram_buckets()[0].1, vec![0.1, 1.0]);
+    ///
+    /// let labels = std::iter::once(("service".to_string(), "api".to_string())).collect();
+    /// assert!(Metrics::from_const_labels_unregistered(labels).is_err());
+    /// ```
+    mod enum_without_default {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// enum Metrics {
+    ///     Active(prometheus::IntCounter),
+    /// }
+    /// ```
+    mod enum_tuple_variant {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("__internal"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod reserved_variable_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     #[metric(labels("content-type"))]
+    ///     requests: prometheus::IntCounterVec,
+    /// }
+    /// ```
+    mod invalid_variable_label {}
+
+    /// ```compile_fail
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// #[metric(labels("0shard"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod invalid_const_label {}
+
+    /// ```compile_fail
+    /// const HELP: &str = "Number of requests.";
+    ///
+    /// #[derive(prometheus_metric_storage::MetricStorage)]
+    /// struct Metrics {
+    ///     #[metric(help = "Number of requests.", help_from = "HELP")]
+    ///     requests: prometheus::IntCounter,
+    /// }
+    /// ```
+    mod help_with_help_from {}
+}
+
+mod dynamic;
+pub mod testing;
+
+pub use dynamic::{DynamicMetric, DynamicStorage, MetricDescriptor};
+
+use std::any::{Any, TypeId};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+#[doc(hidden)]
+pub use prometheus::core::{Collector, Desc};
+#[doc(hidden)]
+pub use prometheus::proto::MetricFamily;
+#[doc(hidden)]
+pub use prometheus::DEFAULT_BUCKETS;
+#[doc(hidden)]
+pub use prometheus::{Error, Opts, Registry, Result};
+
+/// Generates implementation for [`MetricStorage`] and three additional
+/// methods: `new`, `new_unregistered`, `instance`.
+///
+/// See the [crate-level] documentation for more info.
+///
+/// [crate-level]: crate#generated-code-api
+pub use prometheus_metric_storage_derive::MetricStorage;
+
+/// Generates implementations for [`MetricLabel`] and [`MetricLabelVariants`]
+/// for enums without fields.
+///
+/// Variants are converted to label values by changing their names
+/// to `snake_case`. See [`MetricLabel`] for an example.
+pub use prometheus_metric_storage_derive::MetricLabel;
+
+/// Instruments a function with metrics from a storage: increments a counter
+/// on every call, and observes duration of each call in a histogram.
+///
+/// The `storage` parameter is an expression that evaluates to a storage,
+/// or to a reference to it. It's evaluated on every call. The `counter`
+/// and `histogram` parameters are names of the storage's fields,
+/// at least one of them is required:
+///
+/// ```
+/// # use prometheus_metric_storage::{instrument_metrics, MetricStorage};
+/// # use lazy_static::lazy_static;
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+///     /// Processing time of each request in seconds.
+///     duration: prometheus::Histogram,
+/// }
+///
+/// lazy_static! {
+///     static ref METRICS: Metrics = Metrics::new_unregistered().unwrap();
+/// }
+///
+/// #[instrument_metrics(storage = METRICS, counter = requests, histogram = duration)]
+/// fn handle(request: &str) -> usize {
+///     request.len()
+/// }
+///
+/// assert_eq!(handle("foo"), 3);
+/// assert_eq!(METRICS.requests.get(), 1);
+/// assert_eq!(METRICS.duration.get_sample_count(), 1);
+/// ```
+///
+/// Async functions are supported as well. Their duration is measured
+/// from the first poll of the returned future until it completes:
+///
+/// ```
+/// # use prometheus_metric_storage::{instrument_metrics, MetricStorage, StorageRegistry};
+/// # use std::future::Future;
+/// # #[derive(MetricStorage)]
+/// # struct Metrics {
+/// #     /// Number of requests.
+/// #     requests: prometheus::IntCounter,
+/// #     /// Processing time of each request in seconds.
+/// #     duration: prometheus::Histogram,
+/// # }
+/// # lazy_static::lazy_static! {
+/// #     static ref REGISTRY: StorageRegistry = StorageRegistry::default();
+/// # }
+/// #[instrument_metrics(
+///     storage = Metrics::instance(&REGISTRY).unwrap(),
+///     counter = requests,
+///     histogram = duration,
+/// )]
+/// async fn handle(request: &str) -> usize {
+///     request.len()
+/// }
+///
+/// # struct Noop;
+/// # impl std::task::Wake for Noop {
+/// #     fn wake(self: std::sync::Arc<Self>) {}
+/// # }
+/// # let waker = std::sync::Arc::new(Noop).into();
+/// # let mut cx = std::task::Context::from_waker(&waker);
+/// # let mut future = Box::pin(handle("foo"));
+/// # assert!(future.as_mut().poll(&mut cx).is_ready());
+/// # /*
+/// handle("foo").await;
+/// # */
+///
+/// let metrics = Metrics::instance(&REGISTRY).unwrap();
+/// assert_eq!(metrics.requests.get(), 1);
+/// assert_eq!(metrics.duration.get_sample_count(), 1);
+/// ```
+///
+/// The counter is incremented with `inc`, and the histogram is observed
+/// with `start_timer`, so fields should be [`IntCounter`]s or [`Counter`]s,
+/// and [`Histogram`]s respectively.
+///
+/// [`IntCounter`]: prometheus::IntCounter
+/// [`Counter`]: prometheus::Counter
+/// [`Histogram`]: prometheus::Histogram
+pub use prometheus_metric_storage_derive::instrument_metrics;
+
+/// Identifier of a single storage in [`StorageRegistry`].
+///
+/// Storage ID consists of a type ID and static label values
+/// concatenated into a single string with zero bytes as a delimiter.
+type StorageId = (TypeId, String);
+
+/// Type-erased storage saved in [`StorageRegistry`].
+trait AnyStorage: Any + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    fn register(&self, registry: &dyn MetricRegistry) -> Result<()>;
+
+    fn unregister(&self, registry: &dyn MetricRegistry) -> Result<()>;
+
+    fn into_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+impl<T: MetricStorage + Send + Sync + 'static> AnyStorage for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn register(&self, registry: &dyn MetricRegistry) -> Result<()> {
+        MetricStorage::register(self, registry)
+    }
+
+    fn unregister(&self, registry: &dyn MetricRegistry) -> Result<()> {
+        MetricStorage::unregister(self, registry)
+    }
+
+    fn into_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Type-erased map of keys to storage IDs saved in [`StorageRegistry`].
+trait AnyKeys: Any + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn evict(&mut self, metric_id: &StorageId);
+}
+
+impl<K: Hash + Eq + Send + Sync + 'static> AnyKeys for HashMap<K, StorageId> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn evict(&mut self, metric_id: &StorageId) {
+        self.retain(|_, id| id != metric_id);
+    }
+}
+
+/// Wrapper for prometheus' [`Registry`] that keeps track of registered
+/// storages, and helps to avoid "already registered" errors without
+/// having to use lazy statics.
+///
+/// See the [crate-level] documentation for more info.
+///
+/// # Custom registries
+///
+/// Storage registry can wrap any [`MetricRegistry`], not just [`Registry`].
+/// Use [`with_registry`] to create one:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricRegistry, MetricStorage, StorageRegistry};
+/// # use prometheus::core::Collector;
+/// # use prometheus::proto::MetricFamily;
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// #[derive(Default)]
+/// struct CountingRegistry {
+///     registry: prometheus::Registry,
+///     registered: AtomicUsize,
+/// }
+///
+/// impl MetricRegistry for CountingRegistry {
+///     fn register(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+///         self.registered.fetch_add(1, Ordering::Relaxed);
+///         self.registry.register(c)
+///     }
+///
+///     fn unregister(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+///         self.registry.unregister(c)
+///     }
+///
+///     fn gather(&self) -> Vec<MetricFamily> {
+///         self.registry.gather()
+///     }
+/// }
+///
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::with_registry(CountingRegistry::default());
+/// Metrics::instance(&registry).unwrap().requests.inc();
+/// Metrics::instance(&registry).unwrap().requests.inc();
+///
+/// assert_eq!(registry.registry().registered.load(Ordering::Relaxed), 1);
+/// assert_eq!(registry.gather()[0].get_metric()[0].get_counter().get_value(), 2.0);
+/// ```
+///
+/// # Limitations
+///
+/// Calls to [`get_storage`] and [`get_or_create_storage`] involve
+/// concatenating values of all const labels into a single string,
+/// and looking up this string in a hashtable. Make sure that these
+/// calls are out of your hot path.
+///
+/// [crate-level]: crate#metric-storage-registry
+/// [`get_storage`]: StorageRegistry::get_storage
+/// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+/// [`with_registry`]: StorageRegistry::with_registry
+pub struct StorageRegistry<R = Registry> {
+    /// The underlying metrics registry.
+    registry: R,
+
+    /// Configuration the underlying registry was created with.
+    config: RegistryConfig,
+
+    /// Saved registered storages.
+    ///
+    /// # Safety
+    ///
+    /// Storages in this hashmap must not be removed or replaced
+    /// while this registry is borrowed immutably. This registry must hold
+    /// a strong reference to every storage until it is dropped,
+    /// or until the storage is evicted by [`remove`], which takes
+    /// `&mut self`.
+    ///
+    /// [`remove`]: StorageRegistry::remove
+    storages: Mutex<HashMap<StorageId, Arc<dyn AnyStorage>>>,
+
+    /// Registries that track metrics registered in a group.
+    groups: Mutex<HashMap<String, Registry>>,
+
+    /// Storages created by [`instance_scoped`].
+    ///
+    /// [`instance_scoped`]: StorageRegistry::instance_scoped
+    scoped: Mutex<HashMap<StorageId, Weak<dyn Any + Send + Sync>>>,
+
+    /// Storage IDs cached by [`instance_by_key`].
+    ///
+    /// For each pair of storage type and key type, contains
+    /// a `HashMap<K, StorageId>`.
+    ///
+    /// [`instance_by_key`]: StorageRegistry::instance_by_key
+    keys: Mutex<HashMap<(TypeId, TypeId), Box<dyn AnyKeys>>>,
+
+    /// Families returned by the last call to [`gather_changed`].
+    ///
+    /// [`gather_changed`]: StorageRegistry::gather_changed
+    last_gathered: Mutex<HashMap<String, MetricFamily>>,
+
+    /// Result of the last call to [`cached_gather`], and its time.
+    ///
+    /// [`cached_gather`]: StorageRegistry::cached_gather
+    gather_cache: Mutex<Option<(Instant, Arc<Vec<u8>>)>>,
+}
+
+impl StorageRegistry {
+    /// Create a new storage registry.
+    ///
+    /// Prometheus doesn't expose configuration of an existing [`Registry`],
+    /// so [`config`] of the created storage registry will be empty.
+    /// Use [`new_custom`] if you need it to be tracked.
+    ///
+    /// [`config`]: StorageRegistry::config
+    /// [`new_custom`]: StorageRegistry::new_custom
+    pub fn new(registry: prometheus::Registry) -> Self {
+        Self::with_registry(registry)
+    }
+
+    /// Create a new storage registry with a new underlying [`Registry`],
+    /// with optional custom prefix and labels.
+    ///
+    /// See [`Registry::new_custom`] for more info.
+    pub fn new_custom(
+        prefix: Option<String>,
+        labels: Option<HashMap<String, String>>,
+    ) -> Result<Self> {
+        let config = RegistryConfig {
+            prefix: prefix.clone(),
+            const_labels: labels
+                .as_ref()
+                .map(|labels| labels.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        };
+
+        Ok(Self {
+            config,
+            ..Self::new(Registry::new_custom(prefix, labels)?)
+        })
+    }
+}
+
+impl<R: MetricRegistry> StorageRegistry<R> {
+    /// Create a new storage registry that wraps a custom registry.
+    ///
+    /// See [custom registries] for an example.
+    ///
+    /// [custom registries]: StorageRegistry#custom-registries
+    pub fn with_registry(registry: R) -> Self {
+        Self {
+            registry,
+            config: Default::default(),
+            storages: Default::default(),
+            groups: Default::default(),
+            scoped: Default::default(),
+            keys: Default::default(),
+            last_gathered: Default::default(),
+            gather_cache: Default::default(),
+        }
+    }
+
+    /// Return configuration of the underlying [`Registry`].
+    ///
+    /// With the `serde` feature enabled, the returned config can be
+    /// serialized to compare metric setups across deployments:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::StorageRegistry;
+    /// # use std::collections::{BTreeMap, HashMap};
+    /// let mut labels = HashMap::new();
+    /// labels.insert("region".to_string(), "eu".to_string());
+    ///
+    /// let registry = StorageRegistry::new_custom(Some("myapp".into()), Some(labels)).unwrap();
+    ///
+    /// let config = registry.config();
+    /// assert_eq!(config.prefix.as_deref(), Some("myapp"));
+    ///
+    /// # #[cfg(feature = "serde")]
+    /// assert_eq!(
+    ///     serde_json::to_string(config).unwrap(),
+    ///     r#"{"prefix":"myapp","const_labels":{"region":"eu"}}"#,
+    /// );
+    /// ```
+    pub fn config(&self) -> &RegistryConfig {
+        &self.config
+    }
+
+    /// Return a reference to the underlying [`Registry`].
+    pub fn registry(&self) -> &R {
+        &self.registry
+    }
+
+    /// Convert this wrapper into the underlying [`Registry`].
+    ///
+    /// All information about registered storages is lost.
+    pub fn into_registry(self) -> R {
+        self.registry
+    }
+
+    /// Register a single metric in the underlying registry.
+    ///
+    /// Return an error if the given metric was already registered
+    /// when this function is called.
+    ///
+    /// See [`Registry::unregister`] for more info.
+    pub fn register(&self, c: Box<dyn Collector>) -> Result<()> {
+        self.registry.register(c)
+    }
+
+    /// Unregister a single metric from the underlying registry.
+    ///
+    /// Returns an error if the given metric was not registered
+    /// when this function is called.
+    ///
+    /// See [`Registry::unregister`] for more info.
+    pub fn unregister(&self, c: Box<dyn Collector>) -> Result<()> {
+        self.registry.unregister(c)
+    }
+
+    /// Unregister metrics of all storages cached in this registry
+    /// from the underlying registry.
+    ///
+    /// Storages stay in the cache and remain usable, they're just not
+    /// exported anymore. This allows pausing exposition temporarily.
+    /// Use [`register_all`] to resume it:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let metrics = Metrics::instance(&registry).unwrap();
+    ///
+    /// registry.unregister_all().unwrap();
+    /// assert!(registry.gather().is_empty());
+    ///
+    /// metrics.requests.inc();
+    /// assert_eq!(Metrics::instance(&registry).unwrap().requests.get(), 1);
+    ///
+    /// registry.register_all().unwrap();
+    /// assert_eq!(registry.gather().len(), 1);
+    /// ```
+    ///
+    /// Storages created by [`instance_scoped`] are not affected.
+    ///
+    /// Returns an error if metrics of some storage are not registered,
+    /// for example, if this function is called twice.
+    ///
+    /// [`register_all`]: StorageRegistry::register_all
+    /// [`instance_scoped`]: StorageRegistry::instance_scoped
+    pub fn unregister_all(&self) -> Result<()> {
+        for storage in self.storages.lock().unwrap().values() {
+            storage.unregister(&self.registry)?;
+        }
+        Ok(())
+    }
+
+    /// Register metrics of all storages cached in this registry
+    /// in the underlying registry.
+    ///
+    /// This function reverts effects of [`unregister_all`].
+    ///
+    /// [`unregister_all`]: StorageRegistry::unregister_all
+    pub fn register_all(&self) -> Result<()> {
+        for storage in self.storages.lock().unwrap().values() {
+            storage.register(&self.registry)?;
+        }
+        Ok(())
+    }
+
+    /// Gather all metrics from the underlying registry.
+    ///
+    /// See [`Registry::gather`] for more info.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+
+    /// Gather all metrics from the underlying registry, and apply
+    /// the given transformations to the result.
+    ///
+    /// Transformations are applied in order they're given. This allows
+    /// relabeling exported metrics without reconfiguring Prometheus:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage, Transform};
+    /// # #[derive(MetricStorage)]
+    /// # struct Metrics {
+    /// #     /// -
+    /// #     requests: prometheus::IntCounter,
+    /// # }
+    /// let registry = StorageRegistry::default();
+    /// let metrics = Metrics::instance(&registry).unwrap();
+    ///
+    /// let families = registry.gather_with(&[Transform::Rename {
+    ///     from: "requests".into(),
+    ///     to: "requests_total".into(),
+    /// }]);
+    ///
+    /// assert_eq!(families[0].get_name(), "requests_total");
+    /// ```
+    pub fn gather_with(&self, transforms: &[Transform]) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        for transform in transforms {
+            transform.apply(&mut families);
+        }
+        families
+    }
+
+    /// Gather metrics from the underlying registry, and return only
+    /// families that changed since the previous call to this function.
+    ///
+    /// This can reduce exposition size for sets of mostly idle metrics:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # #[derive(MetricStorage)]
+    /// # struct Metrics {
+    /// #     /// -
+    /// #     requests: prometheus::IntCounter,
+    /// #     /// -
+    /// #     errors: prometheus::IntCounter,
+    /// # }
+    /// let registry = StorageRegistry::default();
+    /// let metrics = Metrics::instance(&registry).unwrap();
+    /// assert_eq!(registry.gather_changed().len(), 2);
+    ///
+    /// metrics.requests.inc();
+    /// let changed = registry.gather_changed();
+    /// assert_eq!(changed.len(), 1);
+    /// assert_eq!(changed[0].get_name(), "requests");
+    /// ```
+    ///
+    /// Note that this is not a standard exposition mechanism. Prometheus
+    /// considers series that are missing from a scrape to be stale,
+    /// so this function requires an exporter that remembers previously
+    /// exposed values. Also, all callers share the same state, so this
+    /// function is only meaningful if there's a single consumer.
+    pub fn gather_changed(&self) -> Vec<MetricFamily> {
+        let families = self.registry.gather();
+
+        let mut last_gathered = self.last_gathered.lock().unwrap();
+        let mut current = HashMap::with_capacity(families.len());
+        let mut changed = Vec::new();
+
+        for family in families {
+            if last_gathered.get(family.get_name()) != Some(&family) {
+                changed.push(family.clone());
+            }
+            current.insert(family.get_name().to_string(), family);
+        }
+
+        *last_gathered = current;
+
+        changed
+    }
+
+    /// Gather metrics from the underlying registry and encode them
+    /// in the text format, reusing the previous result if it's younger
+    /// than `ttl`.
+    ///
+    /// This is useful for `/metrics` endpoints that are scraped very often,
+    /// for example, by multiple prometheus replicas. Note that the returned
+    /// data can be up to `ttl` old, so keep it well below
+    /// the scrape interval:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let metrics = Metrics::instance(&registry).unwrap();
+    ///
+    /// let first = registry.cached_gather(Duration::from_secs(60)).unwrap();
+    /// metrics.requests.inc();
+    /// let second = registry.cached_gather(Duration::from_secs(60)).unwrap();
+    /// assert!(Arc::ptr_eq(&first, &second));
+    ///
+    /// let third = registry.cached_gather(Duration::ZERO).unwrap();
+    /// assert!(!Arc::ptr_eq(&first, &third));
+    /// assert!(String::from_utf8_lossy(&third).contains("requests 1"));
+    /// ```
+    pub fn cached_gather(&self, ttl: Duration) -> Result<Arc<Vec<u8>>> {
+        use prometheus::Encoder;
+
+        let mut cache = self.gather_cache.lock().unwrap();
+
+        if let Some((gathered_at, encoded)) = &*cache {
+            if gathered_at.elapsed() < ttl {
+                return Ok(encoded.clone());
+            }
+        }
+
+        let mut encoded = Vec::new();
+        prometheus::TextEncoder::new().encode(&self.registry.gather(), &mut encoded)?;
+        let encoded = Arc::new(encoded);
+        *cache = Some((Instant::now(), encoded.clone()));
+
+        Ok(encoded)
+    }
+
+    /// Register all metrics from the given storage in the underlying registry,
+    /// and add them to the given group.
+    ///
+    /// Metrics from a group can be gathered separately
+    /// via [`gather_group`]. This is useful for serving per-tenant
+    /// endpoints, such as `/metrics/<tenant>`:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// struct FooMetrics {
+    ///     /// -
+    ///     foo: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct BarMetrics {
+    ///     /// -
+    ///     bar: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let foo = FooMetrics::new_unregistered().unwrap();
+    /// foo.register_into_group(&registry, "foo").unwrap();
+    /// let bar = BarMetrics::new_unregistered().unwrap();
+    /// bar.register_into_group(&registry, "bar").unwrap();
+    ///
+    /// assert_eq!(registry.gather().len(), 2);
+    /// assert_eq!(registry.gather_group("foo")[0].get_name(), "foo");
+    /// assert_eq!(registry.gather_group("bar")[0].get_name(), "bar");
+    /// ```
+    ///
+    /// If the storage can't be registered in the group, it is unregistered
+    /// from the underlying registry:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let metrics = Metrics::new_unregistered().unwrap();
+    /// metrics.register_into_group(&registry, "foo").unwrap();
+    ///
+    /// // The group still exports the storage.
+    /// metrics.unregister(registry.registry()).unwrap();
+    ///
+    /// assert!(metrics.register_into_group(&registry, "foo").is_err());
+    /// assert!(registry.gather().is_empty());
+    /// assert_eq!(registry.gather_group("foo").len(), 1);
+    /// ```
+    ///
+    /// [`gather_group`]: StorageRegistry::gather_group
+    pub fn register_in_group<T: MetricStorage>(
+        &self,
+        storage: &T,
+        group: &str,
+    ) -> std::result::Result<(), MetricStorageError> {
+        let mut groups = self.groups.lock().unwrap();
+
+        let group_registry = match groups.entry(group.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                Registry::new_custom(
+                    self.config.prefix.clone(),
+                    self.config
+                        .const_labels
+                        .as_ref()
+                        .map(|labels| labels.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+                )
+                .map_err(Self::storage_error::<T>)?,
+            ),
+        };
+
+        // The group should only export storages that are registered
+        // in the underlying registry, so the latter goes first.
+        storage.register_fields(&self.registry)?;
+        if let Err(err) = storage.register_fields(group_registry) {
+            storage.unregister(&self.registry).ok();
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Gather metrics that were registered in the given group.
+    ///
+    /// Returns an empty vector if nothing was registered in this group.
+    ///
+    /// See [`register_in_group`] for more info.
+    ///
+    /// [`register_in_group`]: StorageRegistry::register_in_group
+    pub fn gather_group(&self, group: &str) -> Vec<MetricFamily> {
+        match self.groups.lock().unwrap().get(group) {
+            Some(registry) => registry.gather(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Find a storage of the given type with tha given labels.
+    ///
+    /// Returns an error if the given metric storage was not registered
+    /// with the given labels, or if the given labels are invalid.
+    pub fn get_storage<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<&T, MetricStorageError> {
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        let mut storages = self.storages.lock().unwrap();
+
+        let storage = match storages.entry(metric_id) {
+            Entry::Occupied(entry) => entry.into_mut().as_any().downcast_ref::<T>().unwrap(),
+            Entry::Vacant(_) => {
+                return Err(Self::storage_error::<T>(Error::Msg(format!(
+                    "metric storage {} not found",
+                    std::any::type_name::<T>()
+                ))))
+            }
+        };
+
+        // Safety:
+        //
+        // See `get_or_insert_storage` for details.
+        unsafe { Ok(&*(storage as *const T)) }
+    }
+
+    /// Return all cached storages of the given type, together with their
+    /// const label values, sorted by label values.
+    ///
+    /// Label values are listed in the same order as they appear
+    /// in the `#[metric(labels(...))]` attribute, and compared
+    /// lexicographically. This gives a stable order for diagnostics
+    /// and golden tests:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant", "region"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// Metrics::instance(&registry, "b", "us").unwrap();
+    /// Metrics::instance(&registry, "a", "us").unwrap().requests.inc();
+    /// Metrics::instance(&registry, "b", "eu").unwrap();
+    ///
+    /// let labels: Vec<_> = registry
+    ///     .iter_sorted::<Metrics>()
+    ///     .map(|(labels, _)| labels.join("/"))
+    ///     .collect();
+    /// assert_eq!(labels, vec!["a/us", "b/eu", "b/us"]);
+    ///
+    /// let (_, first) = registry.iter_sorted::<Metrics>().next().unwrap();
+    /// assert_eq!(first.requests.get(), 1);
+    /// ```
+    ///
+    /// Storages created by [`instance_scoped`] are not included.
+    ///
+    /// [`instance_scoped`]: StorageRegistry::instance_scoped
+    pub fn iter_sorted<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+    ) -> impl Iterator<Item = (Vec<String>, &T)> {
+        let storages = self.storages.lock().unwrap();
+
+        let mut result: Vec<_> = storages
+            .iter()
+            .filter(|((type_id, _), _)| *type_id == TypeId::of::<T>())
+            .map(|((_, values), storage)| {
+                let labels: Vec<_> = values.split_terminator('\0').map(String::from).collect();
+                let storage = storage.as_any().downcast_ref::<T>().unwrap();
+
+                // Safety:
+                //
+                // See `get_or_insert_storage` for details.
+                (labels, unsafe { &*(storage as *const T) })
+            })
+            .collect();
+
+        result.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+        result.into_iter()
+    }
+
+    /// Return a storage of the given type with tha given labels. If such
+    /// storage does not exist in this registry, create it and register
+    /// its metrics.
+    ///
+    /// This function can be called from multiple threads concurrently.
+    /// If several threads race to create the same storage, each of them
+    /// may initialize its own copy, but only one copy is registered
+    /// and returned to all callers; others are discarded.
+    ///
+    /// Returns an error if the given labels are invalid or if storage creation
+    /// has failed. If some metric can't be registered, the returned
+    /// [`MetricStorageError`] names the field that holds it:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, MetricStorageError, StorageRegistry};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// Metrics::new(registry.registry()).unwrap();
+    ///
+    /// let err = Metrics::instance(&registry).map(drop).unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     MetricStorageError::Register { storage: "Metrics", field: "requests", .. }
+    /// ));
+    /// ```
+    pub fn get_or_create_storage<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<&T, MetricStorageError> {
+        self.get_or_create(const_labels).map(|(storage, _)| storage)
+    }
+
+    /// Like [`get_or_create_storage`], but also reports whether the storage
+    /// was created and registered by this call.
+    ///
+    /// The returned flag is `true` if this call has registered the storage,
+    /// and `false` if the storage was found in this registry:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let labels = |tenant: &str| {
+    ///     let mut labels = HashMap::new();
+    ///     labels.insert("tenant".to_string(), tenant.to_string());
+    ///     labels
+    /// };
+    ///
+    /// let (_, created) = registry.get_or_create::<Metrics>(labels("a")).unwrap();
+    /// assert!(created);
+    ///
+    /// let (_, created) = registry.get_or_create::<Metrics>(labels("a")).unwrap();
+    /// assert!(!created);
+    ///
+    /// let (_, created) = registry.get_or_create::<Metrics>(labels("b")).unwrap();
+    /// assert!(created);
+    /// ```
+    ///
+    /// If several threads race to create the same storage, only the one
+    /// whose copy was registered gets `true`.
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    pub fn get_or_create<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<(&T, bool), MetricStorageError> {
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        let (storage, created) = self.get_or_insert_arc_created(metric_id, || {
+            T::from_const_labels_unregistered(const_labels)
+        })?;
+
+        // Safety:
+        //
+        // See `get_or_insert_storage` for details.
+        unsafe { Ok((&*Arc::as_ptr(&storage), created)) }
+    }
+
+    /// Return a storage of the given type with tha given label values.
+    /// If such storage does not exist in this registry, create it
+    /// and register its metrics.
+    ///
+    /// Label values are listed in the same order as they appear
+    /// in the `#[metric(labels(...))]` attribute. The given strings
+    /// are moved into the cache key, so that callers that already own them
+    /// don't have to clone them. They're only copied when a new storage
+    /// is created:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant", "region"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let tenant = format!("tenant-{}", 1);
+    /// let metrics = registry
+    ///     .instance_owned::<Metrics>(vec![tenant, "eu".to_string()])
+    ///     .unwrap();
+    /// metrics.requests.inc();
+    ///
+    /// let again = registry
+    ///     .instance_owned::<Metrics>(vec!["tenant-1".to_string(), "eu".to_string()])
+    ///     .unwrap();
+    /// assert!(std::ptr::eq(metrics, again));
+    ///
+    /// let metrics = Metrics::instance(&registry, "tenant-1", "eu").unwrap();
+    /// assert_eq!(metrics.requests.get(), 1);
+    ///
+    /// assert!(registry.instance_owned::<Metrics>(vec!["tenant-1".to_string()]).is_err());
+    /// ```
+    pub fn instance_owned<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        label_values: Vec<String>,
+    ) -> std::result::Result<&T, MetricStorageError> {
+        let labels_spec = T::const_labels();
+
+        if labels_spec.len() != label_values.len() {
+            return Err(Self::storage_error::<T>(Error::Msg(format!(
+                "invalid number of const labels: expected {}, got {}",
+                labels_spec.len(),
+                label_values.len()
+            ))));
+        }
+
+        let mut values = String::new();
+        for (i, value) in label_values.into_iter().enumerate() {
+            if i == 0 {
+                values = value;
+            } else {
+                values.push_str(&value);
+            }
+            values.push('\0');
+        }
+
+        let metric_id = (TypeId::of::<T>(), values);
+
+        if let Some(storage) = self.storages.lock().unwrap().get(&metric_id) {
+            let storage = storage.as_any().downcast_ref::<T>().unwrap();
+
+            // Safety:
+            //
+            // See `get_or_insert_storage` for details.
+            return unsafe { Ok(&*(storage as *const T)) };
+        }
+
+        let const_labels = labels_spec
+            .iter()
+            .map(|label| label.to_string())
+            .zip(metric_id.1.split_terminator('\0').map(String::from))
+            .collect();
+
+        self.get_or_insert_storage(metric_id, || {
+            T::from_const_labels_unregistered(const_labels)
+        })
+    }
+
+    /// Return a storage of the given type with label values computed
+    /// by the given function. If such storage does not exist in this registry,
+    /// create it and register its metrics.
+    ///
+    /// This is similar to [`instance_owned`], but label computation
+    /// may fail, for example, when label values are parsed from a request.
+    /// Errors returned by the function are propagated as
+    /// [`MetricStorageError::Labels`], and can be downcast back
+    /// to their original type:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage, MetricStorageError};
+    /// # use std::error::Error;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(Debug)]
+    /// struct InvalidHeader;
+    ///
+    /// impl std::fmt::Display for InvalidHeader {
+    ///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    ///         f.write_str("invalid tenant header")
+    ///     }
+    /// }
+    ///
+    /// impl Error for InvalidHeader {}
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let parse = |header: &str| match header.strip_prefix("tenant=") {
+    ///     Some(tenant) => Ok(vec![tenant.to_string()]),
+    ///     None => Err(InvalidHeader),
+    /// };
+    ///
+    /// let metrics = registry.try_instance_with::<Metrics, _>(|| parse("tenant=foo"));
+    /// assert!(metrics.is_ok());
+    ///
+    /// let err = registry
+    ///     .try_instance_with::<Metrics, _>(|| parse("user=foo"))
+    ///     .map(drop)
+    ///     .unwrap_err();
+    /// assert!(matches!(err, MetricStorageError::Labels(_)));
+    /// assert!(err.source().unwrap().downcast_ref::<InvalidHeader>().is_some());
+    /// ```
+    ///
+    /// [`instance_owned`]: StorageRegistry::instance_owned
+    pub fn try_instance_with<T, E>(
+        &self,
+        f: impl FnOnce() -> std::result::Result<Vec<String>, E>,
+    ) -> std::result::Result<&T, MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let label_values = f().map_err(|err| MetricStorageError::Labels(err.into()))?;
+        self.instance_owned(label_values)
+    }
+
+    /// Return a storage of the given type that's cached by the given key.
+    /// If there's no such storage, create it with the given labels
+    /// and register its metrics.
+    ///
+    /// This is useful when the natural cache key is a typed value,
+    /// and stringifying it for every lookup would be wasteful.
+    /// Labels are only used when the key is seen for the first time:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let labels = |tenant_id: u64| {
+    ///     let mut labels = HashMap::new();
+    ///     labels.insert("tenant".to_string(), tenant_id.to_string());
+    ///     labels
+    /// };
+    ///
+    /// let metrics = registry.instance_by_key::<Metrics, u64>(1, labels(1)).unwrap();
+    /// metrics.requests.inc();
+    ///
+    /// let metrics = registry.instance_by_key::<Metrics, u64>(1, HashMap::new()).unwrap();
+    /// assert_eq!(metrics.requests.get(), 1);
+    /// ```
+    ///
+    /// Keyed storages share their cache with [`get_or_create_storage`],
+    /// so different keys that map to the same labels return the same storage.
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    pub fn instance_by_key<T, K>(
+        &self,
+        key: K,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<&T, MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        K: Hash + Eq + Send + Sync + 'static,
+    {
+        let key_id = (TypeId::of::<T>(), TypeId::of::<K>());
+
+        let cached_id = self.keys.lock().unwrap().get(&key_id).and_then(|ids| {
+            let ids = ids
+                .as_any()
+                .downcast_ref::<HashMap<K, StorageId>>()
+                .unwrap();
+            ids.get(&key).cloned()
+        });
+
+        if let Some(metric_id) = cached_id {
+            return self.get_or_insert_storage(metric_id, || {
+                T::from_const_labels_unregistered(const_labels)
+            });
+        }
+
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        let storage = self.get_or_insert_storage(metric_id.clone(), || {
+            T::from_const_labels_unregistered(const_labels)
+        })?;
+
+        self.keys
+            .lock()
+            .unwrap()
+            .entry(key_id)
+            .or_insert_with(|| Box::new(HashMap::<K, StorageId>::new()))
+            .as_any_mut()
+            .downcast_mut::<HashMap<K, StorageId>>()
+            .unwrap()
+            .entry(key)
+            .or_insert(metric_id);
+
+        Ok(storage)
+    }
+
+    /// Return a storage of the given type with tha given labels. If such
+    /// storage does not exist in this registry, create it using the given
+    /// function, and register its metrics.
+    ///
+    /// The function should return an unregistered storage.
+    /// Registry is not locked while the function runs, so it can request
+    /// other storages from this registry:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// struct FooMetrics {
+    ///     /// -
+    ///     foo: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// struct BarMetrics {
+    ///     /// -
+    ///     bar: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let foo = registry.instance_or_insert_with::<FooMetrics, _>(HashMap::new(), || {
+    ///     BarMetrics::instance(&registry)?.bar.inc();
+    ///     FooMetrics::new_unregistered()
+    /// }).unwrap();
+    /// foo.foo.inc();
+    ///
+    /// assert_eq!(BarMetrics::instance(&registry).unwrap().bar.get(), 1);
+    /// ```
+    ///
+    /// If several threads create the same storage simultaneously,
+    /// only one of the created storages is registered and saved,
+    /// the rest are discarded.
+    ///
+    /// Returns an error if the given labels are invalid or if storage creation
+    /// has failed.
+    pub fn instance_or_insert_with<T, F>(
+        &self,
+        const_labels: HashMap<String, String>,
+        init: F,
+    ) -> std::result::Result<&T, MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        F: FnOnce() -> Result<T>,
+    {
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        self.get_or_insert_storage(metric_id, init)
+    }
+
+    fn get_or_insert_storage<T, F>(
+        &self,
+        metric_id: StorageId,
+        init: F,
+    ) -> std::result::Result<&T, MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        F: FnOnce() -> Result<T>,
+    {
+        let storage = self.get_or_insert_arc(metric_id, init)?;
+
+        // Safety:
+        //
+        // This registry holds a strong reference to every storage
+        // until it is dropped or mutably borrowed by `remove`,
+        // thus they will live for as long as this borrow lives.
+        // Contents of an `Arc` are never moved. This means that a reference
+        // to a storage will stay valid for as long as this borrow lives.
+        //
+        // There are no issues with drop check because this registry
+        // does not implement custom drop, and the storage is `'static`.
+        //
+        // It is also ok to unlock mutex guard while holding reference
+        // to a storage because the storage is `Send + Sync`.
+        //
+        // Note that we're not returning a `'static` reference, but rather
+        // a reference with the lifetime of `&self`.
+        unsafe { Ok(&*Arc::as_ptr(&storage)) }
+    }
+
+    fn get_or_insert_arc<T, F>(
+        &self,
+        metric_id: StorageId,
+        init: F,
+    ) -> std::result::Result<Arc<T>, MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        F: FnOnce() -> Result<T>,
+    {
+        Ok(self.get_or_insert_arc_created(metric_id, init)?.0)
+    }
+
+    fn get_or_insert_arc_created<T, F>(
+        &self,
+        metric_id: StorageId,
+        init: F,
+    ) -> std::result::Result<(Arc<T>, bool), MetricStorageError>
+    where
+        T: MetricStorage + Send + Sync + 'static,
+        F: FnOnce() -> Result<T>,
+    {
+        if let Some(storage) = self.storages.lock().unwrap().get(&metric_id) {
+            return Ok((storage.clone().into_any_arc().downcast().unwrap(), false));
+        }
+
+        // Storage initialization may call back into this registry,
+        // so we should not hold the lock while it runs.
+        let storage = init().map_err(Self::storage_error::<T>)?;
+
+        let mut storages = self.storages.lock().unwrap();
+
+        let (storage, created) = match storages.entry(metric_id) {
+            Entry::Occupied(entry) => (entry.get().clone(), false),
+            Entry::Vacant(entry) => {
+                storage.register_fields(&self.registry)?;
+                (entry.insert(Arc::new(storage)).clone(), true)
+            }
+        };
+
+        Ok((storage.into_any_arc().downcast().unwrap(), created))
+    }
+
+    /// Return a `'static` reference to a storage of the given type with
+    /// the given labels. If such storage does not exist in this registry,
+    /// create it and register its metrics.
+    ///
+    /// This function intentionally leaks the storage: it is never dropped,
+    /// not even when this registry is dropped. Use it for process-lifetime
+    /// singletons that are created once, so that they can be stored
+    /// in statics or passed to threads without wrapping the registry
+    /// in an [`Arc`]:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let metrics: &'static Metrics = registry.instance_leaked(HashMap::new()).unwrap();
+    ///
+    /// std::thread::spawn(move || metrics.requests.inc()).join().unwrap();
+    /// assert_eq!(Metrics::instance(&registry).unwrap().requests.get(), 1);
+    ///
+    /// // The storage outlives its registry, its memory is never reclaimed.
+    /// drop(registry);
+    /// assert_eq!(metrics.requests.get(), 1);
+    /// ```
+    ///
+    /// The leaked storage is shared with [`get_or_create_storage`]: if it
+    /// already exists, it's leaked in place, and references returned
+    /// earlier stay valid. See [`instance_arc`] for a handle that doesn't
+    /// leak memory.
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    /// [`instance_arc`]: StorageRegistry::instance_arc
+    pub fn instance_leaked<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<&'static T, MetricStorageError> {
+        let storage = self.instance_arc::<T>(const_labels)?;
+
+        // Safety:
+        //
+        // The pointer is never converted back into an `Arc`, so the storage's
+        // reference count never drops to zero, and it is never dropped.
+        unsafe { Ok(&*Arc::into_raw(storage)) }
+    }
+
+    /// Return a shared handle to a storage of the given type with
+    /// the given labels. If such storage does not exist in this registry,
+    /// create it and register its metrics.
+    ///
+    /// Unlike [`get_or_create_storage`], the returned handle is not tied
+    /// to a borrow of this registry, so it can be moved into a task
+    /// or an actor. Handles share their cache with [`get_or_create_storage`]:
+    /// the same labels always give the same storage:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// # use std::sync::Arc;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// let metrics: Arc<Metrics> = registry.instance_arc(HashMap::new()).unwrap();
+    ///
+    /// let handle = metrics.clone();
+    /// std::thread::spawn(move || handle.requests.inc()).join().unwrap();
+    ///
+    /// let again = registry.instance_arc::<Metrics>(HashMap::new()).unwrap();
+    /// assert!(Arc::ptr_eq(&metrics, &again));
+    /// assert_eq!(Metrics::instance(&registry).unwrap().requests.get(), 1);
+    ///
+    /// // Dropping all handles doesn't unregister the storage.
+    /// drop(metrics);
+    /// drop(again);
+    /// assert_eq!(registry.gather().len(), 1);
+    /// ```
+    ///
+    /// The storage stays registered until it is evicted by [`remove`].
+    /// Handles returned earlier keep it alive even after that.
+    ///
+    /// Returns an error if the given labels are invalid or if storage creation
+    /// has failed. Like all methods that register storages, this one reports
+    /// which field couldn't be registered:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, MetricStorageError, StorageRegistry};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// Metrics::new(registry.registry(), "foo").unwrap();
+    ///
+    /// let mut labels = HashMap::new();
+    /// labels.insert("tenant".to_string(), "foo".to_string());
+    ///
+    /// let err = registry.instance_arc::<Metrics>(labels).map(drop).unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     MetricStorageError::Register { field: "requests", .. }
+    /// ));
+    ///
+    /// let err = registry
+    ///     .instance_owned::<Metrics>(vec!["foo".to_string()])
+    ///     .map(drop)
+    ///     .unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     MetricStorageError::Register { field: "requests", .. }
+    /// ));
+    /// ```
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    /// [`remove`]: StorageRegistry::remove
+    pub fn instance_arc<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<Arc<T>, MetricStorageError> {
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        self.get_or_insert_arc(metric_id, || {
+            T::from_const_labels_unregistered(const_labels)
+        })
+    }
+
+    /// Return a scoped handle to a storage of the given type with the given
+    /// labels. If such storage does not exist in this registry, create it
+    /// and register its metrics.
+    ///
+    /// Scoped storages are tracked separately from ones created by
+    /// [`get_or_create_storage`]. When the last handle to a scoped storage
+    /// is dropped, its metrics are unregistered, and the storage is removed
+    /// from this registry. This is useful for short-lived label values,
+    /// such as request-scoped tenants:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// -
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let mut labels = HashMap::new();
+    /// labels.insert("tenant".to_string(), "foo".to_string());
+    /// let metrics = registry.instance_scoped::<Metrics>(labels).unwrap();
+    /// metrics.requests.inc();
+    /// assert_eq!(registry.gather().len(), 1);
+    ///
+    /// drop(metrics);
+    /// assert!(registry.gather().is_empty());
+    /// ```
+    ///
+    /// Note that creating a scoped storage with the same labels as a storage
+    /// that was created by [`get_or_create_storage`] will result in
+    /// an "already registered" error.
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    pub fn instance_scoped<T: MetricStorage + Send + Sync + 'static>(
+        &self,
+        const_labels: HashMap<String, String>,
+    ) -> std::result::Result<ScopedInstance<'_, T, R>, MetricStorageError> {
+        let metric_id = Self::make_id::<T>(&const_labels).map_err(Self::storage_error::<T>)?;
+
+        let mut scoped = self.scoped.lock().unwrap();
+
+        let existing = scoped
+            .get(&metric_id)
+            .and_then(Weak::upgrade)
+            .map(|storage| storage.downcast::<T>().unwrap());
+
+        let storage = match existing {
+            Some(storage) => storage,
+            None => {
+                let storage = T::from_const_labels_unregistered(const_labels)
+                    .map_err(Self::storage_error::<T>)?;
+                storage.register_fields(&self.registry)?;
+                let storage = Arc::new(storage);
+                let erased: Arc<dyn Any + Send + Sync> = storage.clone();
+                scoped.insert(metric_id.clone(), Arc::downgrade(&erased));
+                storage
+            }
+        };
+
+        Ok(ScopedInstance {
+            registry: self,
+            id: metric_id,
+            storage: Some(storage),
+        })
+    }
+
+    /// Remove a cached storage of the given type with the given label values,
+    /// and unregister its metrics.
+    ///
+    /// Label values are listed in the same order as they appear
+    /// in the `#[metric(labels(...))]` attribute. After removal,
+    /// the next call to [`get_or_create_storage`] with the same labels
+    /// creates a new storage:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let mut registry = StorageRegistry::default();
+    ///
+    /// Metrics::instance(&registry, "foo").unwrap().requests.inc();
+    /// Metrics::instance(&registry, "bar").unwrap().requests.inc();
+    ///
+    /// registry.remove::<Metrics>(&["foo"]).unwrap();
+    /// assert_eq!(registry.gather()[0].get_metric().len(), 1);
+    ///
+    /// assert_eq!(Metrics::instance(&registry, "bar").unwrap().requests.get(), 1);
+    /// assert_eq!(Metrics::instance(&registry, "foo").unwrap().requests.get(), 0);
+    ///
+    /// // Removing a storage that doesn't exist is not an error.
+    /// registry.remove::<Metrics>(&["baz"]).unwrap();
+    /// ```
+    ///
+    /// This function takes `&mut self`, so references returned by this
+    /// registry can't outlive it, and the registry drops its copy
+    /// of the storage right away. Handles returned by [`instance_arc`]
+    /// keep the removed storage alive until they're dropped:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// # use std::sync::Arc;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let mut registry = StorageRegistry::default();
+    ///
+    /// let mut labels = HashMap::new();
+    /// labels.insert("tenant".to_string(), "foo".to_string());
+    ///
+    /// let foo = registry.instance_arc::<Metrics>(labels).unwrap();
+    /// let weak = Arc::downgrade(&foo);
+    ///
+    /// registry.remove::<Metrics>(&["foo"]).unwrap();
+    /// assert!(weak.upgrade().is_some());
+    ///
+    /// drop(foo);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    ///
+    /// Keys cached by [`instance_by_key`] that point to the removed storage
+    /// are evicted, so the next lookup by such a key creates a new storage
+    /// from the labels given with it:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let mut registry = StorageRegistry::default();
+    ///
+    /// let mut labels = HashMap::new();
+    /// labels.insert("tenant".to_string(), "foo".to_string());
+    ///
+    /// registry.instance_by_key::<Metrics, u64>(1, labels.clone()).unwrap();
+    /// registry.remove::<Metrics>(&["foo"]).unwrap();
+    ///
+    /// // The key is no longer cached, so the labels are checked again.
+    /// assert!(registry.instance_by_key::<Metrics, u64>(1, HashMap::new()).is_err());
+    ///
+    /// registry.instance_by_key::<Metrics, u64>(1, labels).unwrap().requests.inc();
+    /// let families = registry.gather();
+    /// assert_eq!(families[0].get_metric()[0].get_label()[0].get_value(), "foo");
+    /// ```
+    ///
+    /// Returns an error if the number of label values is wrong,
+    /// or if the storage's metrics can't be unregistered, for example,
+    /// after [`unregister_all`]. In the latter case, the storage
+    /// is still removed.
+    ///
+    /// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
+    /// [`instance_arc`]: StorageRegistry::instance_arc
+    /// [`instance_by_key`]: StorageRegistry::instance_by_key
+    /// [`unregister_all`]: StorageRegistry::unregister_all
+    pub fn remove<T: MetricStorage + Send + Sync + 'static>(
+        &mut self,
+        labels: &[&str],
+    ) -> Result<()> {
+        let const_labels = T::const_labels()
+            .iter()
+            .zip(labels)
+            .map(|(label, value)| (label.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        if const_labels.len() != labels.len() {
+            return Err(Error::Msg(format!(
+                "invalid number of const labels: expected {}, got {}",
+                T::const_labels().len(),
+                labels.len()
+            )));
+        }
+        let metric_id = Self::make_id::<T>(&const_labels)?;
+
+        for ((type_id, _), ids) in self.keys.lock().unwrap().iter_mut() {
+            if *type_id == TypeId::of::<T>() {
+                ids.evict(&metric_id);
+            }
+        }
+
+        let storage = match self.storages.lock().unwrap().remove(&metric_id) {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+
+        storage.unregister(&self.registry)
+    }
+
+    fn storage_error<T: MetricStorage>(source: Error) -> MetricStorageError {
+        MetricStorageError::Storage {
+            storage: std::any::type_name::<T>(),
+            source,
+        }
+    }
+
+    fn make_id<T: MetricStorage + Send + Sync + 'static>(
+        const_labels: &HashMap<String, String>,
+    ) -> Result<StorageId> {
+        let labels_spec = T::const_labels();
+
+        if labels_spec.len() != const_labels.len() {
+            return Err(Error::Msg(format!(
+                "invalid number of const labels: expected {}, got {}",
+                labels_spec.len(),
+                const_labels.len()
+            )));
+        }
+
+        let mut values = String::new();
+
+        for &label in T::const_labels() {
+            if let Some(value) = const_labels.get(label) {
+                values.push_str(value);
+                values.push('\0');
+            } else {
+                return Err(Error::Msg(format!("label {:?} is missing", label)));
+            }
+        }
+
+        Ok((TypeId::of::<T>(), values))
+    }
+}
+
+impl Default for StorageRegistry {
+    fn default() -> Self {
+        Self::new(Registry::new())
+    }
+}
+
+impl<R> Debug for StorageRegistry<R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StorageRegistry")
+    }
+}
+
+/// A handle to a storage created by [`StorageRegistry::instance_scoped`].
+///
+/// When the last handle to a storage is dropped, metrics of the storage
+/// are unregistered from the registry. This holds even if handles
+/// are dropped concurrently from different threads:
+///
+/// ```
+/// # use prometheus_metric_storage::{StorageRegistry, MetricStorage};
+/// # use std::collections::HashMap;
+/// #[derive(MetricStorage)]
+/// #[metric(labels("tenant"))]
+/// struct Metrics {
+///     /// -
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::default();
+///
+/// let mut labels = HashMap::new();
+/// labels.insert("tenant".to_string(), "foo".to_string());
+///
+/// for _ in 0..100 {
+///     let metrics = registry.instance_scoped::<Metrics>(labels.clone()).unwrap();
+///     let barrier = std::sync::Barrier::new(2);
+///     std::thread::scope(|s| {
+///         for handle in [metrics.clone(), metrics] {
+///             let barrier = &barrier;
+///             s.spawn(move || {
+///                 barrier.wait();
+///                 drop(handle);
+///             });
+///         }
+///     });
+///     assert!(registry.gather().is_empty());
+/// }
+/// ```
+pub struct ScopedInstance<'a, T, R = Registry>
+where
+    T: MetricStorage + Send + Sync + 'static,
+    R: MetricRegistry,
+{
+    registry: &'a StorageRegistry<R>,
+    id: StorageId,
+    storage: Option<Arc<T>>,
+}
+
+impl<'a, T, R> Deref for ScopedInstance<'a, T, R>
+where
+    T: MetricStorage + Send + Sync + 'static,
+    R: MetricRegistry,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.storage.as_ref().unwrap()
+    }
+}
+
+impl<'a, T, R> Clone for ScopedInstance<'a, T, R>
+where
+    T: MetricStorage + Send + Sync + 'static,
+    R: MetricRegistry,
+{
+    fn clone(&self) -> Self {
+        Self {
+            registry: self.registry,
+            id: self.id.clone(),
+            storage: self.storage.clone(),
+        }
+    }
+}
+
+impl<'a, T, R> Drop for ScopedInstance<'a, T, R>
+where
+    T: MetricStorage + Send + Sync + 'static,
+    R: MetricRegistry,
+{
+    fn drop(&mut self) {
+        let storage = self.storage.take().unwrap();
+
+        // Handles are released while holding this lock, so that
+        // when two last handles are dropped concurrently, the one
+        // that drops second is guaranteed to see itself as the only one.
+        // Storages are only revived from the weak reference while
+        // holding this lock as well.
+        let mut scoped = self.registry.scoped.lock().unwrap();
+        match Arc::try_unwrap(storage) {
+            Ok(storage) => {
+                scoped.remove(&self.id);
+                storage.unregister(&self.registry.registry).ok();
+            }
+            Err(storage) => drop(storage),
+        }
+    }
+}
+
+impl<'a, T, R> Debug for ScopedInstance<'a, T, R>
+where
+    T: MetricStorage + Send + Sync + 'static,
+    R: MetricRegistry,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ScopedInstance")
+    }
+}
+
+/// Transformation of gathered metrics for [`StorageRegistry::gather_with`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transform {
+    /// Drop metric family with the given name.
+    Drop(String),
+
+    /// Rename metric family.
+    Rename {
+        /// Current name of the metric family.
+        from: String,
+        /// New name of the metric family.
+        to: String,
+    },
+
+    /// Add a label to every metric of every family. If some metric already
+    /// has a label with this name, its value is replaced.
+    AddLabel {
+        /// Name of the label.
+        name: String,
+        /// Value of the label.
+        value: String,
+    },
+}
+
+impl Transform {
+    fn apply(&self, families: &mut Vec<MetricFamily>) {
+        match self {
+            Transform::Drop(name) => families.retain(|family| family.get_name() != name),
+            Transform::Rename { from, to } => {
+                for family in families.iter_mut() {
+                    if family.get_name() == from {
+                        family.set_name(to.clone());
+                    }
+                }
+            }
+            Transform::AddLabel { name, value } => {
+                for family in families.iter_mut() {
+                    for metric in family.mut_metric().iter_mut() {
+                        let mut labels: Vec<_> = metric
+                            .take_label()
+                            .into_iter()
+                            .filter(|label| label.get_name() != name)
+                            .collect();
+                        let mut label = prometheus::proto::LabelPair::default();
+                        label.set_name(name.clone());
+                        label.set_value(value.clone());
+                        labels.push(label);
+                        labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                        // Protobuf models use `RepeatedField` instead of `Vec`.
+                        #[allow(clippy::useless_conversion)]
+                        metric.set_label(labels.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Configuration of the registry underlying a [`StorageRegistry`].
+///
+/// See [`StorageRegistry::config`] for more info.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegistryConfig {
+    /// Prefix that's prepended to names of all metrics in the registry.
+    pub prefix: Option<String>,
+
+    /// Const labels that are added to all metrics in the registry.
+    pub const_labels: Option<BTreeMap<String, String>>,
+}
+
+/// Get the default storage registry that uses [`prometheus::default_registry`].
+pub fn default_storage_registry() -> &'static StorageRegistry {
+    lazy_static::lazy_static! {
+        static ref REGISTRY: StorageRegistry =
+            StorageRegistry::new(prometheus::default_registry().clone());
+    }
+
+    &REGISTRY as &StorageRegistry
+}
+
+/// Create a `*_build_info` gauge, set it to `1`, and register it
+/// in the given registry.
+///
+/// Given labels are added to the gauge as const labels. This is a
+/// conventional way of exposing version, commit hash, and other build
+/// information. See also the [`build_info_metric`] macro.
+pub fn build_info(
+    registry: &Registry,
+    name: &str,
+    const_labels: HashMap<String, String>,
+) -> Result<prometheus::IntGauge> {
+    let opts = Opts::new(name, "Build information.").const_labels(const_labels);
+    let gauge = prometheus::IntGauge::with_opts(opts)?;
+    gauge.set(1);
+    registry.register(Box::new(gauge.clone()))?;
+    Ok(gauge)
+}
+
+/// Create a `*_build_info` gauge with the given const labels, set it to `1`,
+/// and register it in the given registry.
+///
+/// This is a shortcut for the [`build_info`] function:
+///
+/// ```
+/// # use prometheus_metric_storage::build_info_metric;
+/// let registry = prometheus::Registry::default();
+///
+/// build_info_metric!(
+///     &registry,
+///     "myapp_build_info",
+///     version = env!("CARGO_PKG_VERSION"),
+///     commit = "0a497ff",
+/// ).unwrap();
+///
+/// let families = registry.gather();
+/// assert_eq!(families[0].get_name(), "myapp_build_info");
+///
+/// let metric = &families[0].get_metric()[0];
+/// assert_eq!(metric.get_gauge().get_value(), 1.0);
+/// assert_eq!(metric.get_label()[0].get_name(), "commit");
+/// assert_eq!(metric.get_label()[0].get_value(), "0a497ff");
+/// assert_eq!(metric.get_label()[1].get_name(), "version");
+/// ```
+#[macro_export]
+macro_rules! build_info_metric {
+    ($registry:expr, $name:expr $(, $label:ident = $value:expr)* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut const_labels = ::std::collections::HashMap::new();
+        $(const_labels.insert(stringify!($label).to_string(), ($value).to_string());)*
+        $crate::build_info($registry, $name, const_labels)
+    }};
+}
+
+/// A registry that metrics can be registered in.
+///
+/// This trait is implemented for [`Registry`]. Implement it for registries
+/// that wrap one, for example, to add authentication or tenant scoping,
+/// to use them with generated constructors and [`StorageRegistry`].
+///
+/// See [custom registries] for an example.
+///
+/// [custom registries]: StorageRegistry#custom-registries
+pub trait MetricRegistry {
+    /// Register a collector.
+    ///
+    /// See [`Registry::register`] for more info.
+    fn register(&self, c: Box<dyn Collector>) -> Result<()>;
+
+    /// Unregister a collector.
+    ///
+    /// See [`Registry::unregister`] for more info.
+    fn unregister(&self, c: Box<dyn Collector>) -> Result<()>;
+
+    /// Gather all metrics from this registry.
+    ///
+    /// See [`Registry::gather`] for more info.
+    fn gather(&self) -> Vec<MetricFamily>;
+}
+
+impl MetricRegistry for Registry {
+    fn register(&self, c: Box<dyn Collector>) -> Result<()> {
+        Registry::register(self, c)
+    }
+
+    fn unregister(&self, c: Box<dyn Collector>) -> Result<()> {
+        Registry::unregister(self, c)
+    }
+
+    fn gather(&self) -> Vec<MetricFamily> {
+        Registry::gather(self)
+    }
+}
+
+/// Common interface for metric storages.
+///
+/// This trait should be derived with the `#[derive(MetricStorage)]` macro.
+pub trait MetricStorage: Sized {
+    /// Get array of const labels used in this storage.
+    ///
+    /// Labels are listed in the same order as they appear
+    /// in the `#[metric(labels(...))]` attribute.
+    ///
+    /// See [crate-level] documentation for more info.
+    ///
+    /// [crate-level]: crate#configuring-metrics
+    fn const_labels() -> &'static [&'static str];
+
+    /// Get array of names of metrics defined in this storage.
+    ///
+    /// Names respect `name` and `rename` options, but don't include
+    /// namespace and subsystem prefixes. This is useful for cross-checking
+    /// dashboards against exported metrics:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// #[metric(subsystem = "http", labels("service"))]
+    /// struct CombinedMetrics {
+    ///     /// Number of requests.
+    ///     #[metric(labels("url"))]
+    ///     requests_total: prometheus::IntCounterVec,
+    ///
+    ///     /// Processing time of each request in seconds.
+    ///     #[metric(name = "duration_seconds", buckets(0.1, 0.5, 1))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    ///
+    /// assert_eq!(
+    ///     CombinedMetrics::metric_names(),
+    ///     &["requests_total", "duration_seconds"],
+    /// );
+    /// ```
+    ///
+    /// Metrics are listed in the same order as fields that define them.
+    /// For enums, names from all variants are listed once. With `name_fn`,
+    /// these are the names that are passed to it, and for [`PerVariant`]
+    /// fields, these are names before adding a suffix. Fields with `flatten`
+    /// and `external` are not listed because their names are not known
+    /// at compile time.
+    fn metric_names() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Get names of metrics defined in this storage, along with
+    /// their field-level labels.
+    ///
+    /// Metrics are listed in the same order as in [`metric_names`]. Labels
+    /// are listed in the same order as they appear in the field-level
+    /// `#[metric(labels(...))]` attribute, struct-level labels
+    /// are not included. This allows checking arity of label values
+    /// in tests:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct VecMetrics {
+    ///     /// Number of requests.
+    ///     #[metric(labels("method", "code"))]
+    ///     requests_total: prometheus::IntCounterVec,
+    /// }
+    ///
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("service"))]
+    /// struct CombinedMetrics {
+    ///     /// Number of requests.
+    ///     #[metric(labels("url"))]
+    ///     requests_total: prometheus::IntCounterVec,
+    ///
+    ///     /// Processing time of each request in seconds.
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    ///
+    /// assert_eq!(
+    ///     VecMetrics::field_labels(),
+    ///     &[("requests_total", &["method", "code"][..])],
+    /// );
+    /// assert_eq!(
+    ///     CombinedMetrics::field_labels(),
+    ///     &[
+    ///         ("requests_total", &["url"][..]),
+    ///         ("requests_duration_seconds", &[][..]),
+    ///     ],
+    /// );
+    /// ```
+    ///
+    /// [`metric_names`]: MetricStorage::metric_names
+    fn field_labels() -> &'static [(&'static str, &'static [&'static str])] {
+        &[]
+    }
+
+    /// Create a new instance of this storage and register all of its metrics
+    /// in the given registry.
+    ///
+    /// For any given metric storage, this function should not be called twice
+    /// with the same values for const labels. Otherwise, the registry will
+    /// complain about a metric being registered twice.
+    ///
+    /// If the given const labels do not match the ones declared
+    /// in the `#[metric(labels(...))]` attribute of the struct
+    /// that's being created, this function will return an error.
+    fn from_const_labels<R: MetricRegistry + ?Sized>(
+        registry: &R,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Self> {
+        let storage = Self::from_const_labels_unregistered(const_labels)?;
+        storage.register(registry)?;
+        Ok(storage)
+    }
+
+    /// Create a new instance of this storage and initialize all of its metrics.
+    ///
+    /// This function does not register the created metrics in any storage.
+    ///
+    /// If the given const labels do not match the ones declared
+    /// in the `metric(labels(...))` attribute of the struct
+    /// that's being created, this function will return an error.
+    fn from_const_labels_unregistered(const_labels: HashMap<String, String>) -> Result<Self>;
+
+    /// Register all metrics from this storage in the given registry.
+    fn register<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()>;
+
+    /// Same as [`register`], but reports which field of this storage
+    /// has failed to register.
+    ///
+    /// The derive macro implements this function. The default
+    /// implementation calls [`register`] and doesn't report fields.
+    ///
+    /// [`register`]: MetricStorage::register
+    fn register_fields<R: MetricRegistry + ?Sized>(
+        &self,
+        registry: &R,
+    ) -> std::result::Result<(), MetricStorageError> {
+        self.register(registry)
+            .map_err(|source| MetricStorageError::Storage {
+                storage: std::any::type_name::<Self>(),
+                source,
+            })
+    }
+
+    /// Unregister all metrics of this storage from the given registry.
+    ///
+    /// This is useful for subsystems that are created and torn down
+    /// repeatedly, so that stale metric families don't stay exported:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::default();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    /// assert_eq!(registry.gather().len(), 1);
+    ///
+    /// metrics.unregister(&registry).unwrap();
+    /// assert!(registry.gather().is_empty());
+    /// ```
+    ///
+    /// Returns an error from the registry if some metric is not registered,
+    /// for example, if this function is called twice:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// # #[derive(MetricStorage)]
+    /// # struct Metrics {
+    /// #     /// Number of requests.
+    /// #     requests: prometheus::IntCounter,
+    /// # }
+    /// # let registry = prometheus::Registry::default();
+    /// # let metrics = Metrics::new(&registry).unwrap();
+    /// metrics.unregister(&registry).unwrap();
+    /// assert!(matches!(metrics.unregister(&registry), Err(prometheus::Error::Msg(_))));
+    /// ```
+    fn unregister<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()>;
+
+    /// Get descriptors of all metrics in this storage, along with their kinds.
+    ///
+    /// Kind is `None` for custom collectors that don't report it
+    /// via [`MetricInit::kind`].
+    fn descs(&self) -> Vec<(Option<MetricKind>, Desc)>;
+
+    /// Get bucket bounds of all histograms in this storage,
+    /// along with full names of these histograms.
+    ///
+    /// Histograms without configured buckets report
+    /// [`prometheus::DEFAULT_BUCKETS`]. Custom histograms without configured
+    /// buckets are not listed because their buckets are unknown.
+    fn histogram_buckets(&self) -> Vec<(String, Vec<f64>)> {
+        Vec::new()
+    }
+
+    /// Get descriptors of metrics in the field with the given name.
+    ///
+    /// Fields of tuple structs are named by their index. Returns `None`
+    /// if there's no such field, or if the field is not a metric.
+    fn field_descs(&self, field: &str) -> Option<Vec<Desc>> {
+        let _ = field;
+        None
+    }
+
+    /// Collect current values of all metrics in this storage,
+    /// without going through a registry.
+    ///
+    /// Families are listed in the same order as fields that hold them.
+    /// This is useful for debug endpoints and tests:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    ///     /// Processing time of each request in seconds.
+    ///     #[metric(buckets(0.1, 1))]
+    ///     duration_seconds: prometheus::Histogram,
+    /// }
+    ///
+    /// let metrics = Metrics::new_unregistered().unwrap();
+    /// metrics.requests.inc();
+    /// metrics.duration_seconds.observe(0.5);
+    ///
+    /// let families = metrics.snapshot();
+    /// assert_eq!(families.len(), 2);
+    /// assert_eq!(families[0].get_name(), "requests");
+    /// assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 1.0);
+    /// assert_eq!(families[1].get_name(), "duration_seconds");
+    /// assert_eq!(families[1].get_metric()[0].get_histogram().get_sample_count(), 1);
+    /// ```
+    ///
+    /// The default implementation registers this storage in a new registry,
+    /// and gathers it.
+    fn snapshot(&self) -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        match self.register(&registry) {
+            Ok(()) => registry.gather(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Gather metric families of the given fields from the registry.
+    ///
+    /// Only series that belong to this storage are returned, series
+    /// of other storages with different const labels are filtered out.
+    /// This is useful for debug endpoints and tests:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("tenant"))]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    ///     /// Number of errors.
+    ///     errors: prometheus::IntCounter,
+    ///     /// Number of requests in progress.
+    ///     inflight: prometheus::IntGauge,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    /// let foo = Metrics::new(&registry, "foo").unwrap();
+    /// let bar = Metrics::new(&registry, "bar").unwrap();
+    /// foo.errors.inc();
+    ///
+    /// let families = foo.gather_fields(&registry, &["errors"]).unwrap();
+    /// assert_eq!(families.len(), 1);
+    /// assert_eq!(families[0].get_name(), "errors");
+    /// assert_eq!(families[0].get_metric().len(), 1);
+    /// assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 1.0);
+    ///
+    /// assert!(foo.gather_fields(&registry, &["warnings"]).is_err());
+    /// ```
+    ///
+    /// Returns an error if some of the given names is not a metric field
+    /// of this storage.
+    fn gather_fields<R: MetricRegistry + ?Sized>(
+        &self,
+        registry: &R,
+        names: &[&str],
+    ) -> Result<Vec<MetricFamily>> {
+        let mut descs = Vec::new();
+        for name in names {
+            match self.field_descs(name) {
+                Some(field_descs) => descs.extend(field_descs),
+                None => {
+                    return Err(Error::Msg(format!(
+                        "metric storage {} has no metric field {:?}",
+                        std::any::type_name::<Self>(),
+                        name
+                    )))
+                }
+            }
+        }
+
+        let belongs_to = |family: &MetricFamily, metric: &prometheus::proto::Metric| {
+            descs.iter().any(|desc| {
+                desc.fq_name == family.get_name()
+                    && desc.const_label_pairs.iter().all(|pair| {
+                        metric.get_label().iter().any(|label| {
+                            label.get_name() == pair.get_name()
+                                && label.get_value() == pair.get_value()
+                        })
+                    })
+            })
+        };
 
-#![deny(missing_docs)]
+        let mut families = registry.gather();
+        for family in &mut families {
+            let metrics = family.take_metric().into_iter();
+            let metrics = metrics
+                .filter(|metric| belongs_to(family, metric))
+                .collect();
+            family.set_metric(metrics);
+        }
+        families.retain(|family| !family.get_metric().is_empty());
 
-#[cfg(doctest)]
-mod test_readme {
-    #[doc = include_str!("../../README.md")]
-    mod test_readme_impl {}
+        Ok(families)
+    }
+
+    /// Get metadata of all metrics in this storage as a JSON value.
+    ///
+    /// The metadata describes names, types, help messages, labels
+    /// and histogram buckets, but not metric values. It's meant
+    /// for generating dashboards:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// #[metric(labels("service"))]
+    /// struct CombinedMetrics {
+    ///     /// Number of requests.
+    ///     #[metric(labels("url"))]
+    ///     requests_total: prometheus::IntCounterVec,
+    ///
+    ///     /// Processing time of each request in seconds.
+    ///     #[metric(buckets(0.1, 0.5, 1))]
+    ///     requests_duration_seconds: prometheus::Histogram,
+    /// }
+    ///
+    /// let metrics = CombinedMetrics::new_unregistered("api").unwrap();
+    /// assert_eq!(
+    ///     metrics.metadata_json(),
+    ///     serde_json::json!({
+    ///         "metrics": [
+    ///             {
+    ///                 "name": "requests_total",
+    ///                 "type": "counter",
+    ///                 "help": "Number of requests.",
+    ///                 "labels": ["url"],
+    ///                 "const_labels": ["service"],
+    ///             },
+    ///             {
+    ///                 "name": "requests_duration_seconds",
+    ///                 "type": "histogram",
+    ///                 "help": "Processing time of each request in seconds.",
+    ///                 "labels": [],
+    ///                 "const_labels": ["service"],
+    ///                 "buckets": [0.1, 0.5, 1.0],
+    ///             },
+    ///         ]
+    ///     }),
+    /// );
+    /// ```
+    ///
+    /// This function is only available with the `serde` feature.
+    #[cfg(feature = "serde")]
+    fn metadata_json(&self) -> serde_json::Value {
+        let buckets: HashMap<_, _> = self.histogram_buckets().into_iter().collect();
+
+        let metrics: Vec<_> = self
+            .descs()
+            .into_iter()
+            .map(|(kind, desc)| {
+                let const_labels: Vec<_> = desc
+                    .const_label_pairs
+                    .iter()
+                    .map(|label| label.get_name())
+                    .collect();
+                let mut metric = serde_json::json!({
+                    "name": desc.fq_name,
+                    "type": kind.map(MetricKind::type_name),
+                    "help": desc.help,
+                    "labels": desc.variable_labels,
+                    "const_labels": const_labels,
+                });
+                if let Some(buckets) = buckets.get(&desc.fq_name) {
+                    metric["buckets"] = buckets.clone().into();
+                }
+                metric
+            })
+            .collect();
+
+        serde_json::json!({ "metrics": metrics })
+    }
+
+    /// Get full names and kinds of all metrics in this storage.
+    ///
+    /// This is useful for tools that need to know metric types, such as
+    /// exporters and validators. Metrics whose kind is unknown,
+    /// i.e. custom collectors that don't override [`MetricInit::kind`],
+    /// are not listed.
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, MetricKind};
+    /// #[derive(MetricStorage)]
+    /// #[metric(subsystem = "http")]
+    /// struct AllTypesMetrics {
+    ///     /// -
+    ///     counter: prometheus::IntCounter,
+    ///     /// -
+    ///     gauge: prometheus::Gauge,
+    ///     /// -
+    ///     histogram: prometheus::Histogram,
+    ///     /// -
+    ///     #[metric(labels("url"))]
+    ///     counter_vec: prometheus::CounterVec,
+    ///     /// -
+    ///     #[metric(labels("url"))]
+    ///     gauge_vec: prometheus::IntGaugeVec,
+    ///     /// -
+    ///     #[metric(labels("url"))]
+    ///     histogram_vec: prometheus::HistogramVec,
+    /// }
+    ///
+    /// let metrics = AllTypesMetrics::new_unregistered().unwrap();
+    /// assert_eq!(
+    ///     metrics.metric_types(),
+    ///     vec![
+    ///         ("http_counter".to_string(), MetricKind::Counter),
+    ///         ("http_gauge".to_string(), MetricKind::Gauge),
+    ///         ("http_histogram".to_string(), MetricKind::Histogram),
+    ///         ("http_counter_vec".to_string(), MetricKind::CounterVec),
+    ///         ("http_gauge_vec".to_string(), MetricKind::GaugeVec),
+    ///         ("http_histogram_vec".to_string(), MetricKind::HistogramVec),
+    ///     ],
+    /// );
+    /// ```
+    fn metric_types(&self) -> Vec<(String, MetricKind)> {
+        self.descs()
+            .into_iter()
+            .filter_map(|(kind, desc)| Some((desc.fq_name, kind?)))
+            .collect()
+    }
+
+    /// Check that all metrics from this storage follow prometheus naming
+    /// conventions, then register them in the given registry.
+    ///
+    /// Regular [`register`] only checks what's required by the prometheus
+    /// client. This function additionally requires that counter names
+    /// end with `_total`, that names of other metrics don't, and that
+    /// histograms don't use the `le` label. This is useful when metric
+    /// names are built at runtime:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, DynamicStorage, MetricDescriptor, MetricKind};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    /// let metrics = Metrics::new_unregistered().unwrap();
+    /// assert!(metrics.register_strict(&registry).is_err());
+    /// assert!(metrics.register(&registry).is_ok());
+    ///
+    /// let name = format!("{}_total", "requests");
+    /// let storage = DynamicStorage::new_unregistered(vec![
+    ///     MetricDescriptor::new(name, MetricKind::Counter, "Number of requests."),
+    /// ]).unwrap();
+    /// assert!(storage.register_strict(&registry).is_ok());
+    /// ```
+    ///
+    /// [`register`]: MetricStorage::register
+    fn register_strict<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()> {
+        for (kind, desc) in self.descs() {
+            check_naming_conventions(kind, &desc)?;
+        }
+        self.register(registry)
+    }
+
+    /// Lock the given mutex, register all metrics from this storage
+    /// in the guarded registry, and release the lock.
+    ///
+    /// This is useful for applications that share their registry
+    /// as a `Mutex<Registry>`; the lock is only held while registering:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// # use std::sync::{Arc, Mutex};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = Arc::new(Mutex::new(prometheus::Registry::new()));
+    ///
+    /// let metrics = Metrics::new_unregistered().unwrap();
+    /// metrics.register_locked(&registry).unwrap();
+    ///
+    /// assert_eq!(registry.lock().unwrap().gather().len(), 1);
+    /// ```
+    ///
+    /// A poisoned mutex is not an error: registering metrics can't
+    /// break invariants of a registry.
+    fn register_locked(&self, registry: &Mutex<Registry>) -> Result<()> {
+        let registry = registry.lock().unwrap_or_else(PoisonError::into_inner);
+        self.register(&*registry)
+    }
+
+    /// Register all metrics from this storage in the given registry,
+    /// unless they're registered there already.
+    ///
+    /// This is useful when several tests share a registry, and each of them
+    /// creates its own copy of a storage. The first call registers metrics,
+    /// subsequent calls are no-ops:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    ///
+    /// Metrics::new_unregistered().unwrap().register_once(&registry).unwrap();
+    /// Metrics::new_unregistered().unwrap().register_once(&registry).unwrap();
+    ///
+    /// assert_eq!(registry.gather().len(), 1);
+    /// ```
+    ///
+    /// A storage is considered registered if the registry already contains
+    /// a metric with the same name and const label values as any metric
+    /// of this storage. Note that, in this case, metrics of this storage
+    /// are not exported, so values recorded in them are not visible;
+    /// use [`StorageRegistry`] to share a single copy of a storage instead.
+    ///
+    /// Several threads can call this function at once, exactly one
+    /// of them registers its storage. Here, the registry pauses after
+    /// unregistering a collector to give other threads a chance to interfere:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricRegistry, MetricStorage};
+    /// # use prometheus::core::Collector;
+    /// # use prometheus::proto::MetricFamily;
+    /// # use std::sync::{Arc, Barrier};
+    /// # use std::time::Duration;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// #[derive(Clone)]
+    /// struct SlowRegistry(prometheus::Registry);
+    ///
+    /// impl MetricRegistry for SlowRegistry {
+    ///     fn register(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+    ///         self.0.register(c)
+    ///     }
+    ///
+    ///     fn unregister(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+    ///         let result = self.0.unregister(c);
+    ///         std::thread::sleep(Duration::from_millis(10));
+    ///         result
+    ///     }
+    ///
+    ///     fn gather(&self) -> Vec<MetricFamily> {
+    ///         self.0.gather()
+    ///     }
+    /// }
+    ///
+    /// let registry = SlowRegistry(prometheus::Registry::new());
+    /// let barrier = Arc::new(Barrier::new(4));
+    ///
+    /// let threads: Vec<_> = (0..4)
+    ///     .map(|_| {
+    ///         let registry = registry.clone();
+    ///         let barrier = barrier.clone();
+    ///         std::thread::spawn(move || {
+    ///             let metrics = Metrics::new_unregistered().unwrap();
+    ///             barrier.wait();
+    ///             metrics.register_once(&registry)
+    ///         })
+    ///     })
+    ///     .collect();
+    ///
+    /// for thread in threads {
+    ///     thread.join().unwrap().unwrap();
+    /// }
+    ///
+    /// assert_eq!(registry.gather().len(), 1);
+    /// ```
+    fn register_once<R: MetricRegistry + ?Sized>(&self, registry: &R) -> Result<()> {
+        let probe = Probe(self.descs().into_iter().map(|(_, desc)| desc).collect());
+        if probe.0.is_empty() {
+            return Ok(());
+        }
+
+        // Checking and registering are two separate steps, so we lock
+        // mutexes that are picked based on the storage's descriptors.
+        // Copies of the same storage share descriptors, so no other thread
+        // can register them in between. Mutexes are locked in the same
+        // order by all threads to avoid deadlocks.
+        lazy_static::lazy_static! {
+            static ref LOCKS: Vec<Mutex<()>> = (0..64).map(|_| Mutex::new(())).collect();
+        }
+
+        let mut stripes: Vec<_> = probe
+            .0
+            .iter()
+            .map(|desc| (desc.id % LOCKS.len() as u64) as usize)
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let _guards: Vec<_> = stripes
+            .into_iter()
+            .map(|stripe| LOCKS[stripe].lock().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+
+        // Registering a collector with the same descriptors is the only way
+        // to check whether they're already known to the registry.
+        match registry.register(Box::new(probe.clone())) {
+            Err(Error::AlreadyReg) => return Ok(()),
+            Err(err) => return Err(err),
+            Ok(()) => registry.unregister(Box::new(probe))?,
+        }
+
+        self.register(registry)
+    }
 }
 
-use prometheus::core::Collector;
-use prometheus::proto::MetricFamily;
-use std::any::{Any, TypeId};
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
-use std::pin::Pin;
-use std::sync::Mutex;
+/// A collector that has descriptors, but no metrics.
+///
+/// Used by [`MetricStorage::register_once`] to check whether descriptors
+/// of a storage are registered.
+#[derive(Clone)]
+struct Probe(Vec<Desc>);
 
-#[doc(hidden)]
-pub use prometheus::{Error, Opts, Registry, Result};
+impl Collector for Probe {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.iter().collect()
+    }
 
-/// Generates implementation for [`MetricStorage`] and three additional
-/// methods: `new`, `new_unregistered`, `instance`.
+    fn collect(&self) -> Vec<MetricFamily> {
+        Vec::new()
+    }
+}
+
+/// Error returned by generated methods that create and register storages,
+/// such as `new`, `instance` and `rebind`.
 ///
-/// See the [crate-level] documentation for more info.
+/// Unlike [`prometheus::Error`], it names the storage and, if possible,
+/// the field that has failed:
 ///
-/// [crate-level]: crate#generated-code-api
-pub use prometheus_metric_storage_derive::MetricStorage;
-
-/// Identifier of a single storage in [`StorageRegistry`].
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, MetricStorageError};
+/// #[derive(MetricStorage)]
+/// struct DoubleRegMetrics {
+///     /// Number of requests.
+///     counter: prometheus::IntCounter,
+/// }
 ///
-/// Storage ID consists of a type ID and static label values
-/// concatenated into a single string with zero bytes as a delimiter.
-type StorageId = (TypeId, String);
-
-/// Wrapper for prometheus' [`Registry`] that keeps track of registered
-/// storages, and helps to avoid "already registered" errors without
-/// having to use lazy statics.
+/// let registry = prometheus::Registry::new();
+/// DoubleRegMetrics::new(&registry).unwrap();
 ///
-/// See the [crate-level] documentation for more info.
+/// let err = DoubleRegMetrics::new(&registry).map(drop).unwrap_err();
+/// assert!(matches!(
+///     err,
+///     MetricStorageError::Register { field: "counter", .. }
+/// ));
+/// assert!(err.to_string().contains("DoubleRegMetrics::counter"));
+/// assert!(matches!(err.into_prometheus_error(), prometheus::Error::AlreadyReg));
+/// ```
 ///
-/// # Limitations
+/// It converts into the underlying [`prometheus::Error`], so the `?`
+/// operator still works in functions that return [`prometheus::Result`].
+/// Methods that don't register anything, such as `new_unregistered`,
+/// return [`prometheus::Error`] as before.
 ///
-/// Calls to [`get_storage`] and [`get_or_create_storage`] involve
-/// concatenating values of all const labels into a single string,
-/// and looking up this string in a hashtable. Make sure that these
-/// calls are out of your hot path.
+/// Conversely, [`prometheus::Error`] converts into
+/// [`MetricStorageError::Prometheus`], so that both kinds of errors
+/// can be propagated from the same function:
 ///
-/// [crate-level]: crate#metric-storage-registry
-/// [`get_storage`]: StorageRegistry::get_storage
-/// [`get_or_create_storage`]: StorageRegistry::get_or_create_storage
-pub struct StorageRegistry {
-    /// The underlying metrics registry.
-    registry: Registry,
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, MetricStorageError};
+/// # use std::error::Error;
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// fn setup(registry: &prometheus::Registry) -> Result<Metrics, MetricStorageError> {
+///     let metrics = Metrics::new_unregistered()?;
+///     metrics.register_fields(registry)?;
+///     Ok(metrics)
+/// }
+///
+/// let message = "the message".to_string();
+/// let source = prometheus::Error::Msg(message.clone());
+/// let expected = source.to_string();
+///
+/// let err = MetricStorageError::from(source);
+/// assert_eq!(err.to_string(), expected);
+/// assert_eq!(err.source().unwrap().to_string(), expected);
+///
+/// let err = prometheus::Error::from(err);
+/// assert!(matches!(err, prometheus::Error::Msg(m) if m == message));
+/// # setup(&prometheus::Registry::new()).unwrap();
+/// ```
+#[derive(Debug)]
+pub enum MetricStorageError {
+    /// A metric failed to register.
+    Register {
+        /// Name of the storage type.
+        storage: &'static str,
+        /// Name of the field that holds the metric.
+        field: &'static str,
+        /// The underlying error.
+        source: Error,
+    },
+    /// A storage failed to initialize or register as a whole,
+    /// for example, because its const labels are invalid.
+    Storage {
+        /// Name of the storage type.
+        storage: &'static str,
+        /// The underlying error.
+        source: Error,
+    },
+    /// An error without storage context, converted from [`prometheus::Error`].
+    Prometheus(Error),
+    /// Label values for a storage could not be computed, see
+    /// [`StorageRegistry::try_instance_with`].
+    Labels(Box<dyn std::error::Error + Send + Sync>),
+}
 
-    /// Saved registered storages.
+impl MetricStorageError {
+    /// Get the underlying prometheus error.
     ///
-    /// # Safety
+    /// Returns `None` for [`MetricStorageError::Labels`], which
+    /// doesn't come from prometheus.
+    pub fn prometheus_error(&self) -> Option<&Error> {
+        match self {
+            MetricStorageError::Register { source, .. } => Some(source),
+            MetricStorageError::Storage { source, .. } => Some(source),
+            MetricStorageError::Prometheus(source) => Some(source),
+            MetricStorageError::Labels(_) => None,
+        }
+    }
+
+    /// Convert this error into the underlying prometheus error,
+    /// dropping the context.
     ///
-    /// Storages in this hashmap must not be removed or replaced.
-    /// They must only be dropped when this registry is dropped.
-    storages: Mutex<HashMap<StorageId, Pin<Box<dyn Any + Send + Sync>>>>,
+    /// [`MetricStorageError::Labels`] is converted into [`Error::Msg`].
+    pub fn into_prometheus_error(self) -> Error {
+        match self {
+            MetricStorageError::Register { source, .. } => source,
+            MetricStorageError::Storage { source, .. } => source,
+            MetricStorageError::Prometheus(source) => source,
+            MetricStorageError::Labels(source) => Error::Msg(source.to_string()),
+        }
+    }
 }
 
-impl StorageRegistry {
-    /// Create a new storage registry.
-    pub fn new(registry: prometheus::Registry) -> Self {
-        Self {
-            registry,
-            storages: Default::default(),
+impl Display for MetricStorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricStorageError::Register {
+                storage,
+                field,
+                source,
+            } => write!(f, "failed to register {}::{}: {}", storage, field, source),
+            MetricStorageError::Storage { storage, source } => {
+                write!(f, "failed to create {}: {}", storage, source)
+            }
+            MetricStorageError::Prometheus(source) => Display::fmt(source, f),
+            MetricStorageError::Labels(source) => {
+                write!(f, "failed to compute labels: {}", source)
+            }
         }
     }
+}
 
-    /// Return a reference to the underlying [`Registry`].
-    pub fn registry(&self) -> &Registry {
-        &self.registry
+impl std::error::Error for MetricStorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetricStorageError::Labels(source) => Some(source.as_ref()),
+            _ => self.prometheus_error().map(|source| source as _),
+        }
     }
+}
 
-    /// Convert this wrapper into the underlying [`Registry`].
+impl From<MetricStorageError> for Error {
+    fn from(err: MetricStorageError) -> Self {
+        err.into_prometheus_error()
+    }
+}
+
+impl From<Error> for MetricStorageError {
+    fn from(err: Error) -> Self {
+        MetricStorageError::Prometheus(err)
+    }
+}
+
+/// Kind of a metric.
+///
+/// There's no kind for summaries because the `prometheus` crate
+/// doesn't implement them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetricKind {
+    /// A [`prometheus::Counter`].
+    Counter,
+    /// A [`prometheus::Gauge`].
+    Gauge,
+    /// A [`prometheus::Histogram`].
+    Histogram,
+    /// A [`prometheus::CounterVec`].
+    CounterVec,
+    /// A [`prometheus::GaugeVec`].
+    GaugeVec,
+    /// A [`prometheus::HistogramVec`].
+    HistogramVec,
+}
+
+impl MetricKind {
+    /// Get name of this metric's type, as used in the text exposition format.
     ///
-    /// All information about registered storages is lost.
-    pub fn into_registry(self) -> Registry {
-        self.registry
+    /// Multidimensional metrics have the same type as their children.
+    pub fn type_name(self) -> &'static str {
+        match self {
+            MetricKind::Counter | MetricKind::CounterVec => "counter",
+            MetricKind::Gauge | MetricKind::GaugeVec => "gauge",
+            MetricKind::Histogram | MetricKind::HistogramVec => "histogram",
+        }
     }
 
-    /// Register a single metric in the underlying registry.
+    /// Check if this is a counter or a counter vector.
+    pub fn is_counter(self) -> bool {
+        matches!(self, MetricKind::Counter | MetricKind::CounterVec)
+    }
+
+    /// Check if this is a histogram or a histogram vector.
+    pub fn is_histogram(self) -> bool {
+        matches!(self, MetricKind::Histogram | MetricKind::HistogramVec)
+    }
+
+    /// Check if this is a multidimensional metric.
+    pub fn is_vec(self) -> bool {
+        matches!(
+            self,
+            MetricKind::CounterVec | MetricKind::GaugeVec | MetricKind::HistogramVec
+        )
+    }
+}
+
+/// This trait is used to initialize metrics.
+///
+/// Generated constructor will pass all its options to this trait's
+/// [`init`] function in order to initialize a field. If you're using
+/// custom metric collectors, you'll need to implement
+/// this trait for them.
+///
+/// [`init`]: MetricInit::init
+pub trait MetricInit: Sized {
+    /// Initialize a new instance of the metric using the given options.
+    fn init(opts: prometheus::Opts) -> Result<Self>;
+
+    /// Initialize a new instance of the metric using the given options
+    /// and struct-level default buckets.
     ///
-    /// Return an error if the given metric was already registered
-    /// when this function is called.
+    /// Metrics that don't accept buckets ignore them, which is what
+    /// the default implementation does. Histograms override this.
+    fn init_with_default_buckets(opts: prometheus::Opts, buckets: &[f64]) -> Result<Self> {
+        let _ = buckets;
+        Self::init(opts)
+    }
+
+    /// Get kind of this metric, if it's known.
     ///
-    /// See [`Registry::unregister`] for more info.
-    pub fn register(&self, c: Box<dyn Collector>) -> Result<()> {
-        self.registry.register(c)
+    /// Custom collectors don't have to override this.
+    fn kind() -> Option<MetricKind> {
+        None
     }
+}
 
-    /// Unregister a single metric from the underlying registry.
+/// This trait is used to initialize metrics that accept buckets.
+///
+/// This trait is similar to [`MetricInit`], but accepts histogram-specific
+/// options.
+///
+/// Note that histogram metrics should still be initializeable
+/// with [`MetricInit`]. This trait is only used when histogram-specific
+/// options appear in the metric config.
+pub trait HistMetricInit: Sized {
+    /// Initialize a new instance of the metric using the given options.
+    fn init(opts: prometheus::Opts, buckets: Vec<f64>) -> Result<Self>;
+}
+
+/// A typed value of a const label.
+///
+/// By default, constructors of a storage accept const label values
+/// as strings, which makes it easy to pass them in the wrong order.
+/// With the struct-level `label_types` option, constructors accept
+/// a type that implements this trait instead:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricLabel, MetricStorage};
+/// #[derive(MetricLabel)]
+/// enum Region {
+///     Europe,
+///     NorthAmerica,
+/// }
+///
+/// #[derive(MetricStorage)]
+/// #[metric(labels("region", "endpoint"), label_types(region = "Region"))]
+/// struct Metrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// # let registry = prometheus::Registry::default();
+/// let metrics = Metrics::new(&registry, Region::NorthAmerica, "/").unwrap();
+/// # metrics.requests.inc();
+/// # let labels = registry.gather()[0].get_metric()[0].get_label().to_vec();
+/// # assert_eq!(labels[1].get_name(), "region");
+/// # assert_eq!(labels[1].get_value(), "north_america");
+/// ```
+pub trait MetricLabel {
+    /// Return the label value.
+    fn as_str(&self) -> &str;
+}
+
+/// A [`MetricLabel`] with a fixed set of values, such as a fieldless enum.
+///
+/// This trait is implemented by `#[derive(MetricLabel)]`,
+/// and is used by [`PerVariant`].
+pub trait MetricLabelVariants: MetricLabel + Sized + 'static {
+    /// Return all values of this label.
+    fn variants() -> &'static [Self];
+
+    /// Return index of this value in [`variants`].
+    ///
+    /// [`variants`]: MetricLabelVariants::variants
+    fn index(&self) -> usize;
+}
+
+/// A set of metrics, one for each value of `L`.
+///
+/// This is a compile-time alternative to metric vectors: instead of
+/// a label, each metric gets its own name, suffixed with a label value.
+/// If metric name ends with `_total`, the suffix is inserted before it:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricLabel, MetricStorage, PerVariant};
+/// #[derive(MetricLabel)]
+/// enum Method {
+///     Get,
+///     Post,
+/// }
+///
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of requests.
+///     #[metric(per = "Method")]
+///     requests_total: PerVariant<Method, prometheus::IntCounter>,
+/// }
+///
+/// # let registry = prometheus::Registry::default();
+/// let metrics = Metrics::new(&registry).unwrap();
+/// metrics.requests_total(Method::Get).inc();
+///
+/// assert_eq!(metrics.requests_total(Method::Get).get(), 1);
+/// assert_eq!(metrics.requests_total(Method::Post).get(), 0);
+/// # let families = registry.gather();
+/// # assert_eq!(families[0].get_name(), "requests_get_total");
+/// # assert_eq!(families[1].get_name(), "requests_post_total");
+/// ```
+///
+/// With `per`, the derive macro generates an accessor method named
+/// after the field. Without it, use [`PerVariant::get`].
+pub struct PerVariant<L, M> {
+    metrics: Vec<M>,
+    label: std::marker::PhantomData<fn() -> L>,
+}
+
+impl<L: MetricLabelVariants, M> PerVariant<L, M> {
+    /// Create a set of metrics by calling the given function
+    /// for each value of `L`.
+    pub fn try_new<F: FnMut(&L) -> Result<M>>(f: F) -> Result<Self> {
+        Ok(Self {
+            metrics: L::variants().iter().map(f).collect::<Result<_>>()?,
+            label: std::marker::PhantomData,
+        })
+    }
+
+    /// Return the metric for the given label value.
+    pub fn get(&self, label: L) -> &M {
+        &self.metrics[label.index()]
+    }
+
+    fn with_suffixed_names<F>(opts: Opts, mut f: F) -> Result<Self>
+    where
+        F: FnMut(Opts) -> Result<M>,
+    {
+        Self::try_new(|label| {
+            let mut opts = opts.clone();
+            opts.name = match opts.name.strip_suffix("_total") {
+                Some(name) => format!("{}_{}_total", name, label.as_str()),
+                None => format!("{}_{}", opts.name, label.as_str()),
+            };
+            f(opts)
+        })
+    }
+}
+
+#[doc(hidden)]
+pub trait PerVariantField {
+    type Label;
+    type Metric;
+
+    fn get(&self, label: Self::Label) -> &Self::Metric;
+}
+
+#[doc(hidden)]
+pub trait MetricVecField {
+    type Metric;
+
+    fn with_label_values(&self, label_values: &[&str]) -> Self::Metric;
+}
+
+impl<T: prometheus::core::MetricVecBuilder> MetricVecField for prometheus::core::MetricVec<T> {
+    type Metric = T::M;
+
+    fn with_label_values(&self, label_values: &[&str]) -> T::M {
+        prometheus::core::MetricVec::with_label_values(self, label_values)
+    }
+}
+
+impl<L: MetricLabelVariants, M> PerVariantField for PerVariant<L, M> {
+    type Label = L;
+    type Metric = M;
+
+    fn get(&self, label: L) -> &M {
+        PerVariant::get(self, label)
+    }
+}
+
+impl<L, M: Clone> Clone for PerVariant<L, M> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            label: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<L, M: Debug> Debug for PerVariant<L, M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(&self.metrics).finish()
+    }
+}
+
+impl<L: MetricLabelVariants, M: MetricInit> MetricInit for PerVariant<L, M> {
+    fn init(opts: Opts) -> Result<Self> {
+        Self::with_suffixed_names(opts, M::init)
+    }
+
+    fn init_with_default_buckets(opts: Opts, buckets: &[f64]) -> Result<Self> {
+        Self::with_suffixed_names(opts, |opts| M::init_with_default_buckets(opts, buckets))
+    }
+
+    fn kind() -> Option<MetricKind> {
+        M::kind()
+    }
+}
+
+impl<L: MetricLabelVariants, M: HistMetricInit> HistMetricInit for PerVariant<L, M> {
+    fn init(opts: Opts, buckets: Vec<f64>) -> Result<Self> {
+        Self::with_suffixed_names(opts, |opts| M::init(opts, buckets.clone()))
+    }
+}
+
+impl<L: 'static, M: Collector> Collector for PerVariant<L, M> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metrics.iter().flat_map(Collector::desc).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.metrics.iter().flat_map(Collector::collect).collect()
+    }
+}
+
+/// A metric that is only created when it's accessed for the first time.
+///
+/// Storages with many metrics, especially metric vectors, spend noticeable
+/// time creating them. With `LazyMetric`, only metric descriptors are
+/// created upfront, because registries need them to detect conflicts.
+/// Until the metric is accessed, it's not exported:
+///
+/// ```
+/// # use prometheus_metric_storage::{LazyMetric, MetricStorage};
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of requests.
+///     #[metric(lazy, labels("method"))]
+///     requests: LazyMetric<prometheus::IntCounterVec>,
+/// }
+///
+/// let registry = prometheus::Registry::default();
+/// let metrics = Metrics::new(&registry).unwrap();
+/// assert!(registry.gather().is_empty());
+///
+/// metrics.requests().with_label_values(&["GET"]).inc();
+/// assert_eq!(registry.gather()[0].get_name(), "requests");
+/// ```
+///
+/// With `lazy`, the derive macro generates an accessor method named
+/// after the field. Without it, `LazyMetric` derefs to the metric.
+///
+/// The trade-off is that errors that can only be detected when creating
+/// the metric itself, such as invalid histogram buckets, turn into panics
+/// on first access. Clones of a `LazyMetric` share the same metric.
+pub struct LazyMetric<M> {
+    inner: Arc<LazyMetricInner<M>>,
+}
+
+struct LazyMetricInner<M> {
+    desc: Desc,
+    init: Box<dyn Fn() -> Result<M> + Send + Sync>,
+    metric: std::sync::OnceLock<M>,
+}
+
+impl<M> LazyMetric<M> {
+    /// Create a lazy metric with the given options. The metric
+    /// is created by calling `init` when it's accessed for the first time.
+    pub fn new<F>(opts: &Opts, init: F) -> Result<Self>
+    where
+        F: Fn() -> Result<M> + Send + Sync + 'static,
+    {
+        Ok(Self {
+            inner: Arc::new(LazyMetricInner {
+                desc: prometheus::core::Describer::describe(opts)?,
+                init: Box::new(init),
+                metric: std::sync::OnceLock::new(),
+            }),
+        })
+    }
+
+    /// Return the metric, creating it if necessary.
     ///
-    /// Returns an error if the given metric was not registered
-    /// when this function is called.
+    /// # Panics
     ///
-    /// See [`Registry::unregister`] for more info.
-    pub fn unregister(&self, c: Box<dyn Collector>) -> Result<()> {
-        self.registry.unregister(c)
+    /// Panics if the metric can't be created.
+    pub fn get(&self) -> &M {
+        self.inner.metric.get_or_init(|| match (self.inner.init)() {
+            Ok(metric) => metric,
+            Err(err) => panic!(
+                "failed to create lazy metric {}: {}",
+                self.inner.desc.fq_name, err
+            ),
+        })
     }
 
-    /// Gather all metrics from the underlying registry.
-    ///
-    /// See [`Registry::gather`] for more info.
-    pub fn gather(&self) -> Vec<MetricFamily> {
-        self.registry.gather()
+    /// Return the metric if it was already created.
+    pub fn get_if_created(&self) -> Option<&M> {
+        self.inner.metric.get()
     }
+}
 
-    /// Find a storage of the given type with tha given labels.
-    ///
-    /// Returns an error if the given metric storage was not registered
-    /// with the given labels, or if the given labels are invalid.
-    pub fn get_storage<T: MetricStorage + Send + Sync + 'static>(
-        &self,
-        const_labels: HashMap<String, String>,
-    ) -> Result<&T> {
-        let metric_id = Self::make_id::<T>(&const_labels)?;
+impl<M> Deref for LazyMetric<M> {
+    type Target = M;
 
-        let mut storages = self.storages.lock().unwrap();
+    fn deref(&self) -> &M {
+        self.get()
+    }
+}
 
-        let storage = match storages.entry(metric_id) {
-            Entry::Occupied(entry) => entry.into_mut().downcast_ref::<T>().unwrap(),
-            Entry::Vacant(_) => {
-                return Err(Error::Msg(format!(
-                    "metric storage {} not found",
-                    std::any::type_name::<T>()
-                )))
-            }
-        };
+impl<M> Clone for LazyMetric<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
 
-        // Safety:
-        //
-        // See `get_or_create_storage` for details.
-        unsafe { Ok(&*(storage as *const T)) }
+impl<M: Debug> Debug for LazyMetric<M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazyMetric")
+            .field("name", &self.inner.desc.fq_name)
+            .field("metric", &self.inner.metric.get())
+            .finish()
     }
+}
 
-    /// Return a storage of the given type with tha given labels. If such
-    /// storage does not exist in this registry, create it and register
-    /// its metrics.
-    ///
-    /// Returns an error if the given labels are invalid or if storage creation
-    /// has failed.
-    pub fn get_or_create_storage<T: MetricStorage + Send + Sync + 'static>(
-        &self,
-        const_labels: HashMap<String, String>,
-    ) -> Result<&T> {
-        let metric_id = Self::make_id::<T>(&const_labels)?;
+impl<M: MetricInit + Send + Sync + 'static> MetricInit for LazyMetric<M> {
+    fn init(opts: Opts) -> Result<Self> {
+        let desc_opts = opts.clone();
+        Self::new(&desc_opts, move || M::init(opts.clone()))
+    }
 
-        let mut storages = self.storages.lock().unwrap();
+    fn init_with_default_buckets(opts: Opts, buckets: &[f64]) -> Result<Self> {
+        let buckets = buckets.to_vec();
+        let desc_opts = opts.clone();
+        Self::new(&desc_opts, move || {
+            M::init_with_default_buckets(opts.clone(), &buckets)
+        })
+    }
 
-        let storage = match storages.entry(metric_id) {
-            Entry::Occupied(entry) => entry.into_mut().downcast_ref::<T>().unwrap(),
-            Entry::Vacant(entry) => {
-                let storage = T::from_const_labels(&self.registry, const_labels)?;
-                entry.insert(Box::pin(storage)).downcast_ref::<T>().unwrap()
-            }
-        };
+    fn kind() -> Option<MetricKind> {
+        M::kind()
+    }
+}
 
-        // Safety:
-        //
-        // We never remove storages from this registry, thus they will live
-        // for as long as this registry lives. We've also made storages
-        // `Pin`, so we never move them. This means that a reference
-        // to a storage will stay valid for as long as this registry lives.
-        //
-        // There are no issues with drop check because this registry
-        // does not implement custom drop, and the storage is `'static`.
-        //
-        // It is also ok to unlock mutex guard while holding reference
-        // to a storage because the storage is `Send + Sync`.
-        //
-        // Note that we're not returning a `'static` reference, but rather
-        // a reference with the lifetime of `&self`.
-        unsafe { Ok(&*(storage as *const T)) }
+impl<M: HistMetricInit + Send + Sync + 'static> HistMetricInit for LazyMetric<M> {
+    fn init(opts: Opts, buckets: Vec<f64>) -> Result<Self> {
+        let desc_opts = opts.clone();
+        Self::new(&desc_opts, move || M::init(opts.clone(), buckets.clone()))
     }
+}
 
-    fn make_id<T: MetricStorage + Send + Sync + 'static>(
-        const_labels: &HashMap<String, String>,
-    ) -> Result<StorageId> {
-        let labels_spec = T::const_labels();
+impl<M: Collector + 'static> Collector for LazyMetric<M> {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.inner.desc]
+    }
 
-        if labels_spec.len() != const_labels.len() {
-            return Err(Error::Msg(format!(
-                "invalid number of const labels: expected {}, got {}",
-                labels_spec.len(),
-                const_labels.len()
-            )));
+    fn collect(&self) -> Vec<MetricFamily> {
+        match self.get_if_created() {
+            Some(metric) => metric.collect(),
+            None => Vec::new(),
         }
+    }
+}
 
-        let mut values = String::new();
+/// A metric that is only created when it's enabled.
+///
+/// Fields of this type are marked with the `optional` attribute.
+/// The derive macro generates a method named `enable_{field}` that creates
+/// the metric and registers it. Until then, the metric is not exported:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, OptionalMetric, StorageRegistry};
+/// #[derive(MetricStorage)]
+/// #[metric(labels("tenant"))]
+/// struct Metrics {
+///     /// Size of each request in bytes.
+///     #[metric(optional)]
+///     request_size_bytes: OptionalMetric<prometheus::Histogram>,
+/// }
+///
+/// let registry = StorageRegistry::default();
+/// let metrics = Metrics::instance(&registry, "foo").unwrap();
+/// assert!(registry.gather().is_empty());
+///
+/// metrics.enable_request_size_bytes(registry.registry()).unwrap();
+/// metrics.request_size_bytes.get().unwrap().observe(512.0);
+/// assert_eq!(registry.gather()[0].get_name(), "request_size_bytes");
+/// ```
+///
+/// Options of the metric, including const labels, are saved when
+/// the storage is created. Clones of an `OptionalMetric` that were made
+/// before it was enabled stay disabled.
+pub struct OptionalMetric<M> {
+    init: Arc<dyn Fn() -> Result<M> + Send + Sync>,
+    metric: std::sync::OnceLock<M>,
+    lock: Mutex<()>,
+}
 
-        for &label in T::const_labels() {
-            if let Some(value) = const_labels.get(label) {
-                values.push_str(value);
-                values.push('\0');
-            } else {
-                return Err(Error::Msg(format!("label {:?} is missing", label)));
-            }
+impl<M> OptionalMetric<M> {
+    /// Create a disabled metric. The metric is created by calling `init`
+    /// when it's enabled.
+    pub fn new<F>(init: F) -> Self
+    where
+        F: Fn() -> Result<M> + Send + Sync + 'static,
+    {
+        Self {
+            init: Arc::new(init),
+            metric: std::sync::OnceLock::new(),
+            lock: Mutex::new(()),
         }
+    }
 
-        Ok((TypeId::of::<T>(), values))
+    /// Return the metric if it's enabled.
+    pub fn get(&self) -> Option<&M> {
+        self.metric.get()
+    }
+
+    /// Create the metric and pass it to `register`, unless it's enabled
+    /// already. The metric is only enabled if `register` succeeds.
+    ///
+    /// Concurrent calls are serialized, so `register` is called at most
+    /// once for a successfully enabled metric.
+    pub fn enable<E: From<Error>>(
+        &self,
+        register: impl FnOnce(&M) -> std::result::Result<(), E>,
+    ) -> std::result::Result<&M, E> {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(metric) = self.metric.get() {
+            return Ok(metric);
+        }
+
+        let metric = (self.init)()?;
+        register(&metric)?;
+        Ok(self.metric.get_or_init(|| metric))
     }
 }
 
-impl Default for StorageRegistry {
-    fn default() -> Self {
-        Self::new(Registry::new())
+impl<M: Clone> Clone for OptionalMetric<M> {
+    fn clone(&self) -> Self {
+        Self {
+            init: self.init.clone(),
+            metric: self.metric.clone(),
+            lock: Mutex::new(()),
+        }
     }
 }
 
-impl Debug for StorageRegistry {
+impl<M: Debug> Debug for OptionalMetric<M> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str("StorageRegistry")
+        f.debug_struct("OptionalMetric")
+            .field("metric", &self.metric.get())
+            .finish()
     }
 }
 
-/// Get the default storage registry that uses [`prometheus::default_registry`].
-pub fn default_storage_registry() -> &'static StorageRegistry {
-    lazy_static::lazy_static! {
-        static ref REGISTRY: StorageRegistry =
-            StorageRegistry::new(prometheus::default_registry().clone());
+impl<M: MetricInit + 'static> MetricInit for OptionalMetric<M> {
+    fn init(opts: Opts) -> Result<Self> {
+        Ok(Self::new(move || M::init(opts.clone())))
     }
 
-    &REGISTRY as &StorageRegistry
+    fn init_with_default_buckets(opts: Opts, buckets: &[f64]) -> Result<Self> {
+        let buckets = buckets.to_vec();
+        Ok(Self::new(move || {
+            M::init_with_default_buckets(opts.clone(), &buckets)
+        }))
+    }
+
+    fn kind() -> Option<MetricKind> {
+        M::kind()
+    }
 }
 
-/// Common interface for metric storages.
+impl<M: HistMetricInit + 'static> HistMetricInit for OptionalMetric<M> {
+    fn init(opts: Opts, buckets: Vec<f64>) -> Result<Self> {
+        Ok(Self::new(move || M::init(opts.clone(), buckets.clone())))
+    }
+}
+
+/// This trait is used to observe a value in all histograms of a group.
 ///
-/// This trait should be derived with the `#[derive(MetricStorage)]` macro.
-pub trait MetricStorage: Sized {
-    /// Get array of const labels used in this storage.
-    ///
-    /// Labels are listed in the same order as they appear
-    /// in the `#[metric(labels(...))]` attribute.
+/// Generated `observe_all_<group>` methods pass their arguments to this
+/// trait's [`observe_with_label_values`] function for every field
+/// of the group. Scalar histograms ignore label values, while histogram
+/// vectors use them to pick a child histogram.
+///
+/// [`observe_with_label_values`]: Observe::observe_with_label_values
+pub trait Observe {
+    /// Observe the given value.
     ///
-    /// See [crate-level] documentation for more info.
+    /// # Panics
     ///
-    /// [crate-level]: crate#configuring-metrics
-    fn const_labels() -> &'static [&'static str];
+    /// Multidimensional metrics may panic if the number of label values
+    /// doesn't match the number of labels.
+    fn observe_with_label_values(&self, value: f64, label_values: &[&str]);
+}
 
-    /// Create a new instance of this storage and register all of its metrics
-    /// in the given registry.
-    ///
-    /// For any given metric storage, this function should not be called twice
-    /// with the same values for const labels. Otherwise, the registry will
-    /// complain about a metric being registered twice.
-    ///
-    /// If the given const labels do not match the ones declared
-    /// in the `#[metric(labels(...))]` attribute of the struct
-    /// that's being created, this function will return an error.
-    fn from_const_labels(
-        registry: &Registry,
-        const_labels: HashMap<String, String>,
-    ) -> Result<Self> {
-        let storage = Self::from_const_labels_unregistered(const_labels)?;
-        storage.register(registry)?;
-        Ok(storage)
-    }
+/// Extension methods for gauges.
+///
+/// This trait is implemented for all gauges, so you can call its methods
+/// on gauge fields of your metric storage:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, GaugeExt};
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Maximum number of requests that were inflight at the same time.
+///     peak_inflight: prometheus::IntGauge,
+/// }
+///
+/// let metrics = Metrics::new_unregistered().unwrap();
+/// metrics.peak_inflight.set_max(3);
+/// metrics.peak_inflight.set_max(5);
+/// metrics.peak_inflight.set_max(4);
+/// assert_eq!(metrics.peak_inflight.get(), 5);
+/// ```
+pub trait GaugeExt {
+    /// Type of the gauge's value.
+    type Value;
 
-    /// Create a new instance of this storage and initialize all of its metrics.
+    /// Set the gauge to the given value if it's greater than the current one.
     ///
-    /// This function does not register the created metrics in any storage.
-    ///
-    /// If the given const labels do not match the ones declared
-    /// in the `metric(labels(...))` attribute of the struct
-    /// that's being created, this function will return an error.
-    fn from_const_labels_unregistered(const_labels: HashMap<String, String>) -> Result<Self>;
-
-    /// Register all metrics from this storage in the given registry.
-    fn register(&self, registry: &Registry) -> Result<()>;
+    /// Concurrent calls to this function are serialized, so the gauge
+    /// will always retain the maximum of all values. Note, however,
+    /// that other operations on the gauge, such as `set` or `inc`,
+    /// can still interleave with this function.
+    fn set_max(&self, value: Self::Value);
 }
 
-/// This trait is used to initialize metrics.
+/// Extension methods for gauges that represent durations.
 ///
-/// Generated constructor will pass all its options to this trait's
-/// [`init`] function in order to initialize a field. If you're using
-/// custom metric collectors, you'll need to implement
-/// this trait for them.
+/// This trait is implemented for floating point gauges:
 ///
-/// [`init`]: MetricInit::init
-pub trait MetricInit: Sized {
-    /// Initialize a new instance of the metric using the given options.
-    fn init(opts: prometheus::Opts) -> Result<Self>;
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, DurationGaugeExt};
+/// # use std::time::Duration;
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Time since the last successful sync, in seconds.
+///     seconds_since_last_sync: prometheus::Gauge,
+/// }
+///
+/// let metrics = Metrics::new_unregistered().unwrap();
+/// metrics.seconds_since_last_sync.set_duration_seconds(Duration::from_millis(1500));
+/// assert_eq!(metrics.seconds_since_last_sync.get(), 1.5);
+/// ```
+pub trait DurationGaugeExt {
+    /// Set the gauge to the given duration, converted to seconds.
+    fn set_duration_seconds(&self, duration: std::time::Duration);
 }
 
-/// This trait is used to initialize metrics that accept buckets.
+/// Extension methods for histograms.
 ///
-/// This trait is similar to [`MetricInit`], but accepts histogram-specific
-/// options.
+/// This trait is implemented for [`prometheus::Histogram`]:
 ///
-/// Note that histogram metrics should still be initializeable
-/// with [`MetricInit`]. This trait is only used when histogram-specific
-/// options appear in the metric config.
-pub trait HistMetricInit: Sized {
-    /// Initialize a new instance of the metric using the given options.
-    fn init(opts: prometheus::Opts, buckets: Vec<f64>) -> Result<Self>;
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, HistogramExt};
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Processing time of each request in seconds.
+///     requests_duration_seconds: prometheus::Histogram,
+/// }
+///
+/// let metrics = Metrics::new_unregistered().unwrap();
+///
+/// metrics.requests_duration_seconds.observe_sampled(0.5, 1.0);
+/// assert_eq!(metrics.requests_duration_seconds.get_sample_count(), 1);
+///
+/// metrics.requests_duration_seconds.observe_sampled(0.5, 0.0);
+/// assert_eq!(metrics.requests_duration_seconds.get_sample_count(), 1);
+/// ```
+pub trait HistogramExt {
+    /// Observe the given value with probability `rate`.
+    ///
+    /// This allows trading fidelity for overhead on extremely hot paths.
+    /// Rate of `1.0` or more always observes the value, rate of `0.0`
+    /// or less never does.
+    ///
+    /// Skipped observations are not compensated for in any way,
+    /// so the histogram's count and sum reflect only sampled values.
+    /// Divide them by the rate to estimate the totals. Bucket ratios,
+    /// and thus quantile estimates, stay unbiased, but their variance
+    /// grows as the rate decreases. Mixing different rates
+    /// in one histogram skews distribution towards values
+    /// observed with higher rates.
+    fn observe_sampled(&self, value: f64, rate: f64);
+}
+
+/// Check that the given metric follows prometheus naming conventions.
+pub(crate) fn check_naming_conventions(kind: Option<MetricKind>, desc: &Desc) -> Result<()> {
+    let name = &desc.fq_name;
+
+    let is_valid_name = name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    });
+    if name.is_empty() || !is_valid_name {
+        return Err(Error::Msg(format!("metric name {:?} is invalid", name)));
+    }
+
+    match kind {
+        Some(kind) if kind.is_counter() && !name.ends_with("_total") => {
+            return Err(Error::Msg(format!(
+                "counter name {:?} should end with _total",
+                name
+            )));
+        }
+        Some(kind) if !kind.is_counter() && name.ends_with("_total") => {
+            return Err(Error::Msg(format!(
+                "name {:?} ends with _total, but the metric is not a counter",
+                name
+            )));
+        }
+        _ => {}
+    }
+
+    let labels = desc
+        .const_label_pairs
+        .iter()
+        .map(|label| label.get_name())
+        .chain(desc.variable_labels.iter().map(String::as_str));
+    for label in labels {
+        if label.starts_with("__") {
+            return Err(Error::Msg(format!(
+                "label {:?} of metric {:?} uses reserved prefix __",
+                label, name
+            )));
+        }
+        if label == "le" && kind.is_some_and(MetricKind::is_histogram) {
+            return Err(Error::Msg(format!(
+                "label \"le\" of histogram {:?} is reserved",
+                name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Return a pseudo-random number uniformly distributed in `[0, 1)`.
+///
+/// Uses a thread-local xorshift generator, which is fast, but not suitable
+/// for anything other than sampling.
+fn sample_random() -> f64 {
+    use std::cell::Cell;
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
 }
 
 // Impls
 
+impl<P: prometheus::core::Atomic> GaugeExt for prometheus::core::GenericGauge<P> {
+    type Value = P::T;
+
+    fn set_max(&self, value: P::T) {
+        // Prometheus gauges don't expose compare-and-swap, so we lock
+        // a mutex that's picked based on the gauge's descriptor.
+        // Gauges that are clones of each other share the same descriptor.
+        lazy_static::lazy_static! {
+            static ref LOCKS: Vec<Mutex<()>> = (0..64).map(|_| Mutex::new(())).collect();
+        }
+
+        let id = self.desc().first().map_or(0, |desc| desc.id);
+        let _guard = LOCKS[(id % LOCKS.len() as u64) as usize]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        if value > self.get() {
+            self.set(value);
+        }
+    }
+}
+
+impl HistogramExt for prometheus::Histogram {
+    fn observe_sampled(&self, value: f64, rate: f64) {
+        if rate >= 1.0 || (rate > 0.0 && sample_random() < rate) {
+            self.observe(value)
+        }
+    }
+}
+
+impl DurationGaugeExt for prometheus::Gauge {
+    fn set_duration_seconds(&self, duration: std::time::Duration) {
+        self.set(duration.as_secs_f64())
+    }
+}
+
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGauge<T> {
     fn init(opts: Opts) -> Result<Self> {
         Self::with_opts(opts)
     }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::Gauge)
+    }
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounter<T> {
     fn init(opts: Opts) -> Result<Self> {
         Self::with_opts(opts)
     }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::Counter)
+    }
 }
 
 impl MetricInit for prometheus::Histogram {
     fn init(opts: Opts) -> Result<Self> {
         Self::with_opts(opts.into())
     }
+
+    fn init_with_default_buckets(opts: Opts, buckets: &[f64]) -> Result<Self> {
+        HistMetricInit::init(opts, buckets.to_vec())
+    }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::Histogram)
+    }
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGaugeVec<T> {
@@ -671,6 +6282,10 @@ impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGaugeV
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts, &labels_view)
     }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::GaugeVec)
+    }
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounterVec<T> {
@@ -679,6 +6294,10 @@ impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounte
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts, &labels_view)
     }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::CounterVec)
+    }
 }
 
 impl MetricInit for prometheus::HistogramVec {
@@ -687,6 +6306,26 @@ impl MetricInit for prometheus::HistogramVec {
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts.into(), &labels_view)
     }
+
+    fn init_with_default_buckets(opts: Opts, buckets: &[f64]) -> Result<Self> {
+        HistMetricInit::init(opts, buckets.to_vec())
+    }
+
+    fn kind() -> Option<MetricKind> {
+        Some(MetricKind::HistogramVec)
+    }
+}
+
+impl Observe for prometheus::Histogram {
+    fn observe_with_label_values(&self, value: f64, _label_values: &[&str]) {
+        self.observe(value)
+    }
+}
+
+impl Observe for prometheus::HistogramVec {
+    fn observe_with_label_values(&self, value: f64, label_values: &[&str]) {
+        self.with_label_values(label_values).observe(value)
+    }
 }
 
 impl HistMetricInit for prometheus::Histogram {
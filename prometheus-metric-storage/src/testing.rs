@@ -0,0 +1,297 @@
+//! Helpers for testing metrics.
+
+use crate::{MetricKind, MetricStorage};
+use prometheus::core::Collector;
+use std::collections::BTreeMap;
+
+/// Assert that the largest finite bucket of the given histogram
+/// is at least `max_observed`.
+///
+/// Observations that are greater than the largest finite bucket only fall
+/// into the implicit `+Inf` bucket, which makes them useless for estimating
+/// quantiles. Use this function in tests to catch misconfigured buckets:
+///
+/// ```
+/// # use prometheus_metric_storage::testing::assert_buckets_cover;
+/// let opts = prometheus::HistogramOpts::new("duration_seconds", "-")
+///     .buckets(vec![0.1, 1.0, 10.0]);
+/// let histogram = prometheus::Histogram::with_opts(opts).unwrap();
+/// histogram.observe(5.0);
+///
+/// assert_buckets_cover(&histogram, 5.0);
+/// ```
+///
+/// # Panics
+///
+/// Panics if the largest finite bucket is less than `max_observed`.
+/// The panic message mentions how many of already recorded observations
+/// fell into the `+Inf` bucket only:
+///
+/// ```should_panic
+/// # use prometheus_metric_storage::testing::assert_buckets_cover;
+/// let opts = prometheus::HistogramOpts::new("duration_seconds", "-")
+///     .buckets(vec![0.1, 1.0]);
+/// let histogram = prometheus::Histogram::with_opts(opts).unwrap();
+/// histogram.observe(5.0);
+///
+/// assert_buckets_cover(&histogram, 5.0);
+/// ```
+pub fn assert_buckets_cover(histogram: &prometheus::Histogram, max_observed: f64) {
+    let families = histogram.collect();
+    let family = &families[0];
+    let metric = family.get_metric()[0].get_histogram();
+    let buckets = metric.get_bucket();
+
+    let (top, top_count) = buckets
+        .iter()
+        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+        .rfind(|(upper_bound, _)| upper_bound.is_finite())
+        .unwrap_or((f64::NEG_INFINITY, 0));
+
+    if top < max_observed {
+        panic!(
+            "buckets of histogram {} don't cover observed range: \
+             largest finite bucket is {}, but values up to {} are observed; \
+             {} of {} recorded observations fall only into the +Inf bucket",
+            family.get_name(),
+            top,
+            max_observed,
+            metric.get_sample_count() - top_count,
+            metric.get_sample_count(),
+        );
+    }
+}
+
+/// Estimate the `q`-quantile of observations recorded by the given histogram.
+///
+/// The estimate is computed from bucket counts the same way as PromQL's
+/// `histogram_quantile` does: observations are assumed to be distributed
+/// linearly within each bucket. The result is only as precise
+/// as the bucket layout allows, so compare it with some tolerance:
+///
+/// ```
+/// # use prometheus_metric_storage::testing::histogram_quantile;
+/// let opts = prometheus::HistogramOpts::new("duration_seconds", "-")
+///     .buckets(prometheus::linear_buckets(0.1, 0.1, 10).unwrap());
+/// let histogram = prometheus::Histogram::with_opts(opts).unwrap();
+/// for i in 1..=100 {
+///     histogram.observe(i as f64 / 100.0);
+/// }
+///
+/// let median = histogram_quantile(&histogram, 0.5);
+/// assert!((median - 0.5).abs() < 0.05, "median is {}", median);
+/// # assert!((histogram_quantile(&histogram, 0.95) - 0.95).abs() < 0.05);
+/// # assert!(histogram_quantile(&histogram, 1.5).is_infinite());
+/// ```
+///
+/// Returns `NaN` if the histogram has no observations. If the quantile
+/// falls into the `+Inf` bucket, returns the largest finite bucket bound.
+pub fn histogram_quantile(histogram: &prometheus::Histogram, q: f64) -> f64 {
+    let families = histogram.collect();
+    let metric = families[0].get_metric()[0].get_histogram();
+    let total = metric.get_sample_count();
+
+    if total == 0 || q.is_nan() {
+        return f64::NAN;
+    } else if q < 0.0 {
+        return f64::NEG_INFINITY;
+    } else if q > 1.0 {
+        return f64::INFINITY;
+    }
+
+    let rank = q * total as f64;
+
+    let mut lower_bound = 0.0;
+    let mut lower_count = 0;
+    for (i, bucket) in metric.get_bucket().iter().enumerate() {
+        let upper_bound = bucket.get_upper_bound();
+        let count = bucket.get_cumulative_count();
+        if !upper_bound.is_finite() {
+            break;
+        }
+        if count as f64 >= rank {
+            if i == 0 && upper_bound <= 0.0 {
+                return upper_bound;
+            }
+            if count == lower_count {
+                return upper_bound;
+            }
+            let fraction = (rank - lower_count as f64) / (count - lower_count) as f64;
+            return lower_bound + (upper_bound - lower_bound) * fraction;
+        }
+        lower_bound = upper_bound;
+        lower_count = count;
+    }
+
+    // The quantile is in the `+Inf` bucket.
+    lower_bound
+}
+
+/// Compare schemas of two metric storages, and return a list of differences.
+///
+/// Schema includes names, kinds, help messages and labels of all metrics
+/// in a storage; values of const labels are not compared. Each difference
+/// is described by a single line. Lines that start with `-` describe metrics
+/// that are only present in `A`, lines that start with `+` describe metrics
+/// that are only present in `B`, and lines that start with `~` describe
+/// metrics that are present in both storages, but differ:
+///
+/// ```
+/// # use prometheus_metric_storage::MetricStorage;
+/// # use prometheus_metric_storage::testing::schema_diff;
+/// #[derive(MetricStorage)]
+/// struct OldMetrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+///     /// Number of open connections.
+///     connections: prometheus::IntGauge,
+/// }
+///
+/// #[derive(MetricStorage)]
+/// struct NewMetrics {
+///     /// Number of requests.
+///     requests_total: prometheus::IntCounter,
+///     /// Number of open connections.
+///     connections: prometheus::IntGauge,
+/// }
+///
+/// assert_eq!(
+///     schema_diff::<OldMetrics, NewMetrics>(),
+///     vec!["-requests", "+requests_total"],
+/// );
+/// ```
+///
+/// See also [`assert_same_schema`].
+///
+/// [`assert_same_schema`]: crate::assert_same_schema
+pub fn schema_diff<A: MetricStorage, B: MetricStorage>() -> Vec<String> {
+    let a = schema::<A>();
+    let b = schema::<B>();
+
+    let mut diff = Vec::new();
+
+    for (name, a_metric) in &a {
+        match b.get(name) {
+            None => diff.push(format!("-{}", name)),
+            Some(b_metric) => {
+                if a_metric.kind != b_metric.kind {
+                    diff.push(format!(
+                        "~{}: kind {:?} != {:?}",
+                        name, a_metric.kind, b_metric.kind
+                    ));
+                }
+                if a_metric.help != b_metric.help {
+                    diff.push(format!(
+                        "~{}: help {:?} != {:?}",
+                        name, a_metric.help, b_metric.help
+                    ));
+                }
+                if a_metric.const_labels != b_metric.const_labels {
+                    diff.push(format!(
+                        "~{}: const labels {:?} != {:?}",
+                        name, a_metric.const_labels, b_metric.const_labels
+                    ));
+                }
+                if a_metric.variable_labels != b_metric.variable_labels {
+                    diff.push(format!(
+                        "~{}: labels {:?} != {:?}",
+                        name, a_metric.variable_labels, b_metric.variable_labels
+                    ));
+                }
+            }
+        }
+    }
+
+    for name in b.keys() {
+        if !a.contains_key(name) {
+            diff.push(format!("+{}", name));
+        }
+    }
+
+    diff
+}
+
+/// Assert that two metric storages have the same schema.
+///
+/// This is useful in migration tests, as it catches accidental changes
+/// to metric names, kinds, help messages and labels. If schemas differ,
+/// the panic message lists all differences, as described
+/// in [`schema_diff`]:
+///
+/// ```should_panic
+/// # use prometheus_metric_storage::{MetricStorage, assert_same_schema};
+/// #[derive(MetricStorage)]
+/// struct OldMetrics {
+///     /// Number of requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// #[derive(MetricStorage)]
+/// struct NewMetrics {
+///     /// Number of requests.
+///     requests_total: prometheus::IntCounter,
+/// }
+///
+/// assert_same_schema!(OldMetrics, NewMetrics);
+/// ```
+///
+/// [`schema_diff`]: crate::testing::schema_diff
+#[macro_export]
+macro_rules! assert_same_schema {
+    ($a:ty, $b:ty $(,)?) => {{
+        let diff = $crate::testing::schema_diff::<$a, $b>();
+        if !diff.is_empty() {
+            panic!(
+                "schemas of {} and {} differ:\n{}",
+                stringify!($a),
+                stringify!($b),
+                diff.join("\n")
+            );
+        }
+    }};
+}
+
+/// Schema of a single metric.
+struct MetricSchema {
+    kind: Option<MetricKind>,
+    help: String,
+    const_labels: Vec<String>,
+    variable_labels: Vec<String>,
+}
+
+/// Collect schemas of all metrics of the given storage.
+fn schema<T: MetricStorage>() -> BTreeMap<String, MetricSchema> {
+    // Values of const labels are not a part of the schema,
+    // so we use label names as placeholders.
+    let const_labels = T::const_labels()
+        .iter()
+        .map(|label| (label.to_string(), label.to_string()))
+        .collect();
+
+    let storage = match T::from_const_labels_unregistered(const_labels) {
+        Ok(storage) => storage,
+        Err(err) => panic!(
+            "can't create storage {}: {}",
+            std::any::type_name::<T>(),
+            err
+        ),
+    };
+
+    storage
+        .descs()
+        .into_iter()
+        .map(|(kind, desc)| {
+            let schema = MetricSchema {
+                kind,
+                help: desc.help,
+                const_labels: desc
+                    .const_label_pairs
+                    .iter()
+                    .map(|label| label.get_name().to_string())
+                    .collect(),
+                variable_labels: desc.variable_labels,
+            };
+            (desc.fq_name, schema)
+        })
+        .collect()
+}